@@ -0,0 +1,162 @@
+// Copyright 2019 The Gotts Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Routing broker responses to subscribers: a thread and runtime per
+//! subscription, as `AsyncServer::init` used to do, against tasks on one
+//! shared runtime fed by bounded channels.
+//!
+//! Both designs are modelled with the channels and runtimes the relay uses,
+//! not with the relay's own response handlers, which this binary crate does
+//! not expose to benches. Their numbers compare the designs, not the relay.
+//!
+//! Prints the thread count, resident memory and the time to deliver one
+//! message to every subscriber. `SUBSCRIBERS` overrides the subscriber
+//! counts, e.g. `SUBSCRIBERS=100,500 cargo bench --bench response_routing`.
+
+use std::fs;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{channel, unbounded_channel};
+
+const QUEUE_SIZE: usize = 64;
+
+struct Sample {
+	threads: usize,
+	rss_kb: usize,
+	delivery: Duration,
+}
+
+fn threads() -> usize {
+	fs::read_dir("/proc/self/task")
+		.map(|tasks| tasks.count())
+		.unwrap_or(0)
+}
+
+fn rss_kb() -> usize {
+	fs::read_to_string("/proc/self/status")
+		.ok()
+		.and_then(|status| {
+			status
+				.lines()
+				.find(|line| line.starts_with("VmRSS:"))
+				.and_then(|line| line.split_whitespace().nth(1))
+				.and_then(|kb| kb.parse().ok())
+		})
+		.unwrap_or(0)
+}
+
+fn wait_for(delivered: &AtomicUsize, count: usize) {
+	while delivered.load(Ordering::SeqCst) < count {
+		thread::sleep(Duration::from_millis(1));
+	}
+}
+
+fn thread_per_subscription(subscribers: usize) -> Sample {
+	let delivered = Arc::new(AtomicUsize::new(0));
+	let senders: Vec<_> = (0..subscribers)
+		.map(|_| {
+			let (tx, mut rx) = unbounded_channel::<String>();
+			let delivered = delivered.clone();
+			thread::spawn(move || {
+				let runtime = tokio::runtime::Builder::new_current_thread()
+					.build()
+					.unwrap();
+				runtime.block_on(async move {
+					while rx.recv().await.is_some() {
+						delivered.fetch_add(1, Ordering::SeqCst);
+					}
+				});
+			});
+			tx
+		})
+		.collect();
+
+	let start = Instant::now();
+	for tx in &senders {
+		tx.send("slate".to_string()).unwrap();
+	}
+	wait_for(&delivered, subscribers);
+	let sample = Sample {
+		threads: threads(),
+		rss_kb: rss_kb(),
+		delivery: start.elapsed(),
+	};
+	drop(senders);
+	sample
+}
+
+fn shared_runtime(subscribers: usize) -> Sample {
+	let runtime = tokio::runtime::Builder::new_multi_thread().build().unwrap();
+	let delivered = Arc::new(AtomicUsize::new(0));
+	let senders: Vec<_> = (0..subscribers)
+		.map(|_| {
+			let (tx, mut rx) = channel::<String>(QUEUE_SIZE);
+			let delivered = delivered.clone();
+			runtime.spawn(async move {
+				while rx.recv().await.is_some() {
+					delivered.fetch_add(1, Ordering::SeqCst);
+				}
+			});
+			tx
+		})
+		.collect();
+
+	let start = Instant::now();
+	for tx in &senders {
+		tx.try_send("slate".to_string()).unwrap();
+	}
+	wait_for(&delivered, subscribers);
+	let sample = Sample {
+		threads: threads(),
+		rss_kb: rss_kb(),
+		delivery: start.elapsed(),
+	};
+	drop(senders);
+	runtime.shutdown_timeout(Duration::from_secs(1));
+	sample
+}
+
+fn report(design: &str, subscribers: usize, sample: Sample) {
+	println!(
+		"{:<26} {:>8} subscribers {:>8} threads {:>10} kB rss {:>8} ms delivery",
+		design,
+		subscribers,
+		sample.threads,
+		sample.rss_kb,
+		sample.delivery.as_millis()
+	);
+}
+
+fn main() {
+	let counts: Vec<usize> = std::env::var("SUBSCRIBERS")
+		.unwrap_or("100,500".to_string())
+		.split(',')
+		.filter_map(|count| count.trim().parse().ok())
+		.collect();
+
+	println!("baseline: {} threads, {} kB rss", threads(), rss_kb());
+	for subscribers in counts {
+		// the shared runtime first, the other design leaves threads behind
+		report("shared runtime", subscribers, shared_runtime(subscribers));
+		report(
+			"thread per subscription",
+			subscribers,
+			thread_per_subscription(subscribers),
+		);
+		// let the per subscription threads wind down
+		thread::sleep(Duration::from_millis(500));
+	}
+}
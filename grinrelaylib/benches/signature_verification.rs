@@ -0,0 +1,115 @@
+// Copyright 2019 The Gotts Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Relay hot path: checking a `PostSlate` signature signed over the slate
+//! followed by the challenge, i.e. the second candidate.
+//!
+//! Compares a context per verification with the shared `SignatureVerifier`.
+//! Both verify each signature on its own, there is no batch verification to
+//! compare with.
+
+#[macro_use]
+extern crate criterion;
+
+use criterion::{black_box, Criterion};
+
+use grinrelaylib::utils::crypto::{
+	public_key_from_secret_key, sign_challenge, sign_challenge_schnorr, AddrBech32, Hex,
+	SignatureScheme, SignatureVerifier,
+};
+use grinrelaylib::utils::secp::{Message, PublicKey, Secp256k1, SecretKey, Signature};
+use sha2::{Digest, Sha256};
+
+const CHALLENGE: &str = "7WUDtkSaKyGRUnQ22rE3QUXChV8DmA6NnunDYP4vheTpc";
+
+fn fixture() -> (String, String, String, String) {
+	let secret_key = SecretKey::from_hex(&"11".repeat(32)).unwrap();
+	let public_key = public_key_from_secret_key(&secret_key).unwrap();
+	let address = public_key.to_bech32(b"gn".to_vec());
+	let slate = "x".repeat(4096);
+	let signed = format!("{}{}", slate, CHALLENGE);
+	let ecdsa = sign_challenge(&signed, &secret_key).unwrap().to_hex();
+	let schnorr = sign_challenge_schnorr(&signed, &secret_key)
+		.unwrap()
+		.to_hex();
+	(address, slate, ecdsa, schnorr)
+}
+
+/// What the relay used to do: a context to decode the key, then a context per
+/// attempt, trying the bare slate before the slate followed by the challenge.
+fn verify_with_fresh_context(challenge: &str, signature: &str, public_key: &PublicKey) -> bool {
+	let secp = Secp256k1::new();
+	let signature = Signature::from_hex(signature).unwrap();
+	let mut hasher = Sha256::new();
+	hasher.update(challenge.as_bytes());
+	let message = Message::from_slice(hasher.finalize().as_slice()).unwrap();
+	secp.verify(&message, &signature, public_key).is_ok()
+}
+
+fn fresh_contexts(c: &mut Criterion) {
+	let (address, slate, ecdsa, _) = fixture();
+	c.bench_function("post_slate fresh contexts", move |b| {
+		b.iter(|| {
+			let (public_key, _) = PublicKey::from_bech32_check_raw(&address).unwrap();
+			let mut challenge = slate.clone();
+			if !verify_with_fresh_context(&challenge, &ecdsa, &public_key) {
+				challenge.push_str(CHALLENGE);
+				assert!(verify_with_fresh_context(&challenge, &ecdsa, &public_key));
+			}
+			black_box(challenge)
+		})
+	});
+}
+
+fn shared_verifier(c: &mut Criterion) {
+	let (address, slate, ecdsa, schnorr) = fixture();
+	let with_challenge = format!("{}{}", slate, CHALLENGE);
+	c.bench_function("post_slate shared verifier ecdsa", {
+		let (address, slate, with_challenge) =
+			(address.clone(), slate.clone(), with_challenge.clone());
+		move |b| {
+			b.iter(|| {
+				let verifier = SignatureVerifier::shared();
+				let (public_key, _) = verifier.public_key_from_bech32(&address).unwrap();
+				let signed = verifier
+					.match_challenge(
+						SignatureScheme::Ecdsa,
+						&[&slate, &with_challenge],
+						&ecdsa,
+						&public_key,
+					)
+					.unwrap();
+				black_box(signed)
+			})
+		}
+	});
+	c.bench_function("post_slate shared verifier schnorr", move |b| {
+		b.iter(|| {
+			let verifier = SignatureVerifier::shared();
+			let (public_key, _) = verifier.public_key_from_bech32(&address).unwrap();
+			let signed = verifier
+				.match_challenge(
+					SignatureScheme::Schnorr,
+					&[&slate, &with_challenge],
+					&schnorr,
+					&public_key,
+				)
+				.unwrap();
+			black_box(signed)
+		})
+	});
+}
+
+criterion_group!(benches, fresh_contexts, shared_verifier);
+criterion_main!(benches);
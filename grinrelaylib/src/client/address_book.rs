@@ -0,0 +1,339 @@
+// Copyright 2019 The Gotts Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Contacts shared by wallet front ends.
+//!
+//! The book is persisted as json:
+//! `{"version": 1, "contacts": [{"name": "alice", "public_key": "gn1...",
+//! "relay_domain": "relay.grin.icu", "relay_port": 3418}]}`
+
+use std::fs;
+use std::path::Path;
+
+use crate::client::RelayAddrRetriever;
+use crate::error::{ErrorKind, Result};
+use crate::types::{split_alias_address, GrinboxAddress};
+
+/// Current version of the address book file format.
+pub const ADDRESS_BOOK_VERSION: u16 = 1;
+
+const RELAY_ABBR_LEN: usize = 6;
+const BECH32_CHARSET: &str = "qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Contact {
+	pub name: String,
+	/// The bech32 public key of the contact, without relay location
+	pub public_key: String,
+	/// The relay the contact was last seen on
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub relay_domain: Option<String>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub relay_port: Option<u16>,
+}
+
+impl Contact {
+	pub fn new(name: &str, address: &GrinboxAddress) -> Contact {
+		Contact {
+			name: name.to_string(),
+			public_key: address.public_key.clone(),
+			relay_domain: Some(address.domain.clone()),
+			relay_port: Some(address.port),
+		}
+	}
+
+	/// The contact address at its last known relay.
+	pub fn address(&self) -> Result<GrinboxAddress> {
+		let mut address = self.public_key.clone();
+		if let Some(ref domain) = self.relay_domain {
+			address.push('@');
+			address.push_str(domain);
+			if let Some(port) = self.relay_port {
+				address.push_str(&format!(":{}", port));
+			}
+		}
+		address.parse()
+	}
+
+	fn abbr(&self) -> String {
+		let key: String = self.public_key.chars().filter(|c| *c != '-').collect();
+		let start = key.len().saturating_sub(RELAY_ABBR_LEN);
+		key[start..].to_string()
+	}
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AddressBook {
+	version: u16,
+	contacts: Vec<Contact>,
+}
+
+impl Default for AddressBook {
+	fn default() -> AddressBook {
+		AddressBook::new()
+	}
+}
+
+impl AddressBook {
+	pub fn new() -> AddressBook {
+		AddressBook {
+			version: ADDRESS_BOOK_VERSION,
+			contacts: vec![],
+		}
+	}
+
+	pub fn from_json(json: &str) -> Result<AddressBook> {
+		let book: AddressBook = serde_json::from_str(json)?;
+		if book.version != ADDRESS_BOOK_VERSION {
+			return Err(ErrorKind::AddressBook(format!(
+				"unsupported address book version {}",
+				book.version
+			))
+			.into());
+		}
+		Ok(book)
+	}
+
+	pub fn to_json(&self) -> String {
+		serde_json::to_string_pretty(self).unwrap()
+	}
+
+	/// Load the book stored at `path`, or an empty one if there is no such file.
+	pub fn load<P: AsRef<Path>>(path: P) -> Result<AddressBook> {
+		match fs::read_to_string(path) {
+			Ok(json) => AddressBook::from_json(&json),
+			Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(AddressBook::new()),
+			Err(e) => Err(e.into()),
+		}
+	}
+
+	/// Save the book to `path`, replacing the previous file atomically.
+	pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+		let path = path.as_ref();
+		let tmp = path.with_extension("tmp");
+		fs::write(&tmp, self.to_json())?;
+		fs::rename(&tmp, path)?;
+		Ok(())
+	}
+
+	pub fn contacts(&self) -> &[Contact] {
+		&self.contacts
+	}
+
+	pub fn get(&self, name: &str) -> Option<&Contact> {
+		self.contacts.iter().find(|c| c.name == name)
+	}
+
+	/// Add a contact, its name must not be used yet.
+	pub fn add(&mut self, contact: Contact) -> Result<()> {
+		if self.get(&contact.name).is_some() {
+			return Err(ErrorKind::AddressBook(format!(
+				"contact `{}` already exists",
+				contact.name
+			))
+			.into());
+		}
+		contact.address()?;
+		self.contacts.push(contact);
+		Ok(())
+	}
+
+	pub fn remove(&mut self, name: &str) -> Option<Contact> {
+		let pos = self.contacts.iter().position(|c| c.name == name)?;
+		Some(self.contacts.remove(pos))
+	}
+
+	/// Remember the relay a known contact was last seen on, e.g. when a slate
+	/// from it is received. Returns whether a contact was updated.
+	pub fn update_relay(&mut self, address: &GrinboxAddress) -> bool {
+		let mut updated = false;
+		for contact in self
+			.contacts
+			.iter_mut()
+			.filter(|c| c.public_key == address.public_key)
+		{
+			contact.relay_domain = Some(address.domain.clone());
+			contact.relay_port = Some(address.port);
+			updated = true;
+		}
+		updated
+	}
+
+	/// Resolve a contact name, a relay abbreviation, an `alias@relay` or a full
+	/// address.
+	///
+	/// Abbreviations are first matched against the contacts, then looked up
+	/// on the relay through `retriever` when one is given. Aliases are always
+	/// looked up on their relay.
+	pub fn resolve(
+		&self,
+		input: &str,
+		retriever: Option<&dyn RelayAddrRetriever>,
+	) -> Result<GrinboxAddress> {
+		if let Some(contact) = self.get(input) {
+			return contact.address();
+		}
+
+		if is_relay_abbr(input) {
+			let known: Vec<&Contact> =
+				self.contacts.iter().filter(|c| c.abbr() == input).collect();
+			match known.len() {
+				0 => {}
+				1 => return known[0].address(),
+				_ => return Err(ErrorKind::AmbiguousRelayAbbr(input.to_string()).into()),
+			}
+
+			let retriever =
+				retriever.ok_or_else(|| ErrorKind::UnknownContact(input.to_string()))?;
+			let relay_addr = retriever.retrieve_relay_addr(input)?;
+			return match relay_addr.len() {
+				0 => Err(ErrorKind::UnknownContact(input.to_string()).into()),
+				1 => relay_addr[0].parse(),
+				_ => Err(ErrorKind::AmbiguousRelayAbbr(input.to_string()).into()),
+			};
+		}
+
+		if let Some((alias, relay)) = split_alias_address(input) {
+			let retriever =
+				retriever.ok_or_else(|| ErrorKind::UnknownContact(input.to_string()))?;
+			return retriever.resolve_alias(alias, relay)?.parse();
+		}
+
+		let address: GrinboxAddress = input
+			.parse()
+			.map_err(|_| ErrorKind::UnknownContact(input.to_string()))?;
+		// an address typed without location may belong to a contact on another relay
+		if !input.contains('@') {
+			if let Some(contact) = self
+				.contacts
+				.iter()
+				.find(|c| c.public_key == address.public_key)
+			{
+				return contact.address();
+			}
+		}
+		Ok(address)
+	}
+}
+
+fn is_relay_abbr(input: &str) -> bool {
+	input.len() == RELAY_ABBR_LEN && input.chars().all(|c| BECH32_CHARSET.contains(c))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::types::ChainTypes;
+	use crate::utils::crypto::{public_key_from_secret_key, Hex};
+	use crate::utils::secp::SecretKey;
+
+	fn address(byte: &str, domain: &str, port: u16) -> GrinboxAddress {
+		let secret_key = SecretKey::from_hex(&byte.repeat(32)).unwrap();
+		let public_key = public_key_from_secret_key(&secret_key).unwrap();
+		GrinboxAddress::with_network(
+			public_key,
+			Some(domain.to_string()),
+			Some(port),
+			&ChainTypes::Mainnet,
+		)
+	}
+
+	struct StubRetriever(Vec<String>);
+
+	impl RelayAddrRetriever for StubRetriever {
+		fn retrieve_relay_addr(&self, _abbr: &str) -> Result<Vec<String>> {
+			Ok(self.0.clone())
+		}
+
+		fn resolve_alias(&self, alias: &str, relay: &str) -> Result<String> {
+			match (alias, relay) {
+				("shop", "relay.example.com") => Ok(self.0[0].clone()),
+				_ => Err(ErrorKind::UnknownContact(alias.to_string()).into()),
+			}
+		}
+	}
+
+	#[test]
+	fn resolve_name_abbr_and_address() {
+		let alice = address("11", "relay.example.com", 13420);
+		let bob = address("22", "relay.grin.icu", 3418);
+		let mut book = AddressBook::new();
+		book.add(Contact::new("alice", &alice)).unwrap();
+
+		assert_eq!(book.resolve("alice", None).unwrap(), alice);
+
+		let abbr = Contact::new("alice", &alice).abbr();
+		assert_eq!(book.resolve(&abbr, None).unwrap(), alice);
+
+		let bare_key: String = alice.public_key.clone();
+		assert_eq!(book.resolve(&bare_key, None).unwrap(), alice);
+
+		let bob_abbr = Contact::new("bob", &bob).abbr();
+		assert!(book.resolve(&bob_abbr, None).is_err());
+		let retriever = StubRetriever(vec![bob.public_key.clone()]);
+		assert_eq!(
+			book.resolve(&bob_abbr, Some(&retriever)).unwrap().public_key,
+			bob.public_key
+		);
+
+		assert!(book.resolve("carol", None).is_err());
+	}
+
+	#[test]
+	fn resolve_alias_on_its_relay() {
+		let shop = address("33", "relay.example.com", 13420);
+		let book = AddressBook::new();
+		let retriever = StubRetriever(vec![format!("{}@relay.example.com:13420", shop.public_key)]);
+
+		assert_eq!(
+			book.resolve("shop@relay.example.com", Some(&retriever))
+				.unwrap(),
+			shop
+		);
+		assert!(book.resolve("shop@relay.example.com", None).is_err());
+		assert!(book
+			.resolve("cafe@relay.example.com", Some(&retriever))
+			.is_err());
+	}
+
+	#[test]
+	fn duplicate_names_are_rejected() {
+		let mut book = AddressBook::new();
+		book.add(Contact::new(
+			"alice",
+			&address("11", "relay.grin.icu", 3418),
+		))
+		.unwrap();
+		assert!(book
+			.add(Contact::new(
+				"alice",
+				&address("22", "relay.grin.icu", 3418)
+			))
+			.is_err());
+	}
+
+	#[test]
+	fn json_roundtrip_and_relay_update() {
+		let alice = address("11", "relay.grin.icu", 3418);
+		let mut book = AddressBook::new();
+		book.add(Contact::new("alice", &alice)).unwrap();
+
+		let moved = address("11", "relay.example.com", 13420);
+		assert!(book.update_relay(&moved));
+		assert_eq!(book.resolve("alice", None).unwrap(), moved);
+
+		let restored = AddressBook::from_json(&book.to_json()).unwrap();
+		assert_eq!(restored, book);
+	}
+}
@@ -14,7 +14,7 @@
 // limitations under the License.
 
 use crate::client::CloseReason;
-use crate::types::{GrinboxAddress, Slate, TxProof};
+use crate::types::{GrinboxAddress, GrinboxResponse, Slate, TxProof};
 
 pub trait GrinboxSubscriptionHandler: Send {
 	fn on_open(&self);
@@ -22,4 +22,78 @@ pub trait GrinboxSubscriptionHandler: Send {
 	fn on_close(&self, result: CloseReason);
 	fn on_dropped(&self);
 	fn on_reestablished(&self);
+	/// The address subscribed from another connection, which took over. The
+	/// relay closes this one right after.
+	fn on_revoked(&self, _address: &GrinboxAddress) {}
+}
+
+/// Hand `response` to `handler` if it is a subscription event which needs
+/// none of the subscriber's keys, returning whether it was one. Slates are
+/// left to the subscriber, which decrypts them first.
+pub fn dispatch_subscription_event(
+	handler: &dyn GrinboxSubscriptionHandler,
+	response: &GrinboxResponse,
+) -> bool {
+	match *response {
+		GrinboxResponse::SubscriptionRevoked { ref address } => {
+			// the relay only revokes addresses it accepted, nothing to tell otherwise
+			if let Ok(address) = GrinboxAddress::from_str_raw(address) {
+				handler.on_revoked(&address);
+			}
+			true
+		}
+		_ => false,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::types::GRINRELAY_ADDRESS_HRP_MAINNET;
+	use crate::utils::crypto::AddrBech32;
+	use crate::utils::secp::{PublicKey, Secp256k1, SecretKey};
+	use std::sync::Mutex;
+
+	#[derive(Default)]
+	struct Recorder {
+		revoked: Mutex<Vec<GrinboxAddress>>,
+	}
+
+	impl GrinboxSubscriptionHandler for Recorder {
+		fn on_open(&self) {}
+		fn on_slate(&self, _: &GrinboxAddress, _: &mut Slate, _: Option<&mut TxProof>) {}
+		fn on_close(&self, _: CloseReason) {}
+		fn on_dropped(&self) {}
+		fn on_reestablished(&self) {}
+		fn on_revoked(&self, address: &GrinboxAddress) {
+			self.revoked.lock().unwrap().push(address.clone());
+		}
+	}
+
+	fn public_key() -> String {
+		let secp = Secp256k1::new();
+		let secret_key = SecretKey::from_slice(&secp, &[1; 32]).unwrap();
+		let public_key = PublicKey::from_secret_key(&secp, &secret_key).unwrap();
+		public_key.to_bech32(GRINRELAY_ADDRESS_HRP_MAINNET.into())
+	}
+
+	#[test]
+	fn revoked_subscriptions_reach_the_handler() {
+		let handler = Recorder::default();
+		let response = GrinboxResponse::SubscriptionRevoked {
+			address: public_key(),
+		};
+		assert!(dispatch_subscription_event(&handler, &response));
+
+		let revoked = handler.revoked.lock().unwrap();
+		assert_eq!(revoked.len(), 1);
+		assert_eq!(revoked[0].public_key, public_key());
+	}
+
+	#[test]
+	fn other_responses_are_left_to_the_subscriber() {
+		let handler = Recorder::default();
+		assert!(!dispatch_subscription_event(&handler, &GrinboxResponse::Ok));
+		assert!(handler.revoked.lock().unwrap().is_empty());
+	}
 }
@@ -13,12 +13,18 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+mod address_book;
 mod close_reason;
 mod grinbox_publisher;
 mod grinbox_subscriber;
 mod grinbox_subscription_handler;
+mod relay_addr_retriever;
 
+pub use self::address_book::{AddressBook, Contact, ADDRESS_BOOK_VERSION};
 pub use self::close_reason::CloseReason;
 pub use self::grinbox_publisher::GrinboxPublisher;
 pub use self::grinbox_subscriber::GrinboxSubscriber;
-pub use self::grinbox_subscription_handler::GrinboxSubscriptionHandler;
+pub use self::grinbox_subscription_handler::{
+	dispatch_subscription_event, GrinboxSubscriptionHandler,
+};
+pub use self::relay_addr_retriever::RelayAddrRetriever;
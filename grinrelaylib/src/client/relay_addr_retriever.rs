@@ -0,0 +1,27 @@
+// Copyright 2019 The Gotts Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::error::{ErrorKind, Result};
+
+/// Looks up the full addresses behind a 6 character relay abbreviation, i.e.
+/// a `RetrieveRelayAddr` request.
+pub trait RelayAddrRetriever {
+	fn retrieve_relay_addr(&self, abbr: &str) -> Result<Vec<String>>;
+
+	/// Looks up the address `alias` points to on `relay`, i.e. a `ResolveAlias`
+	/// request sent to that relay.
+	fn resolve_alias(&self, alias: &str, relay: &str) -> Result<String> {
+		Err(ErrorKind::UnknownContact(format!("{}@{}", alias, relay)).into())
+	}
+}
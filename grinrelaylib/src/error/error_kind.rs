@@ -46,4 +46,10 @@ pub enum ErrorKind {
 	GrinboxProtocolError(GrinboxError),
 	#[fail(display = "\x1b[31;1merror:\x1b[0m bech32 coding error `{}`", 0)]
 	Bech32Error(CodingError),
+	#[fail(display = "\x1b[31;1merror:\x1b[0m unknown contact or address `{}`", 0)]
+	UnknownContact(String),
+	#[fail(display = "\x1b[31;1merror:\x1b[0m `{}` matches several addresses", 0)]
+	AmbiguousRelayAbbr(String),
+	#[fail(display = "\x1b[31;1merror:\x1b[0m address book: {}", 0)]
+	AddressBook(String),
 }
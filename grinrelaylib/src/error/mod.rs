@@ -15,7 +15,9 @@
 
 mod error_kind;
 mod result;
+mod tx_proof_error;
 
 pub use self::error_kind::ErrorKind;
 pub use self::result::Result;
+pub use self::tx_proof_error::{TxProofError, TxProofErrorKind};
 pub use failure::Error;
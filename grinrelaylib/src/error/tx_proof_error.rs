@@ -0,0 +1,125 @@
+// Copyright 2019 The Gotts Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use failure::{Backtrace, Context, Fail};
+use std::fmt::{self, Display};
+
+#[derive(Clone, Debug, Eq, Fail, PartialEq, Serialize, Deserialize)]
+pub enum TxProofErrorKind {
+	#[fail(display = "unable to parse the sender address")]
+	ParseAddress,
+	#[fail(display = "unable to parse the sender public key")]
+	ParsePublicKey,
+	#[fail(display = "unable to parse the signature")]
+	ParseSignature,
+	#[fail(display = "the signature does not match the sender")]
+	VerifySignature,
+	#[fail(display = "unable to parse the grinrelay message")]
+	ParseGrinboxMessage,
+	#[fail(display = "the message was sent to another destination")]
+	VerifyDestination,
+	#[fail(display = "unable to derive the decryption key")]
+	DecryptionKey,
+	#[fail(display = "unable to decrypt the message")]
+	DecryptMessage,
+	#[fail(display = "unable to parse the slate")]
+	ParseSlate,
+	#[fail(display = "unable to parse the proof file")]
+	ParseProofFile,
+	#[fail(display = "amount or fee do not match the slate")]
+	VerifyAmount,
+	#[fail(display = "commitments do not match the slate")]
+	VerifyCommitments,
+	#[fail(display = "unsupported proof file version")]
+	UnsupportedVersion,
+	#[fail(display = "unable to sign the proof file")]
+	SignExport,
+	#[fail(display = "the proof file signature does not match the exporter")]
+	VerifyExportSignature,
+}
+
+/// A transaction proof error, keeping the underlying cause when there is one.
+#[derive(Debug)]
+pub struct TxProofError {
+	inner: Context<TxProofErrorKind>,
+}
+
+impl TxProofError {
+	pub fn kind(&self) -> TxProofErrorKind {
+		self.inner.get_context().clone()
+	}
+}
+
+impl Fail for TxProofError {
+	fn cause(&self) -> Option<&dyn Fail> {
+		self.inner.cause()
+	}
+
+	fn backtrace(&self) -> Option<&Backtrace> {
+		self.inner.backtrace()
+	}
+}
+
+impl Display for TxProofError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "\x1b[31;1merror:\x1b[0m {}", self.inner.get_context())?;
+		if let Some(cause) = self.inner.cause() {
+			write!(f, ": {}", cause)?;
+		}
+		Ok(())
+	}
+}
+
+impl From<TxProofErrorKind> for TxProofError {
+	fn from(kind: TxProofErrorKind) -> TxProofError {
+		TxProofError {
+			inner: Context::new(kind),
+		}
+	}
+}
+
+impl From<Context<TxProofErrorKind>> for TxProofError {
+	fn from(inner: Context<TxProofErrorKind>) -> TxProofError {
+		TxProofError { inner }
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use failure::ResultExt;
+
+	#[test]
+	fn converts_into_failure_error() {
+		let error: failure::Error = TxProofError::from(TxProofErrorKind::ParseSlate).into();
+		let tx_proof_error = error.downcast_ref::<TxProofError>().unwrap();
+		assert_eq!(tx_proof_error.kind(), TxProofErrorKind::ParseSlate);
+		assert!(tx_proof_error.cause().is_none());
+	}
+
+	#[test]
+	fn keeps_the_cause() {
+		let result: Result<u8, TxProofError> = "x"
+			.parse::<u8>()
+			.context(TxProofErrorKind::ParseProofFile)
+			.map_err(TxProofError::from);
+		let error: failure::Error = result.unwrap_err().into();
+		let tx_proof_error = error.downcast_ref::<TxProofError>().unwrap();
+		assert_eq!(tx_proof_error.kind(), TxProofErrorKind::ParseProofFile);
+		assert!(tx_proof_error.cause().is_some());
+		assert!(error
+			.to_string()
+			.contains("unable to parse the proof file: "));
+	}
+}
@@ -0,0 +1,190 @@
+// Copyright 2019 The Gotts Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Human readable names registered on a relay for an address.
+//!
+//! The owner of an address proves it by signing the text returned by
+//! `alias_challenge`, which covers the requested lifetime and the time of
+//! signing. A signature is only accepted for a few minutes, and never after a
+//! later one for the same alias. An alias stays reserved until it expires,
+//! whether the owner is online or not, and can be renewed by registering it
+//! again.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Registration lifetime used when the request does not ask for one.
+pub const ALIAS_DEFAULT_TTL_IN_SECONDS: u32 = 30 * 86400;
+/// Longest registration a relay grants.
+pub const ALIAS_MAX_TTL_IN_SECONDS: u32 = 365 * 86400;
+/// Most aliases a single address can hold at once.
+pub const ALIAS_MAX_PER_ADDRESS: usize = 8;
+/// How far the signing time of a registration may be from the relay clock.
+pub const ALIAS_SIGNATURE_WINDOW_IN_SECONDS: u64 = 300;
+
+const ALIAS_MIN_LEN: usize = 3;
+const ALIAS_MAX_LEN: usize = 32;
+const RELAY_ABBR_LEN: usize = 6;
+const BECH32_CHARSET: &str = "qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AliasRecord {
+	pub alias: String,
+	/// The address the alias points to
+	pub address: String,
+	/// Unix time in seconds after which the alias is free again
+	pub expires_at: u64,
+	/// Unix time in seconds the owner signed the registration at
+	#[serde(default)]
+	pub signed_at: u64,
+}
+
+impl AliasRecord {
+	pub fn is_expired(&self, now: u64) -> bool {
+		self.expires_at <= now
+	}
+}
+
+/// Lowercase ascii letters, digits and inner `-`, 3 to 32 characters. Names
+/// which could be taken for a 6 character relay abbreviation are refused.
+pub fn is_valid_alias(alias: &str) -> bool {
+	let len = alias.len();
+	if len < ALIAS_MIN_LEN || len > ALIAS_MAX_LEN {
+		return false;
+	}
+	if alias.starts_with('-') || alias.ends_with('-') {
+		return false;
+	}
+	if !alias
+		.chars()
+		.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+	{
+		return false;
+	}
+	!(len == RELAY_ABBR_LEN && alias.chars().all(|c| BECH32_CHARSET.contains(c)))
+}
+
+/// Split `alias@relay` into the alias and the relay it is registered on.
+pub fn split_alias_address(input: &str) -> Option<(&str, &str)> {
+	let pos = input.find('@')?;
+	let (alias, relay) = (&input[..pos], &input[pos + 1..]);
+	if !is_valid_alias(alias) || relay.is_empty() {
+		return None;
+	}
+	Some((alias, relay))
+}
+
+/// The text signed by the owner of `address` at unix time `signed_at` to
+/// register `alias` for `ttl_in_seconds`.
+pub fn alias_challenge(
+	alias: &str,
+	address: &str,
+	ttl_in_seconds: u32,
+	signed_at: u64,
+	challenge: &str,
+) -> String {
+	format!(
+		"{}|{}|{}|{}|{}",
+		alias, address, ttl_in_seconds, signed_at, challenge
+	)
+}
+
+/// Whether a registration signed at `signed_at` may still be accepted at `now`.
+pub fn is_fresh_signature(signed_at: u64, now: u64) -> bool {
+	let skew = match signed_at > now {
+		true => signed_at - now,
+		false => now - signed_at,
+	};
+	skew <= ALIAS_SIGNATURE_WINDOW_IN_SECONDS
+}
+
+pub fn unix_now() -> u64 {
+	SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.map(|d| d.as_secs())
+		.unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn alias_validation() {
+		assert!(is_valid_alias("shop"));
+		assert!(is_valid_alias("coffee-shop-42"));
+		assert!(!is_valid_alias("ab"));
+		assert!(!is_valid_alias("Shop"));
+		assert!(!is_valid_alias("-shop"));
+		assert!(!is_valid_alias("shop-"));
+		assert!(!is_valid_alias("shop@relay"));
+		assert!(!is_valid_alias(&"a".repeat(33)));
+		// could be a relay abbreviation
+		assert!(!is_valid_alias("qpzry9"));
+		assert!(is_valid_alias("bakery"));
+	}
+
+	#[test]
+	fn alias_addresses_are_split() {
+		assert_eq!(
+			split_alias_address("shop@relay.example.com"),
+			Some(("shop", "relay.example.com"))
+		);
+		assert_eq!(
+			split_alias_address("shop@relay.example.com:13420"),
+			Some(("shop", "relay.example.com:13420"))
+		);
+		assert_eq!(split_alias_address("shop"), None);
+		assert_eq!(split_alias_address("shop@"), None);
+		assert_eq!(split_alias_address("Shop@relay.example.com"), None);
+		assert_eq!(
+			split_alias_address(&format!("gn1{}@relay.example.com", "q".repeat(59))),
+			None
+		);
+	}
+
+	#[test]
+	fn alias_challenge_covers_ttl_and_time() {
+		let challenge = alias_challenge("shop", "gn1owner", 86400, 1000, "challenge");
+		assert_ne!(
+			challenge,
+			alias_challenge("shop", "gn1owner", 86401, 1000, "challenge")
+		);
+		assert_ne!(
+			challenge,
+			alias_challenge("shop", "gn1owner", 86400, 1001, "challenge")
+		);
+	}
+
+	#[test]
+	fn signatures_are_fresh_for_a_while() {
+		let now = 10_000;
+		assert!(is_fresh_signature(now, now));
+		assert!(is_fresh_signature(
+			now - ALIAS_SIGNATURE_WINDOW_IN_SECONDS,
+			now
+		));
+		assert!(is_fresh_signature(
+			now + ALIAS_SIGNATURE_WINDOW_IN_SECONDS,
+			now
+		));
+		assert!(!is_fresh_signature(
+			now - ALIAS_SIGNATURE_WINDOW_IN_SECONDS - 1,
+			now
+		));
+		assert!(!is_fresh_signature(
+			now + ALIAS_SIGNATURE_WINDOW_IN_SECONDS + 1,
+			now
+		));
+	}
+}
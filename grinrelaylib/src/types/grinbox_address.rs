@@ -13,16 +13,17 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use regex::Regex;
 use std::fmt::{self, Display};
+use std::net::Ipv6Addr;
+use std::str::FromStr;
 
-use crate::error::{ErrorKind, Result};
+use crate::error::{Error, ErrorKind, Result};
+use crate::utils::bech32::Bech32;
 use crate::utils::crypto::AddrBech32;
 use crate::utils::secp::PublicKey;
 use parking_lot::RwLock;
 
 pub const GRINRELAY_PREFIX: &str = "grinrelay://";
-pub const GRINRELAY_ADDRESS_REGEX: &str = r"^(grinrelay://)?(?P<public_key>[0-9a-z\-]{62,67})(@(?P<domain>[a-zA-Z0-9\.]+)(:(?P<port>[0-9]*))?)?$";
 pub const GRINRELAY_ADDRESS_HRP_MAINNET: &str = "gn";
 pub const GRINRELAY_ADDRESS_HRP_TESTNET: &str = "tn";
 pub const DEFAULT_GRINRELAY_DOMAIN: &str = "relay.grin.icu";
@@ -36,22 +37,71 @@ pub enum ChainTypes {
 	Mainnet,
 }
 
+/// The network an address lives on.
+pub type Network = ChainTypes;
+
+impl ChainTypes {
+	/// The bech32 human-readable part used by addresses on this chain.
+	pub fn hrp(&self) -> &'static str {
+		match *self {
+			ChainTypes::Mainnet => GRINRELAY_ADDRESS_HRP_MAINNET,
+			ChainTypes::Floonet => GRINRELAY_ADDRESS_HRP_TESTNET,
+		}
+	}
+
+	/// The chain type an address human-readable part belongs to, if any.
+	pub fn from_hrp(hrp: &[u8]) -> Option<ChainTypes> {
+		if hrp == GRINRELAY_ADDRESS_HRP_MAINNET.as_bytes() {
+			Some(ChainTypes::Mainnet)
+		} else if hrp == GRINRELAY_ADDRESS_HRP_TESTNET.as_bytes() {
+			Some(ChainTypes::Floonet)
+		} else {
+			None
+		}
+	}
+}
+
+impl Display for ChainTypes {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			ChainTypes::Mainnet => write!(f, "mainnet"),
+			ChainTypes::Floonet => write!(f, "floonet"),
+		}
+	}
+}
+
+impl FromStr for ChainTypes {
+	type Err = Error;
+
+	fn from_str(s: &str) -> Result<Self> {
+		match s.to_lowercase().as_str() {
+			"mainnet" => Ok(ChainTypes::Mainnet),
+			"floonet" => Ok(ChainTypes::Floonet),
+			_ => Err(ErrorKind::InvalidChainType.into()),
+		}
+	}
+}
+
 lazy_static! {
-	/// The mining parameter mode
+	/// The mining parameter mode, only kept for the deprecated global shim
 	pub static ref CHAIN_TYPE: RwLock<ChainTypes> =
 			RwLock::new(ChainTypes::Mainnet);
 }
 
+#[deprecated(note = "pass a `Network` explicitly instead")]
 pub fn is_mainnet() -> bool {
 	let param_ref = CHAIN_TYPE.read();
 	ChainTypes::Mainnet == *param_ref
 }
 
+#[deprecated(note = "pass a `Network` explicitly instead")]
 pub fn set_running_mode(mode: ChainTypes) {
 	let mut param_ref = CHAIN_TYPE.write();
 	*param_ref = mode;
 }
 
+#[deprecated(note = "use `Network::hrp` instead")]
+#[allow(deprecated)]
 pub fn hrp_bytes() -> Vec<u8> {
 	if is_mainnet() {
 		GRINRELAY_ADDRESS_HRP_MAINNET.into()
@@ -60,6 +110,10 @@ pub fn hrp_bytes() -> Vec<u8> {
 	}
 }
 
+fn running_network() -> Network {
+	CHAIN_TYPE.read().clone()
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct GrinboxAddress {
 	pub public_key: String,
@@ -69,9 +123,19 @@ pub struct GrinboxAddress {
 }
 
 impl GrinboxAddress {
+	#[deprecated(note = "use `GrinboxAddress::with_network` instead")]
 	pub fn new(public_key: PublicKey, domain: Option<String>, port: Option<u16>) -> Self {
+		GrinboxAddress::with_network(public_key, domain, port, &running_network())
+	}
+
+	pub fn with_network(
+		public_key: PublicKey,
+		domain: Option<String>,
+		port: Option<u16>,
+		network: &Network,
+	) -> Self {
 		Self {
-			public_key: public_key.to_bech32(hrp_bytes()),
+			public_key: public_key.to_bech32(network.hrp().into()),
 			domain: domain.unwrap_or(DEFAULT_GRINRELAY_DOMAIN.to_string()),
 			port: port.unwrap_or(DEFAULT_GRINRELAY_PORT),
 			hrp_bytes: None,
@@ -92,46 +156,74 @@ impl GrinboxAddress {
 		}
 	}
 
-	pub fn from_str(s: &str) -> Result<Self> {
-		let re = Regex::new(GRINRELAY_ADDRESS_REGEX).unwrap();
-		let captures = re.captures(s);
-		if captures.is_none() {
-			Err(ErrorKind::GrinboxAddressParsingError(s.to_string()))?;
-		}
+	fn split(s: &str) -> Result<(String, Option<String>, Option<u16>)> {
+		let err = || ErrorKind::GrinboxAddressParsingError(s.to_string());
 
-		let captures = captures.unwrap();
-		let public_key = captures.name("public_key").unwrap().as_str().to_string();
-		let domain = captures.name("domain").map(|m| m.as_str().to_string());
-		let port = captures
-			.name("port")
-			.map(|m| u16::from_str_radix(m.as_str(), 10).unwrap());
+		let rest = if s.starts_with(GRINRELAY_PREFIX) {
+			&s[GRINRELAY_PREFIX.len()..]
+		} else {
+			s
+		};
 
-		let public_key = PublicKey::from_bech32_check(&public_key, hrp_bytes())?;
+		let (public_key, location) = match rest.find('@') {
+			Some(pos) => (&rest[..pos], Some(&rest[pos + 1..])),
+			None => (rest, None),
+		};
 
-		Ok(GrinboxAddress::new(public_key, domain, port))
+		let key_len = public_key.chars().count();
+		if key_len < 62
+			|| key_len > 67
+			|| !public_key
+				.chars()
+				.all(|c| c.is_ascii_digit() || c.is_ascii_lowercase() || c == '-')
+		{
+			return Err(err().into());
+		}
+
+		let (domain, port) = match location {
+			Some(location) => {
+				let (host, port) = parse_location(location).ok_or_else(err)?;
+				(Some(host), port)
+			}
+			None => (None, None),
+		};
+
+		Ok((public_key.to_string(), domain, port))
 	}
 
-	pub fn from_str_raw(s: &str) -> Result<Self> {
-		let re = Regex::new(GRINRELAY_ADDRESS_REGEX).unwrap();
-		let captures = re.captures(s);
-		if captures.is_none() {
-			Err(ErrorKind::GrinboxAddressParsingError(s.to_string()))?;
-		}
+	/// Parse an address, requiring it to belong to the given network.
+	pub fn parse(s: &str, network: &Network) -> Result<Self> {
+		let (public_key, domain, port) = GrinboxAddress::split(s)?;
+		let public_key = PublicKey::from_bech32_check(&public_key, network.hrp().into())?;
+
+		Ok(GrinboxAddress::with_network(public_key, domain, port, network))
+	}
 
-		let captures = captures.unwrap();
-		let public_key = captures.name("public_key").unwrap().as_str().to_string();
-		let domain = captures.name("domain").map(|m| m.as_str().to_string());
-		let port = captures
-			.name("port")
-			.map(|m| u16::from_str_radix(m.as_str(), 10).unwrap());
+	#[deprecated(note = "use `GrinboxAddress::parse` or `str::parse` instead")]
+	pub fn from_str(s: &str) -> Result<Self> {
+		GrinboxAddress::parse(s, &running_network())
+	}
 
+	pub fn from_str_raw(s: &str) -> Result<Self> {
+		let (public_key, domain, port) = GrinboxAddress::split(s)?;
 		let (public_key, hrp_bytes) = PublicKey::from_bech32_check_raw(&public_key)?;
 
 		Ok(GrinboxAddress::new_raw(public_key, domain, port, hrp_bytes))
 	}
 
+	/// The network this address belongs to, according to its bech32 prefix.
+	pub fn network(&self) -> Option<Network> {
+		match self.hrp_bytes {
+			Some(ref hrp_bytes) => ChainTypes::from_hrp(hrp_bytes),
+			None => Bech32::from_string(&self.public_key)
+				.ok()
+				.and_then(|b| ChainTypes::from_hrp(b.hrp.as_bytes())),
+		}
+	}
+
 	pub fn public_key(&self) -> Result<PublicKey> {
-		PublicKey::from_bech32_check(&self.public_key, hrp_bytes())
+		let network = self.network().ok_or(ErrorKind::InvalidChainType)?;
+		PublicKey::from_bech32_check(&self.public_key, network.hrp().into())
 	}
 
 	pub fn stripped(&self) -> String {
@@ -139,6 +231,80 @@ impl GrinboxAddress {
 	}
 }
 
+/// Split `host[:port]` into a normalized host and an optional port.
+/// IPv6 literals must be bracketed and keep their brackets, so the host can be
+/// used as is in a `wss://host:port` url. Unicode domains are converted to punycode.
+fn parse_location(location: &str) -> Option<(String, Option<u16>)> {
+	let (host, port) = if location.starts_with('[') {
+		let end = location.find(']')?;
+		let ip = location[1..end].parse::<Ipv6Addr>().ok()?;
+		let port = match &location[end + 1..] {
+			"" => None,
+			rest if rest.starts_with(':') => Some(&rest[1..]),
+			_ => return None,
+		};
+		(format!("[{}]", ip), port)
+	} else {
+		let mut parts = location.splitn(2, ':');
+		let host = parts.next()?;
+		let port = parts.next();
+		(parse_domain(host)?, port)
+	};
+
+	let port = match port {
+		Some(port) => {
+			if port.is_empty() || !port.chars().all(|c| c.is_ascii_digit()) {
+				return None;
+			}
+			match port.parse::<u16>() {
+				Ok(0) | Err(_) => return None,
+				Ok(port) => Some(port),
+			}
+		}
+		None => None,
+	};
+
+	Some((host, port))
+}
+
+fn parse_domain(host: &str) -> Option<String> {
+	let host = if host.is_ascii() {
+		host.to_string()
+	} else {
+		idna::domain_to_ascii(host).ok()?
+	};
+
+	if host.is_empty() || host.len() > 253 {
+		return None;
+	}
+
+	let valid_labels = host.split('.').all(|label| {
+		!label.is_empty()
+			&& label.len() <= 63
+			&& !label.starts_with('-')
+			&& !label.ends_with('-')
+			&& label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+	});
+
+	if valid_labels {
+		Some(host)
+	} else {
+		None
+	}
+}
+
+impl FromStr for GrinboxAddress {
+	type Err = Error;
+
+	/// Parse an address on whichever network its bech32 prefix names.
+	fn from_str(s: &str) -> Result<Self> {
+		let (public_key, _, _) = GrinboxAddress::split(s)?;
+		let (_, hrp_bytes) = PublicKey::from_bech32_check_raw(&public_key)?;
+		let network = ChainTypes::from_hrp(&hrp_bytes).ok_or(ErrorKind::InvalidChainType)?;
+		GrinboxAddress::parse(s, &network)
+	}
+}
+
 impl Display for GrinboxAddress {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
 		write!(f, "{}{}", GRINRELAY_PREFIX, self.public_key)?;
@@ -151,3 +317,105 @@ impl Display for GrinboxAddress {
 		Ok(())
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::utils::secp::{Secp256k1, SecretKey};
+	use proptest::prelude::*;
+
+	fn public_key() -> String {
+		let secp = Secp256k1::new();
+		let secret_key = SecretKey::from_slice(&secp, &[1; 32]).unwrap();
+		let public_key = PublicKey::from_secret_key(&secp, &secret_key).unwrap();
+		public_key.to_bech32(GRINRELAY_ADDRESS_HRP_MAINNET.into())
+	}
+
+	#[test]
+	fn parse_locations() {
+		let key = public_key();
+
+		let address = format!("{}@relay-1.example.com:13420", key)
+			.parse::<GrinboxAddress>()
+			.unwrap();
+		assert_eq!(address.domain, "relay-1.example.com");
+		assert_eq!(address.port, 13420);
+
+		let address = format!("grinrelay://{}@[::1]:13420", key)
+			.parse::<GrinboxAddress>()
+			.unwrap();
+		assert_eq!(address.domain, "[::1]");
+		assert_eq!(address.port, 13420);
+
+		let address = format!("{}@xn--bcher-kva.example", key)
+			.parse::<GrinboxAddress>()
+			.unwrap();
+		assert_eq!(address.domain, "xn--bcher-kva.example");
+		assert_eq!(address.port, DEFAULT_GRINRELAY_PORT);
+
+		let address = format!("{}@bücher.example", key)
+			.parse::<GrinboxAddress>()
+			.unwrap();
+		assert_eq!(address.domain, "xn--bcher-kva.example");
+
+		for location in &[
+			"host:99999",
+			"host:",
+			"host:0",
+			"host:12ab",
+			"::1",
+			"[::1",
+			"[::1]x",
+			"[not-ip]:80",
+			"-host.com",
+			"host-.com",
+			"host..com",
+			"",
+		] {
+			let address = format!("{}@{}", key, location);
+			assert!(address.parse::<GrinboxAddress>().is_err(), "{}", address);
+		}
+	}
+
+	proptest! {
+		#[test]
+		fn parse_never_panics(s in "\\PC*") {
+			let _ = s.parse::<GrinboxAddress>();
+			let _ = GrinboxAddress::from_str_raw(&s);
+		}
+
+		#[test]
+		fn parse_never_panics_after_key(location in "\\PC*") {
+			let _ = format!("{}@{}", public_key(), location).parse::<GrinboxAddress>();
+		}
+
+		#[test]
+		fn domain_and_port_roundtrip(
+			domain in "[a-z0-9]([a-z0-9-]{0,20}[a-z0-9])?(\\.[a-z0-9]([a-z0-9-]{0,20}[a-z0-9])?){0,3}",
+			port in 1u16..,
+		) {
+			let address = format!("{}@{}:{}", public_key(), domain, port)
+				.parse::<GrinboxAddress>()
+				.unwrap();
+			prop_assert_eq!(&address.domain, &domain);
+			prop_assert_eq!(address.port, port);
+			prop_assert_eq!(address.to_string().parse::<GrinboxAddress>().unwrap(), address);
+		}
+
+		#[test]
+		fn ipv6_roundtrip(segments in any::<[u16; 8]>(), port in 1u16..) {
+			let ip = Ipv6Addr::from(segments);
+			let address = format!("{}@[{}]:{}", public_key(), ip, port)
+				.parse::<GrinboxAddress>()
+				.unwrap();
+			prop_assert_eq!(&address.domain, &format!("[{}]", ip));
+			prop_assert_eq!(address.port, port);
+		}
+
+		#[test]
+		fn port_overflow_is_an_error(port in 65536u64..) {
+			let address = format!("{}@example.com:{}", public_key(), port);
+			prop_assert!(address.parse::<GrinboxAddress>().is_err());
+		}
+	}
+}
@@ -13,51 +13,268 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use ring::{aead, digest, pbkdf2};
+use rand::{thread_rng, Rng};
 
 use crate::error::{ErrorKind, Result};
 use crate::types::GrinboxAddress;
-use crate::utils::from_hex;
+use crate::utils::cipher;
 use crate::utils::secp::{PublicKey, Secp256k1, SecretKey};
+use crate::utils::{from_hex, to_hex};
+
+/// PBKDF2-SHA512 over the x-coordinate of the shared point.
+pub const GRINBOX_MESSAGE_VERSION_LEGACY: u8 = 1;
+/// HKDF-SHA256 over the full shared point, bound to both public keys.
+pub const GRINBOX_MESSAGE_VERSION: u8 = 2;
+/// Versions this library decrypts, advertised in every message it writes.
+pub const GRINBOX_MESSAGE_VERSIONS: [u8; 2] =
+	[GRINBOX_MESSAGE_VERSION_LEGACY, GRINBOX_MESSAGE_VERSION];
+
+const HKDF_INFO_PREFIX: &[u8] = b"grinrelay/message/v2";
+
+fn legacy_version() -> u8 {
+	GRINBOX_MESSAGE_VERSION_LEGACY
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct GrinboxMessage {
+	#[serde(default = "legacy_version")]
+	pub version: u8,
 	#[serde(default)]
 	pub destination: Option<GrinboxAddress>,
+	/// Versions the sender accepts for its replies. Empty from older senders,
+	/// which accept the legacy version only.
+	#[serde(default)]
+	pub accepted_versions: Vec<u8>,
 	encrypted_message: String,
 	salt: String,
 	nonce: String,
 }
 
 impl GrinboxMessage {
-	pub fn key(&self, sender_public_key: &PublicKey, secret_key: &SecretKey) -> Result<[u8; 32]> {
-		let salt = from_hex(self.salt.clone()).map_err(|_| ErrorKind::Decryption)?;
+	/// Encrypt with the legacy version, which every peer understands. Use
+	/// `with_version` and `version_for` once the peer advertised a newer one.
+	pub fn new(
+		message: String,
+		destination: &GrinboxAddress,
+		receiver_public_key: &PublicKey,
+		secret_key: &SecretKey,
+	) -> Result<GrinboxMessage> {
+		GrinboxMessage::with_version(
+			message,
+			destination,
+			receiver_public_key,
+			secret_key,
+			GRINBOX_MESSAGE_VERSION_LEGACY,
+		)
+	}
 
-		let secp = Secp256k1::new();
-		let mut common_secret = sender_public_key.clone();
-		common_secret
-			.mul_assign(&secp, secret_key)
-			.map_err(|_| ErrorKind::Decryption)?;
-		let common_secret_ser = common_secret.serialize_vec(&secp, true);
-		let common_secret_slice = &common_secret_ser[1..33];
+	/// Encrypt with an explicit format version.
+	pub fn with_version(
+		message: String,
+		destination: &GrinboxAddress,
+		receiver_public_key: &PublicKey,
+		secret_key: &SecretKey,
+		version: u8,
+	) -> Result<GrinboxMessage> {
+		let mut rng = thread_rng();
+		let salt: [u8; 8] = rng.gen();
+		let nonce: [u8; cipher::NONCE_LEN] = rng.gen();
 
-		let mut key = [0; 32];
-		pbkdf2::derive(&digest::SHA512, 10000, &salt, common_secret_slice, &mut key);
+		let key = derive_key(version, &salt, receiver_public_key, secret_key)
+			.map_err(|_| ErrorKind::Encryption)?;
+
+		let encrypted_message = cipher::seal(&key, &nonce, message.as_bytes())?;
+
+		Ok(GrinboxMessage {
+			version,
+			destination: Some(destination.clone()),
+			accepted_versions: GRINBOX_MESSAGE_VERSIONS.to_vec(),
+			encrypted_message: to_hex(encrypted_message),
+			salt: to_hex(salt.to_vec()),
+			nonce: to_hex(nonce.to_vec()),
+		})
+	}
 
-		Ok(key)
+	/// The newest version both this library and a peer accepting
+	/// `accepted_versions` understand.
+	pub fn version_for(accepted_versions: &[u8]) -> u8 {
+		GRINBOX_MESSAGE_VERSIONS
+			.iter()
+			.rev()
+			.find(|version| accepted_versions.contains(version))
+			.cloned()
+			.unwrap_or(GRINBOX_MESSAGE_VERSION_LEGACY)
+	}
+
+	/// The version to reply to the sender of this message with.
+	pub fn reply_version(&self) -> u8 {
+		GrinboxMessage::version_for(&self.accepted_versions)
+	}
+
+	pub fn key(&self, sender_public_key: &PublicKey, secret_key: &SecretKey) -> Result<[u8; 32]> {
+		let salt = from_hex(self.salt.clone()).map_err(|_| ErrorKind::Decryption)?;
+		derive_key(self.version, &salt, sender_public_key, secret_key)
 	}
 
 	pub fn decrypt_with_key(&self, key: &[u8; 32]) -> Result<String> {
-		let mut encrypted_message =
+		let encrypted_message =
 			from_hex(self.encrypted_message.clone()).map_err(|_| ErrorKind::Decryption)?;
 		let nonce = from_hex(self.nonce.clone()).map_err(|_| ErrorKind::Decryption)?;
 
-		let opening_key = aead::OpeningKey::new(&aead::CHACHA20_POLY1305, key)
-			.map_err(|_| ErrorKind::Decryption)?;
-		let decrypted_data =
-			aead::open_in_place(&opening_key, &nonce, &[], 0, &mut encrypted_message)
-				.map_err(|_| ErrorKind::Decryption)?;
+		let decrypted_data = cipher::open(key, &nonce, &encrypted_message)?;
+
+		String::from_utf8(decrypted_data).map_err(|_| ErrorKind::Decryption.into())
+	}
+}
+
+/// Derive the symmetric key shared by the owner of `secret_key` and of
+/// `public_key`. Both sides get the same key whichever of them is the sender.
+fn derive_key(
+	version: u8,
+	salt: &[u8],
+	public_key: &PublicKey,
+	secret_key: &SecretKey,
+) -> Result<[u8; 32]> {
+	let secp = Secp256k1::new();
+	let mut common_secret = public_key.clone();
+	common_secret
+		.mul_assign(&secp, secret_key)
+		.map_err(|_| ErrorKind::Decryption)?;
+	let common_secret_ser = common_secret.serialize_vec(&secp, true);
+
+	let mut key = [0; 32];
+	match version {
+		GRINBOX_MESSAGE_VERSION_LEGACY => {
+			let common_secret_slice = &common_secret_ser[1..33];
+			cipher::pbkdf2_sha512(common_secret_slice, salt, 10000, &mut key);
+		}
+		GRINBOX_MESSAGE_VERSION => {
+			let own_public_key =
+				PublicKey::from_secret_key(&secp, secret_key).map_err(|_| ErrorKind::Decryption)?;
+			let mut public_keys = vec![
+				public_key.serialize_vec(&secp, true).to_vec(),
+				own_public_key.serialize_vec(&secp, true).to_vec(),
+			];
+			public_keys.sort();
+
+			let mut info = HKDF_INFO_PREFIX.to_vec();
+			for public_key in public_keys {
+				info.extend(public_key);
+			}
+
+			cipher::hkdf_sha256(salt, &common_secret_ser[..], &info, &mut key)?;
+		}
+		_ => return Err(ErrorKind::Decryption.into()),
+	}
+
+	Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::types::GRINRELAY_ADDRESS_HRP_MAINNET;
+	use crate::utils::crypto::Hex;
+
+	const SENDER_SECRET_KEY: &str =
+		"1111111111111111111111111111111111111111111111111111111111111111";
+	const RECEIVER_SECRET_KEY: &str =
+		"2222222222222222222222222222222222222222222222222222222222222222";
+	const PLAINTEXT: &str = r#"{"grinrelay":"test vector"}"#;
+
+	fn message(version: u8, encrypted_message: &str) -> GrinboxMessage {
+		GrinboxMessage {
+			version,
+			destination: None,
+			accepted_versions: vec![],
+			encrypted_message: encrypted_message.to_string(),
+			salt: "0102030405060708".to_string(),
+			nonce: "0a0b0c0d0e0f101112131415".to_string(),
+		}
+	}
+
+	fn keys() -> (SecretKey, PublicKey, SecretKey, PublicKey) {
+		let secp = Secp256k1::new();
+		let sender = SecretKey::from_hex(SENDER_SECRET_KEY).unwrap();
+		let receiver = SecretKey::from_hex(RECEIVER_SECRET_KEY).unwrap();
+		let sender_public_key = PublicKey::from_secret_key(&secp, &sender).unwrap();
+		let receiver_public_key = PublicKey::from_secret_key(&secp, &receiver).unwrap();
+		(sender, sender_public_key, receiver, receiver_public_key)
+	}
+
+	#[test]
+	fn decrypt_test_vectors() {
+		let (sender, sender_public_key, receiver, receiver_public_key) = keys();
+
+		let vectors = [
+			(
+				GRINBOX_MESSAGE_VERSION_LEGACY,
+				"08eeef9167692c26d406411af5e024e5d141ac6b0a858e52a7e73e2a177aa6ea",
+				"c49e515a6e52ac945106972cc1381067131a54a6e17ce3a776a3aeb6de5d2eaba68116db73d86011e87265",
+			),
+			(
+				GRINBOX_MESSAGE_VERSION,
+				"9409c6efbeee7523314c795f3fcaeaa6670c5493c1a4daf8b38fba8650d3bbd3",
+				"fb6f4b6f6a53776aa022d28c284ad6d87906b3be010db55640fbfe9c94a42aeb6b567da668f892bb84bca8",
+			),
+		];
+
+		for (version, key, encrypted_message) in vectors.iter() {
+			let message = message(*version, encrypted_message);
+
+			let receiver_key = message.key(&sender_public_key, &receiver).unwrap();
+			let sender_key = message.key(&receiver_public_key, &sender).unwrap();
+			assert_eq!(to_hex(receiver_key.to_vec()), *key);
+			assert_eq!(sender_key, receiver_key);
+
+			assert_eq!(message.decrypt_with_key(&receiver_key).unwrap(), PLAINTEXT);
+		}
+	}
+
+	#[test]
+	fn legacy_message_without_version() {
+		let json = r#"{"destination":null,"encrypted_message":"00","salt":"00","nonce":"00"}"#;
+		let message: GrinboxMessage = serde_json::from_str(json).unwrap();
+		assert_eq!(message.version, GRINBOX_MESSAGE_VERSION_LEGACY);
+		assert_eq!(message.reply_version(), GRINBOX_MESSAGE_VERSION_LEGACY);
+	}
+
+	#[test]
+	fn new_messages_stay_legacy_until_the_peer_accepts_more() {
+		let (sender, _, _, receiver_public_key) = keys();
+		let destination = GrinboxAddress::new_raw(
+			receiver_public_key,
+			None,
+			None,
+			GRINRELAY_ADDRESS_HRP_MAINNET.into(),
+		);
+
+		let message = GrinboxMessage::new(
+			PLAINTEXT.to_string(),
+			&destination,
+			&receiver_public_key,
+			&sender,
+		)
+		.unwrap();
+		assert_eq!(message.version, GRINBOX_MESSAGE_VERSION_LEGACY);
+		assert_eq!(message.accepted_versions, GRINBOX_MESSAGE_VERSIONS.to_vec());
+		assert_eq!(message.reply_version(), GRINBOX_MESSAGE_VERSION);
 
-		String::from_utf8(decrypted_data.to_vec()).map_err(|_| ErrorKind::Decryption.into())
+		assert_eq!(
+			GrinboxMessage::version_for(&[]),
+			GRINBOX_MESSAGE_VERSION_LEGACY
+		);
+		assert_eq!(
+			GrinboxMessage::version_for(&[1]),
+			GRINBOX_MESSAGE_VERSION_LEGACY
+		);
+		assert_eq!(
+			GrinboxMessage::version_for(&[1, 2, 3]),
+			GRINBOX_MESSAGE_VERSION
+		);
+		assert_eq!(
+			GrinboxMessage::version_for(&[3]),
+			GRINBOX_MESSAGE_VERSION_LEGACY
+		);
 	}
 }
@@ -13,9 +13,46 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::utils::crypto::SignatureScheme;
 use colored::*;
 use std::fmt::{Display, Formatter, Result};
 
+/// What the signature of a `PostSlate` covers.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum SigningMode {
+	/// The slate alone
+	Slate,
+	/// The slate followed by the relay challenge
+	SlateAndChallenge,
+}
+
+impl SigningMode {
+	/// The mode of a slate relayed without one. Such relays only filled the
+	/// challenge when it was part of the signed text.
+	pub fn from_challenge(challenge: &str) -> SigningMode {
+		match challenge.is_empty() {
+			true => SigningMode::Slate,
+			false => SigningMode::SlateAndChallenge,
+		}
+	}
+
+	/// The exact text covered by the signature.
+	pub fn signed_text(&self, str: &str, challenge: &str) -> String {
+		match *self {
+			SigningMode::Slate => str.to_string(),
+			SigningMode::SlateAndChallenge => format!("{}{}", str, challenge),
+		}
+	}
+}
+
+/// One recipient of a `PostSlateBatch`, with the slate signed for it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BatchSlate {
+	pub to: String,
+	pub str: String,
+	pub signature: String,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(tag = "type")]
 pub enum GrinboxRequest {
@@ -23,6 +60,8 @@ pub enum GrinboxRequest {
 	Subscribe {
 		address: String,
 		signature: String,
+		#[serde(default)]
+		signature_scheme: SignatureScheme,
 	},
 	RetrieveRelayAddr {
 		abbr: String,
@@ -33,10 +72,44 @@ pub enum GrinboxRequest {
 		str: String,
 		signature: String,
 		message_expiration_in_seconds: Option<u32>,
+		#[serde(default)]
+		signature_scheme: SignatureScheme,
+		/// Absent for older clients, in which case both modes are tried.
+		#[serde(default)]
+		signing_mode: Option<SigningMode>,
+		/// Challenge of the relay the slate was first posted to, set when a
+		/// relay forwards a slate signed over its own challenge.
+		#[serde(default)]
+		challenge: Option<String>,
+	},
+	/// Deliver every slate to its recipient, or none of them.
+	PostSlateBatch {
+		from: String,
+		slates: Vec<BatchSlate>,
+		message_expiration_in_seconds: Option<u32>,
+		#[serde(default)]
+		signature_scheme: SignatureScheme,
+		#[serde(default)]
+		signing_mode: Option<SigningMode>,
 	},
 	Unsubscribe {
 		address: String,
 	},
+	/// Reserve `alias` for `address`, signed over `alias_challenge`.
+	RegisterAlias {
+		alias: String,
+		address: String,
+		signature: String,
+		#[serde(default)]
+		signature_scheme: SignatureScheme,
+		#[serde(default)]
+		ttl_in_seconds: Option<u32>,
+		/// Unix time in seconds the registration was signed at
+		timestamp: u64,
+	},
+	ResolveAlias {
+		alias: String,
+	},
 }
 
 impl Display for GrinboxRequest {
@@ -46,6 +119,7 @@ impl Display for GrinboxRequest {
 			GrinboxRequest::Subscribe {
 				ref address,
 				signature: _,
+				signature_scheme: _,
 			} => write!(
 				f,
 				"{} to {}",
@@ -64,6 +138,9 @@ impl Display for GrinboxRequest {
 				str: _,
 				signature: _,
 				message_expiration_in_seconds: _,
+				signature_scheme: _,
+				signing_mode: _,
+				challenge: _,
 			} => write!(
 				f,
 				"{} from {} to {}",
@@ -71,6 +148,34 @@ impl Display for GrinboxRequest {
 				from.bright_green(),
 				to.bright_green()
 			),
+			GrinboxRequest::PostSlateBatch {
+				ref from,
+				ref slates,
+				..
+			} => write!(
+				f,
+				"{} from {} to {} recipients",
+				"PostSlateBatch".bright_purple(),
+				from.bright_green(),
+				slates.len()
+			),
+			GrinboxRequest::RegisterAlias {
+				ref alias,
+				ref address,
+				..
+			} => write!(
+				f,
+				"{} {} for {}",
+				"RegisterAlias".bright_purple(),
+				alias.bright_green(),
+				address.bright_green()
+			),
+			GrinboxRequest::ResolveAlias { ref alias } => write!(
+				f,
+				"{} : {}",
+				"ResolveAlias".bright_purple(),
+				alias.bright_green()
+			),
 			GrinboxRequest::RetrieveRelayAddr { ref abbr } => write!(
 				f,
 				"{} : {}",
@@ -80,3 +185,68 @@ impl Display for GrinboxRequest {
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn batch_slate(to: &str) -> BatchSlate {
+		BatchSlate {
+			to: to.to_string(),
+			str: format!("slate for {}", to),
+			signature: format!("signature for {}", to),
+		}
+	}
+
+	#[test]
+	fn post_slate_batch_round_trip() {
+		let request = GrinboxRequest::PostSlateBatch {
+			from: "gn1sender".to_string(),
+			slates: vec![batch_slate("gn1first"), batch_slate("gn1second")],
+			message_expiration_in_seconds: Some(60),
+			signature_scheme: SignatureScheme::Schnorr,
+			signing_mode: Some(SigningMode::SlateAndChallenge),
+		};
+		let json = serde_json::to_string(&request).unwrap();
+
+		match serde_json::from_str(&json).unwrap() {
+			GrinboxRequest::PostSlateBatch {
+				from,
+				slates,
+				message_expiration_in_seconds,
+				signature_scheme,
+				signing_mode,
+			} => {
+				assert_eq!(from, "gn1sender");
+				assert_eq!(slates.len(), 2);
+				assert_eq!(slates[1].to, "gn1second");
+				assert_eq!(slates[1].str, "slate for gn1second");
+				assert_eq!(slates[1].signature, "signature for gn1second");
+				assert_eq!(message_expiration_in_seconds, Some(60));
+				assert_eq!(signature_scheme, SignatureScheme::Schnorr);
+				assert_eq!(signing_mode, Some(SigningMode::SlateAndChallenge));
+			}
+			request => panic!("unexpected request {}", request),
+		}
+	}
+
+	#[test]
+	fn post_slate_batch_defaults() {
+		let json = r#"{"type":"PostSlateBatch","from":"gn1sender","slates":[]}"#;
+		match serde_json::from_str(json).unwrap() {
+			GrinboxRequest::PostSlateBatch {
+				slates,
+				message_expiration_in_seconds,
+				signature_scheme,
+				signing_mode,
+				..
+			} => {
+				assert!(slates.is_empty());
+				assert_eq!(message_expiration_in_seconds, None);
+				assert_eq!(signature_scheme, SignatureScheme::Ecdsa);
+				assert_eq!(signing_mode, None);
+			}
+			request => panic!("unexpected request {}", request),
+		}
+	}
+}
@@ -13,6 +13,8 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::types::SigningMode;
+use crate::utils::crypto::SignatureScheme;
 use colored::*;
 use failure::Fail;
 use std::fmt::{Display, Formatter, Result};
@@ -33,6 +35,92 @@ pub enum GrinboxError {
 	InvalidRelayAbbr,
 	#[fail(display = "GrinRelay Protocol: not online")]
 	Offline,
+	#[fail(display = "GrinRelay Protocol: invalid address")]
+	InvalidAddress,
+	#[fail(display = "GrinRelay Protocol: address belongs to another chain type")]
+	WrongChain,
+	#[fail(display = "GrinRelay Protocol: challenge expired")]
+	ChallengeExpired,
+	#[fail(display = "GrinRelay Protocol: broker unavailable")]
+	BrokerUnavailable,
+	#[fail(display = "GrinRelay Protocol: remote relay unreachable")]
+	RemoteRelayUnreachable,
+	#[fail(display = "GrinRelay Protocol: relay busy")]
+	Busy,
+	#[fail(display = "GrinRelay Protocol: invalid alias")]
+	InvalidAlias,
+	#[fail(display = "GrinRelay Protocol: alias registered to another address")]
+	AliasTaken,
+	#[fail(display = "GrinRelay Protocol: unknown alias")]
+	UnknownAlias,
+	#[fail(display = "GrinRelay Protocol: address already subscribed from another connection")]
+	AlreadySubscribed,
+	#[fail(display = "GrinRelay Protocol: address holds too many aliases")]
+	TooManyAliases,
+}
+
+impl GrinboxError {
+	/// Stable numeric code of this error, safe for clients to match on.
+	/// Codes are never reused once assigned.
+	pub fn code(&self) -> u32 {
+		match *self {
+			GrinboxError::UnknownError => 1000,
+			GrinboxError::InvalidRequest => 1001,
+			GrinboxError::InvalidSignature => 1002,
+			GrinboxError::InvalidChallenge => 1003,
+			GrinboxError::TooManySubscriptions => 1004,
+			GrinboxError::InvalidRelayAbbr => 1005,
+			GrinboxError::Offline => 1006,
+			GrinboxError::InvalidAddress => 1007,
+			GrinboxError::WrongChain => 1008,
+			GrinboxError::ChallengeExpired => 1009,
+			GrinboxError::InvalidAlias => 1010,
+			GrinboxError::AliasTaken => 1011,
+			GrinboxError::UnknownAlias => 1012,
+			GrinboxError::AlreadySubscribed => 1013,
+			GrinboxError::TooManyAliases => 1014,
+			GrinboxError::BrokerUnavailable => 2000,
+			GrinboxError::RemoteRelayUnreachable => 2001,
+			GrinboxError::Busy => 2002,
+		}
+	}
+
+	/// Suggested delay in seconds before the same request is worth retrying,
+	/// or `None` if retrying the identical request can not succeed.
+	pub fn retry_after(&self) -> Option<u32> {
+		match *self {
+			GrinboxError::Offline => Some(60),
+			GrinboxError::BrokerUnavailable => Some(5),
+			GrinboxError::RemoteRelayUnreachable => Some(30),
+			GrinboxError::Busy => Some(1),
+			_ => None,
+		}
+	}
+
+	/// Look up an error by its numeric code.
+	pub fn from_code(code: u32) -> Option<GrinboxError> {
+		match code {
+			1000 => Some(GrinboxError::UnknownError),
+			1001 => Some(GrinboxError::InvalidRequest),
+			1002 => Some(GrinboxError::InvalidSignature),
+			1003 => Some(GrinboxError::InvalidChallenge),
+			1004 => Some(GrinboxError::TooManySubscriptions),
+			1005 => Some(GrinboxError::InvalidRelayAbbr),
+			1006 => Some(GrinboxError::Offline),
+			1007 => Some(GrinboxError::InvalidAddress),
+			1008 => Some(GrinboxError::WrongChain),
+			1009 => Some(GrinboxError::ChallengeExpired),
+			1010 => Some(GrinboxError::InvalidAlias),
+			1011 => Some(GrinboxError::AliasTaken),
+			1012 => Some(GrinboxError::UnknownAlias),
+			1013 => Some(GrinboxError::AlreadySubscribed),
+			1014 => Some(GrinboxError::TooManyAliases),
+			2000 => Some(GrinboxError::BrokerUnavailable),
+			2001 => Some(GrinboxError::RemoteRelayUnreachable),
+			2002 => Some(GrinboxError::Busy),
+			_ => None,
+		}
+	}
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -42,20 +130,44 @@ pub enum GrinboxResponse {
 	Error {
 		kind: GrinboxError,
 		description: String,
+		#[serde(default)]
+		code: u32,
+		#[serde(default)]
+		retry_after: Option<u32>,
 	},
 	Challenge {
 		str: String,
+		/// Signature schemes accepted by the relay, ECDSA included. Empty from
+		/// older relays, which accept ECDSA only.
+		#[serde(default)]
+		signature_schemes: Vec<SignatureScheme>,
 	},
 	Slate {
 		from: String,
 		str: String,
 		signature: String,
 		challenge: String,
+		#[serde(default)]
+		signature_scheme: SignatureScheme,
+		/// Absent from older relays, see `SigningMode::from_challenge`.
+		#[serde(default)]
+		signing_mode: Option<SigningMode>,
 	},
 	RelayAddr {
 		abbr: String,
 		relay_addr: Vec<String>,
 	},
+	Alias {
+		alias: String,
+		address: String,
+		/// Unix time in seconds at which the registration expires
+		expires_at: u64,
+	},
+	/// The address subscribed from another connection, which took over. The
+	/// relay closes this connection right after.
+	SubscriptionRevoked {
+		address: String,
+	},
 }
 
 impl Display for GrinboxResponse {
@@ -65,8 +177,10 @@ impl Display for GrinboxResponse {
 			GrinboxResponse::Error {
 				ref kind,
 				description: _,
-			} => write!(f, "{}: {}", "error".bright_red(), kind),
-			GrinboxResponse::Challenge { ref str } => {
+				code,
+				retry_after: _,
+			} => write!(f, "{} [{}]: {}", "error".bright_red(), code, kind),
+			GrinboxResponse::Challenge { ref str, .. } => {
 				write!(f, "{} {}", "Challenge".cyan(), str.bright_green())
 			}
 			GrinboxResponse::Slate {
@@ -74,7 +188,26 @@ impl Display for GrinboxResponse {
 				str: _,
 				signature: _,
 				challenge: _,
+				signature_scheme: _,
+				signing_mode: _,
 			} => write!(f, "{} from {}", "Slate".cyan(), from.bright_green()),
+			GrinboxResponse::Alias {
+				ref alias,
+				ref address,
+				expires_at: _,
+			} => write!(
+				f,
+				"{}: {} -> {}",
+				"Alias".cyan(),
+				alias.bright_green(),
+				address.bright_green()
+			),
+			GrinboxResponse::SubscriptionRevoked { ref address } => write!(
+				f,
+				"{} for {}",
+				"SubscriptionRevoked".cyan(),
+				address.bright_green()
+			),
 			GrinboxResponse::RelayAddr {
 				ref abbr,
 				ref relay_addr,
@@ -88,3 +221,36 @@ impl Display for GrinboxResponse {
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const ALL: [GrinboxError; 18] = [
+		GrinboxError::UnknownError,
+		GrinboxError::InvalidRequest,
+		GrinboxError::InvalidSignature,
+		GrinboxError::InvalidChallenge,
+		GrinboxError::TooManySubscriptions,
+		GrinboxError::InvalidRelayAbbr,
+		GrinboxError::Offline,
+		GrinboxError::InvalidAddress,
+		GrinboxError::WrongChain,
+		GrinboxError::ChallengeExpired,
+		GrinboxError::BrokerUnavailable,
+		GrinboxError::RemoteRelayUnreachable,
+		GrinboxError::InvalidAlias,
+		GrinboxError::AliasTaken,
+		GrinboxError::UnknownAlias,
+		GrinboxError::AlreadySubscribed,
+		GrinboxError::TooManyAliases,
+		GrinboxError::Busy,
+	];
+
+	#[test]
+	fn codes_round_trip() {
+		for kind in ALL.iter() {
+			assert_eq!(GrinboxError::from_code(kind.code()).as_ref(), Some(kind));
+		}
+	}
+}
@@ -13,6 +13,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+mod alias;
 mod grinbox_address;
 mod grinbox_message;
 mod grinbox_request;
@@ -23,11 +24,24 @@ pub use grin_wallet_libwallet::Slate;
 pub use parking_lot::{Mutex, MutexGuard};
 pub use std::sync::Arc;
 
+pub use self::alias::{
+	alias_challenge, is_fresh_signature, is_valid_alias, split_alias_address, unix_now,
+	AliasRecord, ALIAS_DEFAULT_TTL_IN_SECONDS, ALIAS_MAX_PER_ADDRESS, ALIAS_MAX_TTL_IN_SECONDS,
+	ALIAS_SIGNATURE_WINDOW_IN_SECONDS,
+};
+#[allow(deprecated)]
+pub use self::grinbox_address::{hrp_bytes, set_running_mode};
 pub use self::grinbox_address::{
-	hrp_bytes, GrinboxAddress, GRINRELAY_ADDRESS_HRP_MAINNET, GRINRELAY_ADDRESS_HRP_TESTNET,
+	ChainTypes, GrinboxAddress, Network, GRINRELAY_ADDRESS_HRP_MAINNET,
+	GRINRELAY_ADDRESS_HRP_TESTNET,
+};
+pub use self::grinbox_message::{
+	GrinboxMessage, GRINBOX_MESSAGE_VERSION, GRINBOX_MESSAGE_VERSIONS,
+	GRINBOX_MESSAGE_VERSION_LEGACY,
 };
-pub use self::grinbox_address::{set_running_mode, ChainTypes};
-pub use self::grinbox_message::GrinboxMessage;
-pub use self::grinbox_request::GrinboxRequest;
+pub use self::grinbox_request::{BatchSlate, GrinboxRequest, SigningMode};
 pub use self::grinbox_response::{GrinboxError, GrinboxResponse};
-pub use self::tx_proof::{ErrorKind as TxProofErrorKind, TxProof};
+pub use self::tx_proof::{
+	TxProof, TxProofFile, VerificationMode, VerificationReport, TX_PROOF_FILE_VERSION,
+};
+pub use crate::error::{TxProofError, TxProofErrorKind};
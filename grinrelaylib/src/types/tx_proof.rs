@@ -13,21 +13,69 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::types::{GrinboxAddress, GrinboxMessage, Slate};
-use crate::utils::crypto::{verify_signature, Hex};
-use crate::utils::secp::{Commitment, SecretKey, Signature};
-
-#[derive(Debug)]
-pub enum ErrorKind {
-	ParseAddress,
-	ParsePublicKey,
-	ParseSignature,
-	VerifySignature,
-	ParseGrinboxMessage,
-	VerifyDestination,
-	DecryptionKey,
-	DecryptMessage,
-	ParseSlate,
+use failure::{Fail, ResultExt};
+
+use crate::error::{TxProofError, TxProofErrorKind as ErrorKind};
+use crate::types::{GrinboxAddress, GrinboxMessage, SigningMode, Slate};
+use crate::utils::crypto::{
+	public_key_from_secret_key, sign_challenge, verify_schnorr_signature, verify_signature, Hex,
+	SchnorrSignature, SignatureScheme,
+};
+use crate::utils::secp::{Commitment, PublicKey, SecretKey, Signature};
+use crate::utils::to_hex;
+
+/// Current version of the exported proof file format.
+pub const TX_PROOF_FILE_VERSION: u16 = 1;
+
+/// Leads the signed payload of a proof file, so the exporter signature can not
+/// be mistaken for a signature over anything else.
+const TX_PROOF_FILE_DOMAIN: &str = "grinrelay-tx-proof-file";
+
+/// How strictly the destination recorded in a proof is checked. Destinations
+/// are compared by public key, the relay they were reached through and the
+/// way the addresses were built do not matter.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VerificationMode {
+	/// The message must name the expected destination. Without
+	/// an expected destination nothing can match, so verification always fails
+	/// with `VerifyDestination`.
+	Strict,
+	/// Only a destination present on both sides has to match. Proofs created by
+	/// older wallets carry no destination and are accepted for any address.
+	Lenient,
+}
+
+/// Outcome of each step of a proof verification.
+#[derive(Debug, Default)]
+pub struct VerificationReport {
+	pub signature_ok: bool,
+	pub destination_ok: bool,
+	pub decryption_ok: bool,
+	pub slate_parsed: bool,
+	pub destination: Option<GrinboxAddress>,
+	pub slate: Option<Slate>,
+	/// The first step that failed, if any.
+	pub error: Option<TxProofError>,
+}
+
+impl VerificationReport {
+	pub fn is_ok(&self) -> bool {
+		self.error.is_none()
+	}
+
+	fn fail<E: Into<TxProofError>>(&mut self, error: E) {
+		if self.error.is_none() {
+			self.error = Some(error.into());
+		}
+	}
+
+	pub fn into_result(self) -> Result<(Option<GrinboxAddress>, Slate), TxProofError> {
+		match (self.error, self.slate) {
+			(None, Some(slate)) => Ok((self.destination, slate)),
+			(Some(error), _) => Err(error),
+			(None, None) => Err(ErrorKind::ParseSlate.into()),
+		}
+	}
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -35,7 +83,15 @@ pub struct TxProof {
 	pub address: GrinboxAddress,
 	pub message: String,
 	pub challenge: String,
-	pub signature: Signature,
+	/// The sender's signature, for ECDSA signed slates
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub signature: Option<Signature>,
+	/// The sender's signature, for Schnorr signed slates
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub schnorr_signature: Option<SchnorrSignature>,
+	/// What the signature covers, absent from proofs of older wallets.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub signing_mode: Option<SigningMode>,
 	pub key: [u8; 32],
 	pub amount: u64,
 	pub fee: u64,
@@ -44,41 +100,165 @@ pub struct TxProof {
 }
 
 impl TxProof {
-	pub fn verify_extract(
+	/// Run every verification step and report the outcome of each of them.
+	pub fn verify_report(
 		&self,
 		expected_destination: Option<&GrinboxAddress>,
-	) -> Result<(Option<GrinboxAddress>, Slate), ErrorKind> {
-		let mut challenge = String::new();
-		challenge.push_str(self.message.as_str());
-		challenge.push_str(self.challenge.as_str());
+		mode: VerificationMode,
+	) -> VerificationReport {
+		let mut report = VerificationReport::default();
 
-		let public_key = self
-			.address
-			.public_key()
-			.map_err(|_| ErrorKind::ParsePublicKey)?;
+		let challenge = self.signed_text();
 
-		verify_signature(&challenge, &self.signature, &public_key)
-			.map_err(|_| ErrorKind::VerifySignature)?;
+		match self.address.public_key() {
+			Ok(public_key) => match self.verify_signature(&challenge, &public_key) {
+				Ok(()) => report.signature_ok = true,
+				Err(e) => report.fail(e.context(ErrorKind::VerifySignature)),
+			},
+			Err(e) => report.fail(e.context(ErrorKind::ParsePublicKey)),
+		}
 
-		let grinbox_message: GrinboxMessage =
-			serde_json::from_str(&self.message).map_err(|_| ErrorKind::ParseGrinboxMessage)?;
+		let grinbox_message: GrinboxMessage = match serde_json::from_str(&self.message) {
+			Ok(grinbox_message) => grinbox_message,
+			Err(e) => {
+				report.fail(e.context(ErrorKind::ParseGrinboxMessage));
+				return report;
+			}
+		};
 
-		// TODO: at some point, make this check required
 		let destination = grinbox_message.destination.clone();
-		if destination.is_some()
-			&& expected_destination.is_some()
-			&& destination.as_ref() != expected_destination
-		{
-			return Err(ErrorKind::VerifyDestination);
+		let matches = match (&destination, expected_destination) {
+			(Some(destination), Some(expected)) => destination.public_key == expected.public_key,
+			_ => false,
+		};
+		report.destination_ok = match mode {
+			VerificationMode::Strict => matches,
+			VerificationMode::Lenient => {
+				destination.is_none() || expected_destination.is_none() || matches
+			}
+		};
+		if !report.destination_ok {
+			report.fail(ErrorKind::VerifyDestination);
 		}
+		report.destination = destination;
+
+		let decrypted_message = match grinbox_message.decrypt_with_key(&self.key) {
+			Ok(decrypted_message) => decrypted_message,
+			Err(e) => {
+				report.fail(e.context(ErrorKind::DecryptMessage));
+				return report;
+			}
+		};
+		report.decryption_ok = true;
+
+		match serde_json::from_str(&decrypted_message) {
+			Ok(slate) => {
+				report.slate_parsed = true;
+				report.slate = Some(slate);
+			}
+			Err(e) => report.fail(e.context(ErrorKind::ParseSlate)),
+		}
+
+		report
+	}
 
-		let decrypted_message = grinbox_message
-			.decrypt_with_key(&self.key)
-			.map_err(|_| ErrorKind::DecryptMessage)?;
+	pub fn verify_extract(
+		&self,
+		expected_destination: Option<&GrinboxAddress>,
+		mode: VerificationMode,
+	) -> Result<(Option<GrinboxAddress>, Slate), TxProofError> {
+		self.verify_report(expected_destination, mode).into_result()
+	}
 
-		serde_json::from_str(&decrypted_message)
-			.map(|message| (destination, message))
-			.map_err(|_| ErrorKind::ParseSlate)
+	/// The text the sender signed, as relayed.
+	pub fn signed_text(&self) -> String {
+		self.signing_mode
+			.unwrap_or_else(|| SigningMode::from_challenge(&self.challenge))
+			.signed_text(&self.message, &self.challenge)
+	}
+
+	fn verify_signature(
+		&self,
+		challenge: &str,
+		public_key: &PublicKey,
+	) -> crate::error::Result<()> {
+		match (&self.signature, &self.schnorr_signature) {
+			(Some(signature), _) => verify_signature(challenge, signature, public_key),
+			(None, Some(signature)) => verify_schnorr_signature(challenge, signature, public_key),
+			(None, None) => Err(crate::error::ErrorKind::SecpError.into()),
+		}
+	}
+
+	/// Build a proof whose amount, fee and commitments are taken from the slate.
+	pub fn from_slate(
+		address: GrinboxAddress,
+		message: String,
+		challenge: String,
+		signature: Option<Signature>,
+		schnorr_signature: Option<SchnorrSignature>,
+		signing_mode: Option<SigningMode>,
+		key: [u8; 32],
+		slate: &Slate,
+	) -> TxProof {
+		let (inputs, outputs) = slate_commitments(slate);
+		TxProof {
+			address,
+			message,
+			challenge,
+			signature,
+			schnorr_signature,
+			signing_mode,
+			key,
+			amount: slate.amount,
+			fee: slate.fee,
+			inputs,
+			outputs,
+		}
+	}
+
+	/// Verify the proof and check that its amount, fee and commitments match
+	/// the slate it carries.
+	pub fn verify(
+		&self,
+		expected_destination: Option<&GrinboxAddress>,
+		mode: VerificationMode,
+	) -> Result<(Option<GrinboxAddress>, Slate), TxProofError> {
+		let (destination, slate) = self.verify_extract(expected_destination, mode)?;
+
+		if self.amount != slate.amount || self.fee != slate.fee {
+			return Err(ErrorKind::VerifyAmount.into());
+		}
+
+		let (inputs, outputs) = slate_commitments(&slate);
+		if self.inputs != inputs || self.outputs != outputs {
+			return Err(ErrorKind::VerifyCommitments.into());
+		}
+
+		Ok((destination, slate))
+	}
+
+	/// Wrap the proof into a file signed by the exporting wallet.
+	pub fn export(
+		self,
+		exporter: GrinboxAddress,
+		secret_key: &SecretKey,
+	) -> Result<TxProofFile, TxProofError> {
+		let public_key = public_key_from_secret_key(secret_key).context(ErrorKind::SignExport)?;
+		if exporter.public_key().ok() != Some(public_key) {
+			return Err(ErrorKind::SignExport.into());
+		}
+
+		let mut file = TxProofFile {
+			version: TX_PROOF_FILE_VERSION,
+			exporter,
+			proof: self,
+			signature: String::new(),
+		};
+		let signature =
+			sign_challenge(&file.signing_payload(), secret_key).context(ErrorKind::SignExport)?;
+		file.signature = signature.to_hex();
+
+		Ok(file)
 	}
 
 	pub fn from_response(
@@ -86,27 +266,41 @@ impl TxProof {
 		message: String,
 		challenge: String,
 		signature: String,
+		signature_scheme: SignatureScheme,
+		signing_mode: Option<SigningMode>,
 		secret_key: &SecretKey,
 		expected_destination: Option<&GrinboxAddress>,
-	) -> Result<(Slate, TxProof), ErrorKind> {
-		let address =
-			GrinboxAddress::from_str(from.as_str()).map_err(|_| ErrorKind::ParseAddress)?;
-		let signature =
-			Signature::from_hex(signature.as_str()).map_err(|_| ErrorKind::ParseSignature)?;
-		let public_key = address
-			.public_key()
-			.map_err(|_| ErrorKind::ParsePublicKey)?;
+		mode: VerificationMode,
+	) -> Result<(Slate, TxProof), TxProofError> {
+		let address = from
+			.parse::<GrinboxAddress>()
+			.context(ErrorKind::ParseAddress)?;
+		let (signature, schnorr_signature) = match signature_scheme {
+			SignatureScheme::Ecdsa => {
+				let signature =
+					Signature::from_hex(signature.as_str()).context(ErrorKind::ParseSignature)?;
+				(Some(signature), None)
+			}
+			SignatureScheme::Schnorr => {
+				let signature = SchnorrSignature::from_hex(signature.as_str())
+					.context(ErrorKind::ParseSignature)?;
+				(None, Some(signature))
+			}
+		};
+		let public_key = address.public_key().context(ErrorKind::ParsePublicKey)?;
 		let grinbox_message: GrinboxMessage =
-			serde_json::from_str(&message).map_err(|_| ErrorKind::ParseGrinboxMessage)?;
+			serde_json::from_str(&message).context(ErrorKind::ParseGrinboxMessage)?;
 		let key = grinbox_message
 			.key(&public_key, secret_key)
-			.map_err(|_| ErrorKind::DecryptionKey)?;
+			.context(ErrorKind::DecryptionKey)?;
 
-		let proof = TxProof {
+		let unverified = TxProof {
 			address,
 			message,
 			challenge,
 			signature,
+			schnorr_signature,
+			signing_mode,
 			key,
 			amount: 0,
 			fee: 0,
@@ -114,8 +308,368 @@ impl TxProof {
 			outputs: vec![],
 		};
 
-		let (_, slate) = proof.verify_extract(expected_destination)?;
+		let (_, slate) = unverified.verify_extract(expected_destination, mode)?;
+
+		let proof = TxProof::from_slate(
+			unverified.address,
+			unverified.message,
+			unverified.challenge,
+			unverified.signature,
+			unverified.schnorr_signature,
+			unverified.signing_mode,
+			unverified.key,
+			&slate,
+		);
 
 		Ok((slate, proof))
 	}
 }
+
+fn slate_commitments(slate: &Slate) -> (Vec<Commitment>, Vec<Commitment>) {
+	let inputs = slate
+		.tx
+		.inputs()
+		.iter()
+		.map(|input| Commitment::from_vec(input.commit.0.to_vec()))
+		.collect();
+	let outputs = slate
+		.tx
+		.outputs()
+		.iter()
+		.map(|output| Commitment::from_vec(output.commit.0.to_vec()))
+		.collect();
+	(inputs, outputs)
+}
+
+fn push_field(payload: &mut String, field: &str) {
+	payload.push_str(&field.len().to_string());
+	payload.push(':');
+	payload.push_str(field);
+}
+
+/// A proof as shared with a third party, e.g. to settle a payment dispute.
+/// The exporting wallet signs the proof so the file can not be altered.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TxProofFile {
+	pub version: u16,
+	pub exporter: GrinboxAddress,
+	pub proof: TxProof,
+	pub signature: String,
+}
+
+impl TxProofFile {
+	pub fn from_json(json: &str) -> Result<TxProofFile, TxProofError> {
+		Ok(serde_json::from_str(json).context(ErrorKind::ParseProofFile)?)
+	}
+
+	pub fn to_json(&self) -> String {
+		serde_json::to_string_pretty(self).unwrap()
+	}
+
+	/// Everything the exporter signs, each field prefixed with its length so
+	/// no two different files share a payload.
+	fn signing_payload(&self) -> String {
+		let proof = &self.proof;
+		let signing_mode = match proof.signing_mode {
+			Some(SigningMode::Slate) => "slate",
+			Some(SigningMode::SlateAndChallenge) => "slate_and_challenge",
+			None => "",
+		};
+
+		let mut payload = String::new();
+		for field in &[
+			TX_PROOF_FILE_DOMAIN.to_string(),
+			self.version.to_string(),
+			self.exporter.stripped(),
+			proof.address.stripped(),
+			proof.message.clone(),
+			proof.challenge.clone(),
+			proof
+				.signature
+				.as_ref()
+				.map(|s| s.to_hex())
+				.unwrap_or_default(),
+			proof
+				.schnorr_signature
+				.as_ref()
+				.map(|s| s.to_hex())
+				.unwrap_or_default(),
+			signing_mode.to_string(),
+			to_hex(proof.key.to_vec()),
+			proof.amount.to_string(),
+			proof.fee.to_string(),
+			proof.inputs.len().to_string(),
+			proof.outputs.len().to_string(),
+		] {
+			push_field(&mut payload, field);
+		}
+		for commit in proof.inputs.iter().chain(proof.outputs.iter()) {
+			push_field(&mut payload, &commit.to_hex());
+		}
+		payload
+	}
+
+	/// Check the exporter signature, then the proof itself including its
+	/// amount, fee and commitments against the slate.
+	pub fn verify(
+		&self,
+		expected_destination: Option<&GrinboxAddress>,
+		mode: VerificationMode,
+	) -> Result<(Option<GrinboxAddress>, Slate), TxProofError> {
+		if self.version != TX_PROOF_FILE_VERSION {
+			return Err(ErrorKind::UnsupportedVersion.into());
+		}
+
+		let public_key = self
+			.exporter
+			.public_key()
+			.context(ErrorKind::ParsePublicKey)?;
+		let signature = Signature::from_hex(&self.signature).context(ErrorKind::ParseSignature)?;
+		verify_signature(&self.signing_payload(), &signature, &public_key)
+			.context(ErrorKind::VerifyExportSignature)?;
+
+		self.proof.verify(expected_destination, mode)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::types::{ChainTypes, GRINRELAY_ADDRESS_HRP_MAINNET};
+
+	const SENDER_SECRET_KEY: &str =
+		"1111111111111111111111111111111111111111111111111111111111111111";
+	const RECEIVER_SECRET_KEY: &str =
+		"2222222222222222222222222222222222222222222222222222222222222222";
+
+	fn keys(secret_key: &str) -> (SecretKey, PublicKey, GrinboxAddress) {
+		let secret_key = SecretKey::from_hex(secret_key).unwrap();
+		let public_key = public_key_from_secret_key(&secret_key).unwrap();
+		let address =
+			GrinboxAddress::new_raw(public_key, None, None, GRINRELAY_ADDRESS_HRP_MAINNET.into());
+		(secret_key, public_key, address)
+	}
+
+	/// A proof of a slate sent by the sender, as kept by the receiver.
+	fn sent_proof() -> (TxProof, GrinboxAddress) {
+		let (sender_secret_key, sender_public_key, sender) = keys(SENDER_SECRET_KEY);
+		let (receiver_secret_key, receiver_public_key, receiver) = keys(RECEIVER_SECRET_KEY);
+
+		let mut slate = Slate::blank(2);
+		slate.amount = 42;
+		slate.fee = 8;
+
+		let grinbox_message = GrinboxMessage::new(
+			serde_json::to_string(&slate).unwrap(),
+			&receiver,
+			&receiver_public_key,
+			&sender_secret_key,
+		)
+		.unwrap();
+		let key = grinbox_message
+			.key(&sender_public_key, &receiver_secret_key)
+			.unwrap();
+		let message = serde_json::to_string(&grinbox_message).unwrap();
+		let signature = sign_challenge(&message, &sender_secret_key).unwrap();
+
+		let proof = TxProof::from_slate(
+			sender,
+			message,
+			String::new(),
+			Some(signature),
+			None,
+			Some(SigningMode::Slate),
+			key,
+			&slate,
+		);
+		(proof, receiver)
+	}
+
+	fn exported() -> TxProofFile {
+		let (proof, receiver) = sent_proof();
+		let (receiver_secret_key, _, _) = keys(RECEIVER_SECRET_KEY);
+		proof.export(receiver, &receiver_secret_key).unwrap()
+	}
+
+	fn commit(byte: u8) -> Commitment {
+		Commitment::from_vec(vec![byte; 33])
+	}
+
+	#[test]
+	fn from_slate_takes_amount_fee_and_commitments() {
+		let (proof, _) = sent_proof();
+		assert_eq!(proof.amount, 42);
+		assert_eq!(proof.fee, 8);
+		assert!(proof.inputs.is_empty());
+		assert!(proof.outputs.is_empty());
+	}
+
+	#[test]
+	fn verify_proof() {
+		let (proof, receiver) = sent_proof();
+		let (destination, slate) = proof
+			.verify(Some(&receiver), VerificationMode::Strict)
+			.unwrap();
+		assert_eq!(destination, Some(receiver));
+		assert_eq!(slate.amount, 42);
+
+		let (_, _, sender) = keys(SENDER_SECRET_KEY);
+		let err = proof
+			.verify(Some(&sender), VerificationMode::Strict)
+			.unwrap_err();
+		assert_eq!(err.kind(), ErrorKind::VerifyDestination);
+	}
+
+	#[test]
+	fn destinations_match_whatever_built_them() {
+		let (proof, receiver) = sent_proof();
+		let expected = [
+			GrinboxAddress::parse(&receiver.public_key, &ChainTypes::Mainnet).unwrap(),
+			GrinboxAddress::from_str_raw(&receiver.public_key).unwrap(),
+			GrinboxAddress::from_str_raw(&format!(
+				"{}@relay.example.com:13420",
+				receiver.public_key
+			))
+			.unwrap(),
+		];
+		assert_eq!(expected[0].hrp_bytes, None);
+		assert!(expected[1].hrp_bytes.is_some());
+
+		for (i, expected) in expected.iter().enumerate() {
+			for mode in &[VerificationMode::Strict, VerificationMode::Lenient] {
+				let report = proof.verify_report(Some(expected), *mode);
+				assert!(report.destination_ok, "{} {:?}", i, mode);
+			}
+		}
+	}
+
+	#[test]
+	fn strict_mode_requires_an_expected_destination() {
+		let (proof, _) = sent_proof();
+		let err = proof.verify(None, VerificationMode::Strict).unwrap_err();
+		assert_eq!(err.kind(), ErrorKind::VerifyDestination);
+
+		let report = proof.verify_report(None, VerificationMode::Strict);
+		assert!(report.signature_ok);
+		assert!(!report.destination_ok);
+
+		assert!(proof.verify(None, VerificationMode::Lenient).is_ok());
+	}
+
+	#[test]
+	fn verify_rejects_tampered_proof() {
+		let (mut proof, receiver) = sent_proof();
+		proof.amount += 1;
+		let err = proof
+			.verify(Some(&receiver), VerificationMode::Strict)
+			.unwrap_err();
+		assert_eq!(err.kind(), ErrorKind::VerifyAmount);
+
+		let (mut proof, receiver) = sent_proof();
+		proof.outputs.push(commit(9));
+		let err = proof
+			.verify(Some(&receiver), VerificationMode::Strict)
+			.unwrap_err();
+		assert_eq!(err.kind(), ErrorKind::VerifyCommitments);
+
+		let (mut proof, receiver) = sent_proof();
+		proof.challenge = "challenge".to_string();
+		proof.signing_mode = Some(SigningMode::SlateAndChallenge);
+		let err = proof
+			.verify(Some(&receiver), VerificationMode::Strict)
+			.unwrap_err();
+		assert_eq!(err.kind(), ErrorKind::VerifySignature);
+	}
+
+	#[test]
+	fn export_requires_the_exporter_key() {
+		let (proof, receiver) = sent_proof();
+		let (sender_secret_key, _, _) = keys(SENDER_SECRET_KEY);
+		let err = proof.export(receiver, &sender_secret_key).unwrap_err();
+		assert_eq!(err.kind(), ErrorKind::SignExport);
+	}
+
+	#[test]
+	fn exported_file_round_trips() {
+		let file = TxProofFile::from_json(&exported().to_json()).unwrap();
+		assert_eq!(file.version, TX_PROOF_FILE_VERSION);
+
+		let (_, _, receiver) = keys(RECEIVER_SECRET_KEY);
+		let (_, slate) = file
+			.verify(Some(&receiver), VerificationMode::Strict)
+			.unwrap();
+		assert_eq!(slate.amount, 42);
+	}
+
+	#[test]
+	fn exported_file_rejects_tampering() {
+		let (_, _, receiver) = keys(RECEIVER_SECRET_KEY);
+		let (_, _, sender) = keys(SENDER_SECRET_KEY);
+		let tamperings: Vec<Box<dyn Fn(&mut TxProofFile)>> = vec![
+			Box::new(|file| file.proof.amount += 1),
+			Box::new(|file| file.proof.fee += 1),
+			Box::new(|file| file.proof.key[0] ^= 1),
+			Box::new(|file| file.proof.challenge.push('x')),
+			Box::new(|file| file.proof.signing_mode = None),
+			Box::new(|file| file.proof.signature = None),
+			Box::new(|file| file.proof.address.domain = "example.com".to_string()),
+			Box::new(|file| file.proof.inputs.push(commit(1))),
+			Box::new(|file| file.exporter.port += 1),
+		];
+
+		for (i, tamper) in tamperings.iter().enumerate() {
+			let mut file = exported();
+			tamper(&mut file);
+			let err = file
+				.verify(Some(&receiver), VerificationMode::Strict)
+				.unwrap_err();
+			assert_eq!(err.kind(), ErrorKind::VerifyExportSignature, "{}", i);
+		}
+
+		let mut file = exported();
+		file.exporter = sender;
+		let err = file
+			.verify(Some(&receiver), VerificationMode::Strict)
+			.unwrap_err();
+		assert_eq!(err.kind(), ErrorKind::VerifyExportSignature);
+
+		let mut file = exported();
+		file.version += 1;
+		let err = file
+			.verify(Some(&receiver), VerificationMode::Strict)
+			.unwrap_err();
+		assert_eq!(err.kind(), ErrorKind::UnsupportedVersion);
+	}
+
+	#[test]
+	fn signing_payload_is_unambiguous() {
+		let (_, _, address) = keys(SENDER_SECRET_KEY);
+		let file = |message: &str, challenge: &str, inputs, outputs| TxProofFile {
+			version: TX_PROOF_FILE_VERSION,
+			exporter: address.clone(),
+			proof: TxProof {
+				address: address.clone(),
+				message: message.to_string(),
+				challenge: challenge.to_string(),
+				signature: None,
+				schnorr_signature: None,
+				signing_mode: None,
+				key: [0; 32],
+				amount: 0,
+				fee: 0,
+				inputs,
+				outputs,
+			},
+			signature: String::new(),
+		};
+
+		assert_ne!(
+			file("a|b", "c", vec![], vec![]).signing_payload(),
+			file("a", "b|c", vec![], vec![]).signing_payload()
+		);
+		assert_ne!(
+			file("a", "", vec![commit(1)], vec![]).signing_payload(),
+			file("a", "", vec![], vec![commit(1)]).signing_payload()
+		);
+	}
+}
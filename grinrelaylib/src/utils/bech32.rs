@@ -21,18 +21,23 @@
 
 //! Encode and decode the Bech32 format, with checksums
 //!
+//! Both the original BIP-173 checksum and the BIP-350 bech32m one are
+//! supported, the variant of a decoded string is detected from its checksum.
+//! Grinrelay addresses may be split in groups with `-` for readability, such
+//! separators are only accepted in the data part.
+//!
 //! # Examples
 //! ```rust
-//! use bech32::Bech32;
+//! use grinrelaylib::utils::bech32::Bech32;
 //!
-//! let addr = Bech32 {
-//!            hrp: "gn".to_string(),
-//!            data: vec![
+//! let addr = Bech32::new(
+//!            "gn",
+//!            vec![
 //!                0x11, 0x6a, 0x20, 0x76, 0x58, 0x22, 0x1a, 0xa9, 0xa0, 0x4b, 0xe2,
 //!                0xfa, 0x6e, 0xbf, 0x28, 0x51, 0xdd, 0xf0, 0x36, 0x8d, 0x7a, 0x74,
 //!                0x94, 0x34, 0xc9, 0xd1, 0x2c, 0x8f, 0xc8, 0x2a, 0xa8, 0x11, 0xf8,
-//!            ]
-//!        };
+//!            ],
+//!        );
 //!
 //! let bech32_addr = addr.to_string(false).unwrap();
 //! assert_eq!(bech32_addr, "gn1z94zqajcygd2ngztutaxa0eg28wlqd5d0f6fgdxf6ykgljp24qglsmstrr5".to_string());
@@ -55,6 +60,18 @@ pub enum CodingError {
 	/// The data or human-readable part is too long or too short
 	#[fail(display = "Invalid Length Error")]
 	InvalidLength,
+	/// The string is longer than the length policy allows
+	#[fail(display = "Too Long Error: {} characters, at most {} allowed", length, max)]
+	TooLong { length: usize, max: usize },
+	/// A `-` separator of the data part is doubled or trailing
+	#[fail(display = "Invalid Separator Error at position {}", 0)]
+	InvalidSeparator(usize),
+	/// The human-readable part is not the expected one
+	#[fail(
+		display = "Wrong Human Readable Part Error: expected {}, found {}",
+		expected, found
+	)]
+	WrongHrp { expected: String, found: String },
 	/// Some part of the string contains an invalid character
 	#[fail(display = "Invalid Char Error")]
 	InvalidChar,
@@ -62,7 +79,7 @@ pub enum CodingError {
 	#[fail(display = "Invalid Data Error")]
 	InvalidData,
 	/// The whole string must be of one case
-	#[fail(display = "Mised Case Error")]
+	#[fail(display = "Mixed Case Error")]
 	MixedCase,
 	/// Some AddressError
 	#[fail(display = "\x1b[31;1merror:\x1b[0m address error `{}`", 0)]
@@ -83,6 +100,63 @@ pub enum AddressError {
 	InvalidHumanReadablePart,
 }
 
+/// Checksum variant, see BIP-350
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum Variant {
+	/// The original BIP-173 checksum
+	Bech32,
+	/// The BIP-350 checksum
+	Bech32m,
+}
+
+impl Default for Variant {
+	fn default() -> Variant {
+		Variant::Bech32
+	}
+}
+
+impl Variant {
+	fn constant(&self) -> u32 {
+		match *self {
+			Variant::Bech32 => 1,
+			Variant::Bech32m => 0x2bc830a3,
+		}
+	}
+
+	fn from_constant(constant: u32) -> Option<Variant> {
+		match constant {
+			1 => Some(Variant::Bech32),
+			0x2bc830a3 => Some(Variant::Bech32m),
+			_ => None,
+		}
+	}
+}
+
+/// Maximum length of a string, `-` separators excluded
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LengthPolicy {
+	/// At most 90 characters, as required by BIP-173
+	Bip173,
+	/// At most the given number of characters
+	Max(usize),
+	/// No upper bound
+	Unbounded,
+}
+
+impl LengthPolicy {
+	fn check(&self, length: usize) -> Result<(), CodingError> {
+		let max = match *self {
+			LengthPolicy::Bip173 => BIP173_MAX_LENGTH,
+			LengthPolicy::Max(max) => max,
+			LengthPolicy::Unbounded => return Ok(()),
+		};
+		match length > max {
+			true => Err(CodingError::TooLong { length, max }),
+			false => Ok(()),
+		}
+	}
+}
+
 /// Grouping structure for the human-readable part and the data part
 /// of decoded Bech32 string.
 #[derive(Clone, Debug, Eq, Fail, PartialEq, Serialize, Deserialize)]
@@ -91,6 +165,9 @@ pub struct Bech32 {
 	pub hrp: String,
 	/// Data payload
 	pub data: Vec<u8>,
+	/// Checksum variant
+	#[serde(default)]
+	pub variant: Variant,
 }
 
 impl fmt::Display for Bech32 {
@@ -107,6 +184,11 @@ impl fmt::Display for Bech32 {
 // Human-readable part and data part separator
 const SEP: char = '1';
 
+// Readability separator inside the data part
+const GROUP_SEP: char = '-';
+
+const BIP173_MAX_LENGTH: usize = 90;
+
 // Encoding character set. Maps data value -> char
 const CHARSET: [char; 32] = [
 	'q', 'p', 'z', 'r', 'y', '9', 'x', '8', 'g', 'f', '2', 't', 'v', 'd', 'w', '0', 's', '3', 'j',
@@ -127,8 +209,22 @@ type EncodeResult = Result<String, CodingError>;
 type DecodeResult = Result<Bech32, CodingError>;
 
 impl Bech32 {
-	/// Encode as a string
+	/// A payload with the original bech32 checksum
+	pub fn new(hrp: &str, data: Vec<u8>) -> Bech32 {
+		Bech32 {
+			hrp: hrp.to_string(),
+			data,
+			variant: Variant::Bech32,
+		}
+	}
+
+	/// Encode as a string, whatever its length
 	pub fn to_string(&self, split: bool) -> EncodeResult {
+		self.to_string_with(split, LengthPolicy::Unbounded)
+	}
+
+	/// Encode as a string no longer than `policy` allows, separators excluded
+	pub fn to_string_with(&self, split: bool, policy: LengthPolicy) -> EncodeResult {
 		if self.hrp.len() < 1 {
 			return Err(CodingError::InvalidLength);
 		}
@@ -141,7 +237,7 @@ impl Bech32 {
 		};
 
 		let pure_data = combined.clone();
-		combined.extend_from_slice(&create_checksum(&hrp_bytes, &pure_data));
+		combined.extend_from_slice(&create_checksum(&hrp_bytes, &pure_data, self.variant));
 		let mut encoded: String = String::with_capacity(128);
 		encoded.push_str(format!("{}{}", self.hrp, SEP).as_str());
 		let start_pos = encoded.len();
@@ -151,6 +247,7 @@ impl Bech32 {
 			}
 			encoded.push(CHARSET[p as usize]);
 		}
+		policy.check(encoded.len())?;
 
 		if split {
 			if encoded.len() > start_pos + 16 {
@@ -172,108 +269,156 @@ impl Bech32 {
 		Ok(encoded)
 	}
 
-	/// Decode from a string
+	/// Decode from a string of at most 90 characters, separators excluded
 	pub fn from_string(bech32_addr: &str) -> DecodeResult {
-		let mut s: String = bech32_addr.to_owned();
-		s.retain(|c| c != '-');
+		Bech32::from_string_with(bech32_addr, LengthPolicy::Bip173)
+	}
 
-		// Ensure overall length is within bounds
-		let len: usize = s.len();
-		if len < 8 || len > 90 {
-			return Err(CodingError::InvalidLength);
-		}
+	/// Decode from a string no longer than `policy` allows
+	pub fn from_string_with(bech32_addr: &str, policy: LengthPolicy) -> DecodeResult {
+		let (hrp, data, variant) = decode_raw(bech32_addr, policy)?;
+		Ok(Bech32 {
+			hrp,
+			// Convert to 8-bit program and assign
+			data: match convert_bits(data, 5, 8, false) {
+				Ok(p) => p,
+				Err(e) => return Err(CodingError::Address(AddressError::Conversion(e))),
+			},
+			variant,
+		})
+	}
 
-		// Check for missing separator
-		if s.find(SEP).is_none() {
-			return Err(CodingError::MissingSeparator);
+	/// Decode from a string which must have the `expected` human-readable part
+	pub fn from_string_with_hrp(
+		bech32_addr: &str,
+		expected: &str,
+		policy: LengthPolicy,
+	) -> DecodeResult {
+		let decoded = Bech32::from_string_with(bech32_addr, policy)?;
+		if decoded.hrp != expected.to_lowercase() {
+			return Err(CodingError::WrongHrp {
+				expected: expected.to_string(),
+				found: decoded.hrp,
+			});
 		}
+		Ok(decoded)
+	}
+}
 
-		// Split at separator and check for two pieces
-		let parts: Vec<&str> = s.rsplitn(2, SEP).collect();
-		let raw_hrp = parts[1];
-		let raw_data = parts[0];
-		if raw_hrp.len() < 1 || raw_data.len() < 6 {
-			return Err(CodingError::InvalidLength);
-		}
+/// Decode the human-readable part and the 5-bit data values, checksum removed.
+pub fn decode_raw(
+	bech32_addr: &str,
+	policy: LengthPolicy,
+) -> Result<(String, Vec<u8>, Variant), CodingError> {
+	// Check for missing separator. The data part can not contain it, so the
+	// last one splits the string.
+	let sep_pos = match bech32_addr.rfind(SEP) {
+		Some(pos) => pos,
+		None => return Err(CodingError::MissingSeparator),
+	};
+	let raw_hrp = &bech32_addr[..sep_pos];
+	let raw_data = strip_group_separators(&bech32_addr[sep_pos + 1..], sep_pos + 1)?;
+
+	// Ensure overall length is within bounds
+	let len: usize = raw_hrp.len() + 1 + raw_data.len();
+	if len < 8 {
+		return Err(CodingError::InvalidLength);
+	}
+	policy.check(len)?;
 
-		let mut has_lower: bool = false;
-		let mut has_upper: bool = false;
-		let mut hrp_bytes: Vec<u8> = Vec::new();
-		for b in raw_hrp.bytes() {
-			// Valid subset of ASCII
-			if b < 33 || b > 126 {
-				return Err(CodingError::InvalidChar);
-			}
-			let mut c = b;
-			// Lowercase
-			if b >= b'a' && b <= b'z' {
-				has_lower = true;
-			}
-			// Uppercase
-			if b >= b'A' && b <= b'Z' {
-				has_upper = true;
-				// Convert to lowercase
-				c = b + (b'a' - b'A');
-			}
-			hrp_bytes.push(c);
-		}
+	if raw_hrp.len() < 1 || raw_data.len() < 6 {
+		return Err(CodingError::InvalidLength);
+	}
 
-		// Check data payload
-		let mut data_bytes: Vec<u8> = Vec::new();
-		for b in raw_data.bytes() {
-			// Aphanumeric only
-			if !((b >= b'0' && b <= b'9') || (b >= b'A' && b <= b'Z') || (b >= b'a' && b <= b'z')) {
-				return Err(CodingError::InvalidChar);
-			}
-			// Excludes these characters: [1,b,i,o]
-			if b == b'1' || b == b'b' || b == b'i' || b == b'o' {
-				return Err(CodingError::InvalidChar);
-			}
-			// Lowercase
-			if b >= b'a' && b <= b'z' {
-				has_lower = true;
-			}
-			let mut c = b;
-			// Uppercase
-			if b >= b'A' && b <= b'Z' {
-				has_upper = true;
-				// Convert to lowercase
-				c = b + (b'a' - b'A');
-			}
-			data_bytes.push(CHARSET_REV[c as usize] as u8);
+	let mut has_lower: bool = false;
+	let mut has_upper: bool = false;
+	let mut hrp_bytes: Vec<u8> = Vec::new();
+	for b in raw_hrp.bytes() {
+		// Valid subset of ASCII
+		if b < 33 || b > 126 {
+			return Err(CodingError::InvalidChar);
 		}
-
-		// Ensure no mixed case
-		if has_lower && has_upper {
-			return Err(CodingError::MixedCase);
+		let mut c = b;
+		// Lowercase
+		if b >= b'a' && b <= b'z' {
+			has_lower = true;
 		}
+		// Uppercase
+		if b >= b'A' && b <= b'Z' {
+			has_upper = true;
+			// Convert to lowercase
+			c = b + (b'a' - b'A');
+		}
+		hrp_bytes.push(c);
+	}
 
-		// Ensure checksum
-		if !verify_checksum(&hrp_bytes, &data_bytes) {
-			return Err(CodingError::InvalidChecksum);
+	// Check data payload
+	let mut data_bytes: Vec<u8> = Vec::new();
+	for b in raw_data.bytes() {
+		// Aphanumeric only
+		if !((b >= b'0' && b <= b'9') || (b >= b'A' && b <= b'Z') || (b >= b'a' && b <= b'z')) {
+			return Err(CodingError::InvalidChar);
+		}
+		// Excludes these characters: [1,b,i,o]
+		if b == b'1' || b == b'b' || b == b'i' || b == b'o' {
+			return Err(CodingError::InvalidChar);
+		}
+		// Lowercase
+		if b >= b'a' && b <= b'z' {
+			has_lower = true;
+		}
+		let mut c = b;
+		// Uppercase
+		if b >= b'A' && b <= b'Z' {
+			has_upper = true;
+			// Convert to lowercase
+			c = b + (b'a' - b'A');
 		}
+		data_bytes.push(CHARSET_REV[c as usize] as u8);
+	}
 
-		// Remove checksum from data payload
-		let dbl: usize = data_bytes.len();
-		data_bytes.truncate(dbl - 6);
+	// Ensure no mixed case
+	if has_lower && has_upper {
+		return Err(CodingError::MixedCase);
+	}
 
-		Ok(Bech32 {
-			hrp: String::from_utf8(hrp_bytes).unwrap(),
-			// Convert to 8-bit program and assign
-			data: match convert_bits(data_bytes, 5, 8, false) {
-				Ok(p) => p,
-				Err(e) => return Err(CodingError::Address(AddressError::Conversion(e))),
-			},
-		})
+	// Ensure checksum
+	let variant = match verify_checksum(&hrp_bytes, &data_bytes) {
+		Some(variant) => variant,
+		None => return Err(CodingError::InvalidChecksum),
+	};
+
+	// Remove checksum from data payload
+	let dbl: usize = data_bytes.len();
+	data_bytes.truncate(dbl - 6);
+
+	Ok((String::from_utf8(hrp_bytes).unwrap(), data_bytes, variant))
+}
+
+/// Remove the readability separators of a data part starting at `offset`.
+/// They can not be doubled nor end the string.
+fn strip_group_separators(raw_data: &str, offset: usize) -> Result<String, CodingError> {
+	let mut stripped = String::with_capacity(raw_data.len());
+	let mut previous = None;
+	for (pos, c) in raw_data.char_indices() {
+		if c == GROUP_SEP {
+			if previous == Some(GROUP_SEP) || pos + 1 == raw_data.len() {
+				return Err(CodingError::InvalidSeparator(offset + pos));
+			}
+		} else {
+			stripped.push(c);
+		}
+		previous = Some(c);
 	}
+	Ok(stripped)
 }
 
-fn create_checksum(hrp: &Vec<u8>, data: &Vec<u8>) -> Vec<u8> {
+fn create_checksum(hrp: &Vec<u8>, data: &Vec<u8>, variant: Variant) -> Vec<u8> {
 	let mut values: Vec<u8> = hrp_expand(hrp);
 	values.extend_from_slice(data);
 	// Pad with 6 zeros
 	values.extend_from_slice(&[0u8; 6]);
-	let plm: u32 = polymod(values) ^ 1;
+	let plm: u32 = polymod(values) ^ variant.constant();
 	let mut checksum: Vec<u8> = Vec::new();
 	for p in 0..6 {
 		checksum.push(((plm >> 5 * (5 - p)) & 0x1f) as u8);
@@ -281,10 +426,10 @@ fn create_checksum(hrp: &Vec<u8>, data: &Vec<u8>) -> Vec<u8> {
 	checksum
 }
 
-fn verify_checksum(hrp: &Vec<u8>, data: &Vec<u8>) -> bool {
+fn verify_checksum(hrp: &Vec<u8>, data: &Vec<u8>) -> Option<Variant> {
 	let mut exp = hrp_expand(hrp);
 	exp.extend_from_slice(data);
-	polymod(exp) == 1u32
+	Variant::from_constant(polymod(exp))
 }
 
 fn hrp_expand(hrp: &Vec<u8>) -> Vec<u8> {
@@ -374,10 +519,7 @@ mod tests {
 	fn bech32_demo() {
 		// with 5 bytes data
 
-		let b = Bech32 {
-			hrp: "bech32".to_string(),
-			data: vec![0x00, 0x44, 0x32, 0x14, 0xc7],
-		};
+		let b = Bech32::new("bech32", vec![0x00, 0x44, 0x32, 0x14, 0xc7]);
 		let encode = b.to_string(false).unwrap();
 		assert_eq!(encode, "bech321qpzry9x8vrqdnl".to_string());
 
@@ -386,14 +528,14 @@ mod tests {
 
 		// with 33 bytes data (a typical Public Key address)
 
-		let a1 = Bech32 {
-			hrp: "gn".to_string(),
-			data: vec![
+		let a1 = Bech32::new(
+			"gn",
+			vec![
 				0x11, 0x6a, 0x20, 0x76, 0x58, 0x22, 0x1a, 0xa9, 0xa0, 0x4b, 0xe2, 0xfa, 0x6e, 0xbf,
 				0x28, 0x51, 0xdd, 0xf0, 0x36, 0x8d, 0x7a, 0x74, 0x94, 0x34, 0xc9, 0xd1, 0x2c, 0x8f,
 				0xc8, 0x2a, 0xa8, 0x11, 0xf8,
 			],
-		};
+		);
 		let encode = a1.to_string(false).unwrap();
 		assert_eq!(
 			encode,
@@ -426,4 +568,146 @@ mod tests {
 		let decode = Bech32::from_string(encode.as_str()).unwrap();
 		assert_eq!(decode, a1);
 	}
+	fn valid_bip173() -> Vec<String> {
+		vec![
+			"A12UEL5L".to_string(),
+			"a12uel5l".to_string(),
+			"an83characterlonghumanreadablepartthatcontainsthenumber1andtheexcludedcharactersbio1tt5tgs".to_string(),
+			"abcdef1qpzry9x8gf2tvdw0s3jn54khce6mua7lmqqqxw".to_string(),
+			format!("11{}c8247j", "q".repeat(82)),
+			"split1checkupstagehandshakeupstreamerranterredcaperred2y9e3w".to_string(),
+			"?1ezyfcl".to_string(),
+		]
+	}
+
+	fn valid_bip350() -> Vec<String> {
+		vec![
+			"A1LQFN3A".to_string(),
+			"a1lqfn3a".to_string(),
+			"an83characterlonghumanreadablepartthatcontainsthetheexcludedcharactersbioandnumber11sg7hg6".to_string(),
+			"abcdef1l7aum6echk45nj3s0wdvt2fg8x9yrzpqzd3ryx".to_string(),
+			format!("11{}udsr8", "l".repeat(83)),
+			"split1checkupstagehandshakeupstreamerranterredcaperredlc445v".to_string(),
+			"?1v759aa".to_string(),
+		]
+	}
+
+	#[test]
+	fn bip173_valid_vectors() {
+		for s in valid_bip173() {
+			let (_, _, variant) = decode_raw(&s, LengthPolicy::Bip173).unwrap();
+			assert_eq!(variant, Variant::Bech32, "{}", s);
+		}
+	}
+
+	#[test]
+	fn bip350_valid_vectors() {
+		for s in valid_bip350() {
+			let (_, _, variant) = decode_raw(&s, LengthPolicy::Bip173).unwrap();
+			assert_eq!(variant, Variant::Bech32m, "{}", s);
+		}
+	}
+
+	#[test]
+	fn bip173_invalid_vectors() {
+		let too_long = "an84characterslonghumanreadablepartthatcontainsthenumber1andtheexcludedcharactersbio1569pvx";
+		let vectors = vec![
+			("\u{20}1nwldj5", CodingError::InvalidChar),
+			("\u{7f}1axkwrx", CodingError::InvalidChar),
+			("\u{80}1eym55h", CodingError::InvalidChar),
+			(too_long, CodingError::TooLong { length: 91, max: 90 }),
+			("pzry9x0s0muk", CodingError::MissingSeparator),
+			("1pzry9x0s0muk", CodingError::InvalidLength),
+			("x1b4n0q5v", CodingError::InvalidChar),
+			("li1dgmt3", CodingError::InvalidLength),
+			("de1lg7wt\u{ff}", CodingError::InvalidChar),
+			("A1G7SGD8", CodingError::InvalidChecksum),
+			("10a06t8", CodingError::InvalidLength),
+			("1qzzfhee", CodingError::InvalidLength),
+		];
+		for (s, error) in vectors {
+			assert_eq!(decode_raw(s, LengthPolicy::Bip173).err(), Some(error), "{}", s);
+		}
+		assert!(decode_raw(too_long, LengthPolicy::Unbounded).is_ok());
+	}
+
+	#[test]
+	fn bip350_invalid_vectors() {
+		let too_long = "an84characterslonghumanreadablepartthatcontainsthetheexcludedcharactersbioandnumber11d6pts4";
+		let vectors = vec![
+			("\u{20}1xj0phk", CodingError::InvalidChar),
+			("\u{7f}1g6xzxy", CodingError::InvalidChar),
+			("\u{80}1vctc34", CodingError::InvalidChar),
+			(too_long, CodingError::TooLong { length: 91, max: 90 }),
+			("qyrz8wqd2c9m", CodingError::MissingSeparator),
+			("1qyrz8wqd2c9m", CodingError::InvalidLength),
+			("y1b0jsk6g", CodingError::InvalidChar),
+			("lt1igcx5c0", CodingError::InvalidChar),
+			("in1muywd", CodingError::InvalidLength),
+			("mm1crxm3i", CodingError::InvalidChar),
+			("au1s5cgom", CodingError::InvalidChar),
+			("M1VUXWEZ", CodingError::InvalidChecksum),
+			("16plkw9", CodingError::InvalidLength),
+			("1p2gdwpf", CodingError::InvalidLength),
+		];
+		for (s, error) in vectors {
+			assert_eq!(decode_raw(s, LengthPolicy::Bip173).err(), Some(error), "{}", s);
+		}
+	}
+
+	#[test]
+	fn bech32m_roundtrip() {
+		let mut b = Bech32::new("gn", vec![0x00, 0x44, 0x32, 0x14, 0xc7]);
+		b.variant = Variant::Bech32m;
+		let encoded = b.to_string(false).unwrap();
+		assert_eq!(Bech32::from_string(&encoded).unwrap(), b);
+		assert_ne!(encoded, Bech32::new("gn", b.data.clone()).to_string(false).unwrap());
+	}
+
+	#[test]
+	fn precise_errors() {
+		let address = "gn1z94zqajcygd2ngztutaxa0eg28wlqd5d0f6fgdxf6ykgljp24qglsmstrr5";
+
+		assert_eq!(
+			Bech32::from_string(&address.to_uppercase()).unwrap().hrp,
+			"gn"
+		);
+		assert_eq!(
+			Bech32::from_string("gn1z94zqajcygd2ngztutaxa0eg28wlqd5d0f6fgdxf6ykgljp24qglsmsTRR5"),
+			Err(CodingError::MixedCase)
+		);
+		assert_eq!(
+			Bech32::from_string(
+				"gn1-z94zqajc--ygd2ngztutaxa0eg28wlqd5d0f6fgdxf6ykgljp24qglsmstrr5"
+			),
+			Err(CodingError::InvalidSeparator(13))
+		);
+		assert_eq!(
+			Bech32::from_string(&format!("{}-", address)),
+			Err(CodingError::InvalidSeparator(62))
+		);
+		assert_eq!(
+			Bech32::from_string_with_hrp(address, "tn", LengthPolicy::Bip173),
+			Err(CodingError::WrongHrp {
+				expected: "tn".to_string(),
+				found: "gn".to_string(),
+			})
+		);
+		assert_eq!(
+			Bech32::from_string_with(address, LengthPolicy::Max(40)),
+			Err(CodingError::TooLong {
+				length: 62,
+				max: 40
+			})
+		);
+		assert_eq!(
+			Bech32::from_string(address)
+				.unwrap()
+				.to_string_with(true, LengthPolicy::Max(40)),
+			Err(CodingError::TooLong {
+				length: 62,
+				max: 40
+			})
+		);
+	}
 }
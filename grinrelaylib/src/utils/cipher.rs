@@ -0,0 +1,53 @@
+// Copyright 2019 The Gotts Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Symmetric primitives used by grinrelay messages.
+
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use sha2::{Sha256, Sha512};
+
+use crate::error::{ErrorKind, Result};
+
+pub const NONCE_LEN: usize = 12;
+
+pub fn pbkdf2_sha512(password: &[u8], salt: &[u8], rounds: u32, out: &mut [u8]) {
+	pbkdf2::pbkdf2_hmac::<Sha512>(password, salt, rounds, out);
+}
+
+pub fn hkdf_sha256(salt: &[u8], ikm: &[u8], info: &[u8], out: &mut [u8]) -> Result<()> {
+	Hkdf::<Sha256>::new(Some(salt), ikm)
+		.expand(info, out)
+		.map_err(|_| ErrorKind::Encryption.into())
+}
+
+/// ChaCha20-Poly1305 encryption, returning the ciphertext followed by the tag.
+pub fn seal(key: &[u8; 32], nonce: &[u8], plaintext: &[u8]) -> Result<Vec<u8>> {
+	if nonce.len() != NONCE_LEN {
+		return Err(ErrorKind::Encryption.into());
+	}
+	ChaCha20Poly1305::new(Key::from_slice(key))
+		.encrypt(Nonce::from_slice(nonce), plaintext)
+		.map_err(|_| ErrorKind::Encryption.into())
+}
+
+pub fn open(key: &[u8; 32], nonce: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>> {
+	if nonce.len() != NONCE_LEN {
+		return Err(ErrorKind::Decryption.into());
+	}
+	ChaCha20Poly1305::new(Key::from_slice(key))
+		.decrypt(Nonce::from_slice(nonce), ciphertext)
+		.map_err(|_| ErrorKind::Decryption.into())
+}
@@ -13,10 +13,13 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use serde::de::{self, Deserialize, Deserializer};
+use serde::{Serialize, Serializer};
 use sha2::{Digest, Sha256};
+use std::fmt;
 
 use super::bech32::Bech32;
-use super::secp::{Commitment, Message, PublicKey, Secp256k1, SecretKey, Signature};
+use super::secp::{Commitment, ContextFlag, Message, PublicKey, Secp256k1, SecretKey, Signature};
 use super::{from_hex, to_hex};
 use crate::error::{ErrorKind, Result};
 
@@ -83,10 +86,10 @@ impl AddrBech32<PublicKey> for PublicKey {
 	}
 
 	fn to_bech32(&self, hrp_bytes: Vec<u8>) -> String {
-		let b = Bech32 {
-			hrp: String::from_utf8_lossy(&hrp_bytes).into_owned(),
-			data: serialize_public_key(self),
-		};
+		let b = Bech32::new(
+			&String::from_utf8_lossy(&hrp_bytes),
+			serialize_public_key(self),
+		);
 		b.to_string(true).unwrap()
 	}
 }
@@ -133,10 +136,14 @@ pub fn public_key_from_secret_key(secret_key: &SecretKey) -> Result<PublicKey> {
 	PublicKey::from_secret_key(&secp, secret_key).map_err(|_| ErrorKind::SecpError.into())
 }
 
-pub fn sign_challenge(challenge: &str, secret_key: &SecretKey) -> Result<Signature> {
+fn ecdsa_message(challenge: &str) -> Result<Message> {
 	let mut hasher = Sha256::new();
-	hasher.input(challenge.as_bytes());
-	let message = Message::from_slice(hasher.result().as_slice())?;
+	hasher.update(challenge.as_bytes());
+	Ok(Message::from_slice(hasher.finalize().as_slice())?)
+}
+
+pub fn sign_challenge(challenge: &str, secret_key: &SecretKey) -> Result<Signature> {
+	let message = ecdsa_message(challenge)?;
 	let secp = Secp256k1::new();
 	secp.sign(&message, secret_key)
 		.map_err(|_| ErrorKind::SecpError.into())
@@ -147,10 +154,278 @@ pub fn verify_signature(
 	signature: &Signature,
 	public_key: &PublicKey,
 ) -> Result<()> {
+	SignatureVerifier::shared().verify_ecdsa(challenge, signature, public_key)
+}
+
+/// Signature schemes a client may use to sign its challenges.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub enum SignatureScheme {
+	/// ECDSA, DER encoded
+	#[default]
+	Ecdsa,
+	/// BIP-340 Schnorr, 64 bytes
+	Schnorr,
+}
+
+/// A BIP-340 Schnorr signature, serialized as hex.
+#[derive(Clone, Copy)]
+pub struct SchnorrSignature(pub [u8; 64]);
+
+impl PartialEq for SchnorrSignature {
+	fn eq(&self, other: &SchnorrSignature) -> bool {
+		self.0[..] == other.0[..]
+	}
+}
+
+impl fmt::Debug for SchnorrSignature {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "SchnorrSignature({})", self.to_hex())
+	}
+}
+
+impl Hex<SchnorrSignature> for SchnorrSignature {
+	fn from_hex(str: &str) -> Result<SchnorrSignature> {
+		let data = from_hex(str.to_string())?;
+		if data.len() != 64 {
+			return Err(ErrorKind::SecpError.into());
+		}
+		let mut signature = [0; 64];
+		signature.copy_from_slice(&data);
+		Ok(SchnorrSignature(signature))
+	}
+
+	fn to_hex(&self) -> String {
+		to_hex(self.0.to_vec())
+	}
+}
+
+impl Serialize for SchnorrSignature {
+	fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+		serializer.serialize_str(&self.to_hex())
+	}
+}
+
+impl<'de> Deserialize<'de> for SchnorrSignature {
+	fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+		let hex = String::deserialize(deserializer)?;
+		SchnorrSignature::from_hex(&hex).map_err(de::Error::custom)
+	}
+}
+
+fn challenge_message(challenge: &str) -> Result<secp256k1::Message> {
 	let mut hasher = Sha256::new();
-	hasher.input(challenge.as_bytes());
-	let message = Message::from_slice(hasher.result().as_slice())?;
-	let secp = Secp256k1::new();
-	secp.verify(&message, signature, public_key)
+	hasher.update(challenge.as_bytes());
+	secp256k1::Message::from_slice(hasher.finalize().as_slice())
 		.map_err(|_| ErrorKind::SecpError.into())
 }
+
+pub fn sign_challenge_schnorr(challenge: &str, secret_key: &SecretKey) -> Result<SchnorrSignature> {
+	let message = challenge_message(challenge)?;
+	let secp = secp256k1::Secp256k1::signing_only();
+	let keypair = secp256k1::KeyPair::from_seckey_slice(&secp, &secret_key.0)
+		.map_err(|_| ErrorKind::SecpError)?;
+	let signature = secp.sign_schnorr_no_aux_rand(&message, &keypair);
+	let mut bytes = [0; 64];
+	bytes.copy_from_slice(signature.as_ref());
+	Ok(SchnorrSignature(bytes))
+}
+
+pub fn verify_schnorr_signature(
+	challenge: &str,
+	signature: &SchnorrSignature,
+	public_key: &PublicKey,
+) -> Result<()> {
+	SignatureVerifier::shared().verify_schnorr(challenge, signature, public_key)
+}
+
+/// Verify a hex encoded signature made with the given scheme.
+pub fn verify_signature_hex(
+	scheme: SignatureScheme,
+	challenge: &str,
+	signature: &str,
+	public_key: &PublicKey,
+) -> Result<()> {
+	SignatureVerifier::shared()
+		.match_challenge(scheme, &[challenge], signature, public_key)
+		.map(|_| ())
+}
+
+lazy_static! {
+	static ref SHARED_VERIFIER: SignatureVerifier = SignatureVerifier::new();
+}
+
+/// Challenge signature verification with pre-allocated secp256k1 contexts.
+///
+/// Allocating a context is far more expensive than a verification, so the
+/// relay keeps one verifier for all its connections instead of building a
+/// context per call. Signatures are verified one by one: secp256k1 exposes
+/// no batch verification, nor the multi-scalar multiplication it rests on.
+pub struct SignatureVerifier {
+	secp: Secp256k1,
+	schnorr: secp256k1::Secp256k1<secp256k1::VerifyOnly>,
+}
+
+impl SignatureVerifier {
+	pub fn new() -> SignatureVerifier {
+		SignatureVerifier {
+			secp: Secp256k1::with_caps(ContextFlag::VerifyOnly),
+			schnorr: secp256k1::Secp256k1::verification_only(),
+		}
+	}
+
+	/// The verifier shared by the whole process.
+	pub fn shared() -> &'static SignatureVerifier {
+		&SHARED_VERIFIER
+	}
+
+	/// Decode a bech32 address key without allocating a new context.
+	pub fn public_key_from_bech32(&self, bech32_str: &str) -> Result<(PublicKey, Vec<u8>)> {
+		let addr = Bech32::from_string(bech32_str)?;
+		let public_key = PublicKey::from_slice(&self.secp, &addr.data)
+			.map_err(|_| ErrorKind::InvalidBech32Key)?;
+		Ok((public_key, addr.hrp.into_bytes()))
+	}
+
+	pub fn verify_ecdsa(
+		&self,
+		challenge: &str,
+		signature: &Signature,
+		public_key: &PublicKey,
+	) -> Result<()> {
+		let message = ecdsa_message(challenge)?;
+		self.secp
+			.verify(&message, signature, public_key)
+			.map_err(|_| ErrorKind::SecpError.into())
+	}
+
+	pub fn verify_schnorr(
+		&self,
+		challenge: &str,
+		signature: &SchnorrSignature,
+		public_key: &PublicKey,
+	) -> Result<()> {
+		let public_key = self.x_only_public_key(public_key)?;
+		let signature = secp256k1::schnorr::Signature::from_slice(&signature.0)
+			.map_err(|_| ErrorKind::SecpError)?;
+		self.verify_schnorr_parsed(challenge, &signature, &public_key)
+	}
+
+	/// Find which of the candidate challenges a hex encoded signature signs
+	/// and return its index. Candidates are verified one after the other, the
+	/// signature and the key are only decoded once whatever their number.
+	pub fn match_challenge(
+		&self,
+		scheme: SignatureScheme,
+		challenges: &[&str],
+		signature: &str,
+		public_key: &PublicKey,
+	) -> Result<usize> {
+		match scheme {
+			SignatureScheme::Ecdsa => {
+				let signature = Signature::from_der(&self.secp, &from_hex(signature.to_string())?)
+					.map_err(|_| ErrorKind::SecpError)?;
+				challenges
+					.iter()
+					.position(|challenge| {
+						self.verify_ecdsa(challenge, &signature, public_key).is_ok()
+					})
+					.ok_or_else(|| ErrorKind::SecpError.into())
+			}
+			SignatureScheme::Schnorr => {
+				let signature = SchnorrSignature::from_hex(signature)?;
+				let signature = secp256k1::schnorr::Signature::from_slice(&signature.0)
+					.map_err(|_| ErrorKind::SecpError)?;
+				let public_key = self.x_only_public_key(public_key)?;
+				challenges
+					.iter()
+					.position(|challenge| {
+						self.verify_schnorr_parsed(challenge, &signature, &public_key)
+							.is_ok()
+					})
+					.ok_or_else(|| ErrorKind::SecpError.into())
+			}
+		}
+	}
+
+	fn x_only_public_key(&self, public_key: &PublicKey) -> Result<secp256k1::XOnlyPublicKey> {
+		let public_key =
+			secp256k1::PublicKey::from_slice(&public_key.serialize_vec(&self.secp, true))
+				.map_err(|_| ErrorKind::SecpError)?;
+		Ok(public_key.x_only_public_key().0)
+	}
+
+	fn verify_schnorr_parsed(
+		&self,
+		challenge: &str,
+		signature: &secp256k1::schnorr::Signature,
+		public_key: &secp256k1::XOnlyPublicKey,
+	) -> Result<()> {
+		let message = challenge_message(challenge)?;
+		self.schnorr
+			.verify_schnorr(signature, &message, public_key)
+			.map_err(|_| ErrorKind::SecpError.into())
+	}
+}
+
+impl Default for SignatureVerifier {
+	fn default() -> SignatureVerifier {
+		SignatureVerifier::new()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn secret_key(byte: &str) -> SecretKey {
+		SecretKey::from_hex(&byte.repeat(32)).unwrap()
+	}
+
+	#[test]
+	fn schnorr_roundtrip() {
+		let secret_key = secret_key("11");
+		let public_key = public_key_from_secret_key(&secret_key).unwrap();
+		let signature = sign_challenge_schnorr("challenge", &secret_key).unwrap();
+
+		assert!(verify_schnorr_signature("challenge", &signature, &public_key).is_ok());
+		assert!(verify_schnorr_signature("another challenge", &signature, &public_key).is_err());
+
+		let other = public_key_from_secret_key(&secret_key("22")).unwrap();
+		assert!(verify_schnorr_signature("challenge", &signature, &other).is_err());
+	}
+
+	#[test]
+	fn verify_signature_hex_dispatches_on_scheme() {
+		let secret_key = secret_key("11");
+		let public_key = public_key_from_secret_key(&secret_key).unwrap();
+		let ecdsa = sign_challenge("challenge", &secret_key).unwrap().to_hex();
+		let schnorr = sign_challenge_schnorr("challenge", &secret_key)
+			.unwrap()
+			.to_hex();
+
+		assert!(
+			verify_signature_hex(SignatureScheme::Ecdsa, "challenge", &ecdsa, &public_key).is_ok()
+		);
+		assert!(
+			verify_signature_hex(SignatureScheme::Schnorr, "challenge", &schnorr, &public_key)
+				.is_ok()
+		);
+		assert!(
+			verify_signature_hex(SignatureScheme::Schnorr, "challenge", &ecdsa, &public_key)
+				.is_err()
+		);
+		assert!(
+			verify_signature_hex(SignatureScheme::Ecdsa, "challenge", &schnorr, &public_key)
+				.is_err()
+		);
+	}
+
+	#[test]
+	fn signature_scheme_defaults_to_ecdsa() {
+		assert_eq!(SignatureScheme::default(), SignatureScheme::Ecdsa);
+		assert_eq!(
+			serde_json::to_string(&SignatureScheme::Schnorr).unwrap(),
+			"\"Schnorr\""
+		);
+	}
+}
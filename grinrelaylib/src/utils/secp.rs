@@ -15,4 +15,4 @@
 
 pub use secp256k1zkp::key::{PublicKey, SecretKey};
 pub use secp256k1zkp::pedersen::Commitment;
-pub use secp256k1zkp::{Message, Secp256k1, Signature};
+pub use secp256k1zkp::{ContextFlag, Message, Secp256k1, Signature};
@@ -13,14 +13,25 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use futures::sync::mpsc::UnboundedSender;
+use grinrelaylib::types::GrinboxError;
+use tokio::sync::mpsc::Sender;
+use tokio::sync::oneshot;
+
+/// Resolved once the broker confirmed a message, or went away before that
+pub type Delivery = oneshot::Sender<Result<(), GrinboxError>>;
+
+#[derive(Debug)]
+pub struct BatchMessage {
+	pub subject: String,
+	pub payload: String,
+}
 
 #[derive(Debug)]
 pub enum BrokerRequest {
 	Subscribe {
 		id: String,
 		subject: String,
-		response_sender: UnboundedSender<BrokerResponse>,
+		response_sender: Sender<BrokerResponse>,
 	},
 	Unsubscribe {
 		id: String,
@@ -30,6 +41,27 @@ pub enum BrokerRequest {
 		payload: String,
 		reply_to: String,
 		message_expiration_in_seconds: Option<u32>,
+		delivery: Delivery,
+	},
+	/// Publish every message or none, in a single transaction
+	PostBatch {
+		messages: Vec<BatchMessage>,
+		reply_to: String,
+		message_expiration_in_seconds: Option<u32>,
+		delivery: Delivery,
+	},
+	/// Fan a presence event out to every relay of the cluster
+	PublishPresence {
+		payload: String,
+	},
+	/// Take the subject away from its current consumer, telling it why
+	Revoke {
+		subject: String,
+	},
+	/// The consumer passed a message on, the broker may send the next one
+	Ack {
+		id: String,
+		ack_id: String,
 	},
 }
 
@@ -39,5 +71,9 @@ pub enum BrokerResponse {
 		subject: String,
 		payload: String,
 		reply_to: String,
+		/// To be sent back in `BrokerRequest::Ack` once delivered
+		ack_id: String,
 	},
+	/// The subscription was handed over to another connection
+	Revoked { subject: String },
 }
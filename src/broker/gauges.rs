@@ -0,0 +1,149 @@
+// Copyright 2019 The Gotts Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Depth of the queues between the websocket connections and the broker.
+//!
+//! The broker session refreshes the depths and logs them periodically. The
+//! connections count the posts they turned away because the broker was behind,
+//! the broker session the presence events it dropped because the presence
+//! listener was.
+
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::sync::mpsc::Sender;
+
+#[derive(Default)]
+pub struct QueueGauges {
+	/// Requests waiting for the broker session
+	requests: AtomicUsize,
+	/// Broker messages waiting for websocket clients, over all connections
+	responses: AtomicUsize,
+	/// Broker messages waiting for the slowest websocket client
+	responses_peak: AtomicUsize,
+	/// Posts refused with `Busy` since the last report
+	rejected: AtomicUsize,
+	/// Broker messages put back to their queue since the last report
+	requeued: AtomicUsize,
+	/// Presence events of other relays dropped since the last report
+	presence_dropped: AtomicUsize,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct QueueDepths {
+	pub requests: usize,
+	pub responses: usize,
+	pub responses_peak: usize,
+	pub rejected: usize,
+	pub requeued: usize,
+	pub presence_dropped: usize,
+}
+
+impl QueueDepths {
+	pub fn is_idle(&self) -> bool {
+		*self == QueueDepths::default()
+	}
+}
+
+impl fmt::Display for QueueDepths {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(
+			f,
+			"requests: {}, responses: {} (peak {}), rejected: {}, requeued: {}, \
+			 presence dropped: {}",
+			self.requests,
+			self.responses,
+			self.responses_peak,
+			self.rejected,
+			self.requeued,
+			self.presence_dropped
+		)
+	}
+}
+
+/// Messages queued on the channel behind `sender`.
+pub fn depth<T>(sender: &Sender<T>) -> usize {
+	sender.max_capacity() - sender.capacity()
+}
+
+impl QueueGauges {
+	pub fn new() -> QueueGauges {
+		QueueGauges::default()
+	}
+
+	pub fn set_requests(&self, depth: usize) {
+		self.requests.store(depth, Ordering::Relaxed);
+	}
+
+	pub fn set_responses<'a, T: 'a, I>(&self, senders: I)
+	where
+		I: Iterator<Item = &'a Sender<T>>,
+	{
+		let (total, peak) = senders.map(depth).fold((0, 0), |(total, peak), depth| {
+			(total + depth, peak.max(depth))
+		});
+		self.responses.store(total, Ordering::Relaxed);
+		self.responses_peak.store(peak, Ordering::Relaxed);
+	}
+
+	pub fn rejected(&self) {
+		self.rejected.fetch_add(1, Ordering::Relaxed);
+	}
+
+	pub fn requeued(&self) {
+		self.requeued.fetch_add(1, Ordering::Relaxed);
+	}
+
+	pub fn presence_dropped(&self) {
+		self.presence_dropped.fetch_add(1, Ordering::Relaxed);
+	}
+
+	/// Current depths, restarting the counts of rejected, requeued and dropped
+	/// messages.
+	pub fn report(&self) -> QueueDepths {
+		QueueDepths {
+			requests: self.requests.load(Ordering::Relaxed),
+			responses: self.responses.load(Ordering::Relaxed),
+			responses_peak: self.responses_peak.load(Ordering::Relaxed),
+			rejected: self.rejected.swap(0, Ordering::Relaxed),
+			requeued: self.requeued.swap(0, Ordering::Relaxed),
+			presence_dropped: self.presence_dropped.swap(0, Ordering::Relaxed),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use tokio::sync::mpsc::channel;
+
+	#[test]
+	fn depths_follow_the_channels() {
+		let (a, _a) = channel::<u32>(4);
+		let (b, _b) = channel::<u32>(4);
+		a.try_send(1).unwrap();
+		b.try_send(1).unwrap();
+		b.try_send(2).unwrap();
+
+		let gauges = QueueGauges::new();
+		gauges.set_responses(vec![&a, &b].into_iter());
+		gauges.rejected();
+		let depths = gauges.report();
+		assert_eq!(depths.responses, 3);
+		assert_eq!(depths.responses_peak, 2);
+		assert_eq!(depths.rejected, 1);
+
+		gauges.set_responses(std::iter::empty::<&Sender<u32>>());
+		assert!(gauges.report().is_idle());
+	}
+}
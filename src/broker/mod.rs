@@ -16,9 +16,10 @@
 #![allow(dead_code)]
 
 mod broker_protocol;
+mod gauges;
 mod rabbit_broker;
 mod stomp;
 
-pub use self::broker_protocol::{BrokerRequest, BrokerResponse};
+pub use self::broker_protocol::{BatchMessage, BrokerRequest, BrokerResponse, Delivery};
+pub use self::gauges::QueueGauges;
 pub use self::rabbit_broker::Broker;
-pub use parking_lot::Mutex;
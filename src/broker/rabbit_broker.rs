@@ -13,39 +13,44 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::Mutex;
-use std::collections::HashMap;
+use futures::StreamExt;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpStream;
-use tokio::prelude::*;
-
-use futures::{
-	sync::mpsc::{unbounded, UnboundedSender},
-	Future, Stream,
-};
+use tokio::sync::mpsc::{channel, error::TrySendError, Receiver, Sender};
 
 use grinrelaylib::error::Result;
+use grinrelaylib::types::GrinboxError;
 
 use crate::broker::stomp::connection::{Credentials, HeartBeat};
 use crate::broker::stomp::frame::Frame;
-use crate::broker::stomp::header::{Header, HeaderName, SUBSCRIPTION};
-use crate::broker::stomp::session::SessionEvent;
+use crate::broker::stomp::header::{Header, HeaderName, ACK, MESSAGE, RECEIPT_ID, SUBSCRIPTION};
+use crate::broker::stomp::message_builder::MessageBuilder;
+use crate::broker::stomp::session::{GenerateReceipt, Session, SessionEvent};
 use crate::broker::stomp::session_builder::SessionBuilder;
-use crate::broker::stomp::subscription::AckMode;
-use crate::broker::{BrokerRequest, BrokerResponse};
-
-type Session = crate::broker::stomp::session::Session<TcpStream>;
+use crate::broker::stomp::subscription::{AckMode, PrefetchCount};
+use crate::broker::{BatchMessage, BrokerRequest, BrokerResponse, Delivery, QueueGauges};
+use crate::Mutex;
 
 const DEFAULT_QUEUE_EXPIRATION: &str = "86400000";
 const DEFAULT_MESSAGE_EXPIRATION: u32 = 86400;
 const REPLY_TO_HEADER_NAME: &str = "grinrelay-reply-to";
+const PRESENCE_DESTINATION: &str = "/exchange/grinrelay.presence";
+/// Requests buffered for the broker session before posts are refused as busy
+const REQUEST_QUEUE_SIZE: usize = 1024;
+const GAUGES_INTERVAL: Duration = Duration::from_secs(60);
 
 pub struct Broker {
 	address: SocketAddr,
 	username: String,
 	password: String,
 	consumers: Arc<Mutex<HashMap<String, Vec<String>>>>,
+	presence_sender: Sender<String>,
+	gauges: Arc<QueueGauges>,
+	prefetch_count: u32,
 }
 
 impl Broker {
@@ -54,76 +59,49 @@ impl Broker {
 		username: String,
 		password: String,
 		consumers: Arc<Mutex<HashMap<String, Vec<String>>>>,
+		presence_sender: Sender<String>,
+		gauges: Arc<QueueGauges>,
+		prefetch_count: u32,
 	) -> Broker {
 		Broker {
 			address,
 			username,
 			password,
 			consumers,
+			presence_sender,
+			gauges,
+			prefetch_count,
 		}
 	}
 
-	pub fn start(&mut self) -> Result<UnboundedSender<BrokerRequest>> {
-		let (tx, rx) = unbounded();
-		let address = self.address.clone();
-		let username = self.username.clone();
-		let password = self.password.clone();
-		let consumers = self.consumers.clone();
-		std::thread::spawn(move || {
-			let tcp_stream = Box::new(TcpStream::connect(&address));
-
-			let session = SessionBuilder::new()
-				.with(Credentials(&username, &password))
-				.with(HeartBeat(10000, 10000))
-				.build(tcp_stream);
-
-			let session = BrokerSession {
-				session: Arc::new(Mutex::new(session)),
-				session_number: 0,
-				consumers: Arc::new(Mutex::new(HashMap::new())),
-				subject_to_consumer_id_lookup: Arc::new(Mutex::new(HashMap::new())),
-				subscription_id_to_consumer_id_lookup: Arc::new(Mutex::new(HashMap::new())),
-				consumer_shortname_to_subject_loopup: consumers,
-			};
-
-			let mut session_clone = session.clone();
-
-			let request_loop = rx
-				.for_each(move |request| {
-					match request {
-						BrokerRequest::Subscribe {
-							id,
-							subject,
-							response_sender,
-						} => {
-							session_clone.subscribe(id, subject.clone(), response_sender.clone());
-						}
-						BrokerRequest::Unsubscribe { id } => {
-							session_clone.unsubscribe(&id);
-						}
-						BrokerRequest::PostMessage {
-							subject,
-							payload,
-							reply_to,
-							message_expiration_in_seconds,
-						} => {
-							session_clone.publish(
-								&subject,
-								&payload,
-								&reply_to,
-								message_expiration_in_seconds,
-							);
-						}
-					}
-					Ok(())
-				})
-				.map_err(|()| std::io::Error::new(std::io::ErrorKind::Other, ""));
-
-			let f = session.select(request_loop).map_err(|_| {}).map(|_| {});
+	/// Spawn the broker session on the current runtime.
+	pub fn start(&mut self) -> Result<Sender<BrokerRequest>> {
+		let (tx, rx) = channel(REQUEST_QUEUE_SIZE);
+		let address = self.address;
+
+		let session = SessionBuilder::new()
+			.with(Credentials(&self.username, &self.password))
+			.with(HeartBeat(10000, 10000))
+			.build(Box::pin(TcpStream::connect(address)));
+
+		let session = BrokerSession {
+			session,
+			session_number: 0,
+			consumers: HashMap::new(),
+			subject_to_consumer_id_lookup: HashMap::new(),
+			subscription_id_to_consumer_id_lookup: HashMap::new(),
+			consumer_shortname_to_subject_loopup: self.consumers.clone(),
+			presence_sender: self.presence_sender.clone(),
+			presence_subscription_id: None,
+			deliveries: HashMap::new(),
+			gauges: self.gauges.clone(),
+			prefetch_count: self.prefetch_count,
+		};
 
-			tokio::run(f);
+		tokio::spawn(async move {
+			session.run(rx).await;
 
-			error!("broker thread ending!");
+			error!("broker session ending!");
 
 			// TODO: attempt reconnection and re-establishment of subscriptions?
 			std::process::exit(1);
@@ -136,46 +114,240 @@ impl Broker {
 struct Consumer {
 	subject: String,
 	subscription_id: String,
-	sender: UnboundedSender<BrokerResponse>,
+	sender: Sender<BrokerResponse>,
+	/// Messages handed to the consumer and not acknowledged yet
+	unacked: HashSet<String>,
+	/// Messages, by ack id, the consumer had no room for yet. The broker sends
+	/// no more than the prefetch count before some are acknowledged, so these
+	/// are handed over as the consumer acknowledges the ones before them.
+	backlog: VecDeque<(String, BrokerResponse)>,
 }
 
 impl Consumer {
 	pub fn new(
 		subject: String,
 		subscription_id: String,
-		sender: UnboundedSender<BrokerResponse>,
+		sender: Sender<BrokerResponse>,
 	) -> Consumer {
 		Consumer {
 			subject,
 			subscription_id,
 			sender,
+			unacked: HashSet::new(),
+			backlog: VecDeque::new(),
 		}
 	}
+
+	/// Hand held back messages over while the consumer has room. Returns the
+	/// ack ids of those it can no longer take, its connection being gone.
+	fn catch_up(&mut self) -> Vec<String> {
+		while let Some((ack_id, response)) = self.backlog.pop_front() {
+			match self.sender.try_send(response) {
+				Ok(()) => {
+					self.unacked.insert(ack_id);
+				}
+				Err(TrySendError::Full(response)) => {
+					self.backlog.push_front((ack_id, response));
+					break;
+				}
+				Err(TrySendError::Closed(_)) => {
+					let mut gone = vec![ack_id];
+					gone.extend(self.backlog.drain(..).map(|(ack_id, _)| ack_id));
+					return gone;
+				}
+			}
+		}
+		vec![]
+	}
 }
 
-#[derive(Clone)]
-struct BrokerSession {
-	session: Arc<Mutex<Session>>,
+/// The STOMP session and its consumers, owned by a single task.
+struct BrokerSession<T: 'static> {
+	session: Session<T>,
 	session_number: u32,
-	consumers: Arc<Mutex<HashMap<String, Consumer>>>,
-	subject_to_consumer_id_lookup: Arc<Mutex<HashMap<String, String>>>,
-	subscription_id_to_consumer_id_lookup: Arc<Mutex<HashMap<String, String>>>,
+	consumers: HashMap<String, Consumer>,
+	subject_to_consumer_id_lookup: HashMap<String, String>,
+	subscription_id_to_consumer_id_lookup: HashMap<String, String>,
 	consumer_shortname_to_subject_loopup: Arc<Mutex<HashMap<String, Vec<String>>>>,
+	presence_sender: Sender<String>,
+	presence_subscription_id: Option<String>,
+	/// Posts waiting for their receipt, by receipt id
+	deliveries: HashMap<String, Delivery>,
+	gauges: Arc<QueueGauges>,
+	/// Messages of a subscription in flight to its consumer
+	prefetch_count: u32,
 }
 
-impl BrokerSession {
+impl<T> BrokerSession<T>
+where
+	T: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+	/// Serve requests and session events until either side goes away.
+	async fn run(mut self, mut requests: Receiver<BrokerRequest>) {
+		let mut gauges = tokio::time::interval(GAUGES_INTERVAL);
+		loop {
+			tokio::select! {
+				event = self.session.next() => match event {
+					Some(event) => {
+						if !self.on_event(event) {
+							break;
+						}
+					}
+					None => break,
+				},
+				request = requests.recv() => match request {
+					Some(request) => self.on_request(request),
+					None => break,
+				},
+				_ = gauges.tick() => {
+					self.report_gauges(&requests);
+					// forget posts which gave up waiting for a receipt
+					self.deliveries.retain(|_, delivery| !delivery.is_closed());
+				}
+			}
+		}
+	}
+
+	fn report_gauges(&self, requests: &Receiver<BrokerRequest>) {
+		self.gauges.set_requests(requests.len());
+		self.gauges
+			.set_responses(self.consumers.values().map(|consumer| &consumer.sender));
+		let depths = self.gauges.report();
+		if depths.is_idle() {
+			debug!("queues: {}", depths);
+		} else {
+			info!("queues: {}", depths);
+		}
+	}
+
+	fn on_request(&mut self, request: BrokerRequest) {
+		match request {
+			BrokerRequest::Subscribe {
+				id,
+				subject,
+				response_sender,
+			} => {
+				self.subscribe(id, subject, response_sender);
+			}
+			BrokerRequest::Unsubscribe { id } => {
+				self.unsubscribe(&id);
+			}
+			BrokerRequest::PostMessage {
+				subject,
+				payload,
+				reply_to,
+				message_expiration_in_seconds,
+				delivery,
+			} => {
+				let receipt_id =
+					self.publish(&subject, &payload, &reply_to, message_expiration_in_seconds);
+				self.deliveries.insert(receipt_id, delivery);
+			}
+			BrokerRequest::PostBatch {
+				messages,
+				reply_to,
+				message_expiration_in_seconds,
+				delivery,
+			} => {
+				let receipt_id =
+					self.publish_batch(&messages, &reply_to, message_expiration_in_seconds);
+				self.deliveries.insert(receipt_id, delivery);
+			}
+			BrokerRequest::PublishPresence { payload } => {
+				self.publish_presence(&payload);
+			}
+			BrokerRequest::Revoke { subject } => {
+				self.unsubscribe_by_subject(&subject);
+			}
+			BrokerRequest::Ack { id, ack_id } => {
+				self.acknowledge(&id, &ack_id);
+			}
+		}
+	}
+
+	/// Handle a session event, `false` once the session is over.
+	fn on_event(&mut self, event: SessionEvent) -> bool {
+		trace!("msg: {:?}", event);
+		match event {
+			SessionEvent::Connected => {
+				self.on_connected();
+			}
+
+			SessionEvent::Message {
+				destination: _destination,
+				ack_mode: _ack_mode,
+				frame,
+			} => self.on_message(frame),
+
+			SessionEvent::Receipt { id, .. } => self.on_receipt(id, Ok(())),
+
+			// the broker closes the connection after an ERROR frame, the
+			// other waiting posts fail once it is gone
+			SessionEvent::Error(frame) => {
+				error!("session error event: {}", frame);
+				if let Some(receipt_id) = frame.headers.get(RECEIPT_ID) {
+					let error = broker_error(frame.headers.get(MESSAGE).unwrap_or(""));
+					self.on_receipt(receipt_id.to_string(), Err(error));
+				}
+			}
+
+			SessionEvent::Disconnected(reason) => {
+				warn!(
+					"session [{}] disconnected due to [{:?}]",
+					self.session_number, reason
+				);
+				self.fail_deliveries();
+				return false;
+			}
+
+			m => {
+				warn!("unexepcted msg: {:?}", m);
+			}
+		}
+		true
+	}
+
 	fn on_connected(&mut self) {
 		info!("established broker session");
+
+		let subscription_id = self
+			.session
+			.subscription(PRESENCE_DESTINATION)
+			.with(AckMode::Auto)
+			.start();
+		self.presence_subscription_id = Some(subscription_id);
 	}
 
-	fn subscribe(&mut self, id: String, subject: String, sender: UnboundedSender<BrokerResponse>) {
+	/// Tell the poster how its message fared.
+	fn on_receipt(&mut self, receipt_id: String, result: std::result::Result<(), GrinboxError>) {
+		match self.deliveries.remove(&receipt_id) {
+			// the poster may have given up waiting
+			Some(delivery) => {
+				let _ = delivery.send(result);
+			}
+			None => debug!("receipt [{}] without a waiting post", receipt_id),
+		}
+	}
+
+	/// Tell every waiting poster the broker is gone.
+	fn fail_deliveries(&mut self) {
+		for (_, delivery) in self.deliveries.drain() {
+			let _ = delivery.send(Err(GrinboxError::BrokerUnavailable));
+		}
+	}
+
+	fn publish_presence(&mut self, payload: &str) {
+		self.session.message(PRESENCE_DESTINATION, payload).send();
+	}
+
+	fn subscribe(&mut self, id: String, subject: String, sender: Sender<BrokerResponse>) {
 		self.unsubscribe_by_subject(&subject);
 
 		let subscription_id = self
 			.session
-			.lock()
 			.subscription(&subject)
-			.with(AckMode::Auto)
+			.with(AckMode::ClientIndividual)
+			.with(PrefetchCount(self.prefetch_count))
 			.with(Header::new(
 				HeaderName::from_str("x-expires"),
 				DEFAULT_QUEUE_EXPIRATION,
@@ -184,21 +356,25 @@ impl BrokerSession {
 
 		let consumer = Consumer::new(subject.clone(), subscription_id.clone(), sender);
 		self.subject_to_consumer_id_lookup
-			.lock()
 			.insert(subject, id.clone());
 		self.subscription_id_to_consumer_id_lookup
-			.lock()
 			.insert(subscription_id, id.clone());
-		self.consumers.lock().insert(id, consumer);
+		self.consumers.insert(id, consumer);
 	}
 
+	/// Take `subject` away from its consumer, which is told so.
 	fn unsubscribe_by_subject(&mut self, subject: &str) {
-		if let Some(consumer_id) = self.subject_to_consumer_id_lookup.lock().remove(subject) {
-			if let Some(consumer) = self.consumers.lock().remove(&consumer_id) {
+		if let Some(consumer_id) = self.subject_to_consumer_id_lookup.remove(subject) {
+			if let Some(consumer) = self.consumers.remove(&consumer_id) {
+				let revoked = BrokerResponse::Revoked {
+					subject: subject.to_string(),
+				};
+				if consumer.sender.try_send(revoked).is_err() {
+					warn!("could not notify consumer [{}] of revocation", consumer_id);
+				}
 				self.subscription_id_to_consumer_id_lookup
-					.lock()
 					.remove(&consumer.subscription_id);
-				self.session.lock().unsubscribe(&consumer.subscription_id);
+				self.session.unsubscribe(&consumer.subscription_id);
 			} else {
 				error!("could not find consumer for subject [{}]", subject);
 			}
@@ -206,135 +382,446 @@ impl BrokerSession {
 	}
 
 	fn unsubscribe(&mut self, id: &str) {
-		if let Some(consumer) = self.consumers.lock().remove(id) {
-			if let Some(_) = self
+		if let Some(consumer) = self.consumers.remove(id) {
+			if self
 				.subject_to_consumer_id_lookup
-				.lock()
 				.remove(&consumer.subject)
+				.is_some()
 			{
 				self.subscription_id_to_consumer_id_lookup
-					.lock()
 					.remove(&consumer.subscription_id);
-				self.session.lock().unsubscribe(&consumer.subscription_id);
+				self.session.unsubscribe(&consumer.subscription_id);
 			} else {
 				error!("could not find consumer for id [{}]", id);
 			}
 		}
 	}
 
+	/// Acknowledge a message its consumer passed on. Acknowledgements of
+	/// messages the broker redelivered since, after an unsubscribe, are dropped
+	/// as the broker would refuse them.
+	fn acknowledge(&mut self, id: &str, ack_id: &str) {
+		let delivered = match self.consumers.get_mut(id) {
+			Some(consumer) => consumer.unacked.remove(ack_id),
+			None => false,
+		};
+		if delivered {
+			self.session.send_frame(Frame::ack(ack_id));
+			self.catch_up(id);
+		} else {
+			debug!("dropping stale ack [{}] of consumer [{}]", ack_id, id);
+		}
+	}
+
+	/// Hand the messages held back for consumer `id` over while it has room.
+	fn catch_up(&mut self, id: &str) {
+		let gone = match self.consumers.get_mut(id) {
+			Some(consumer) => consumer.catch_up(),
+			None => return,
+		};
+		if !gone.is_empty() {
+			error!("failed sending broker message to channel!");
+		}
+		// back to the queue, for the next subscriber
+		for ack_id in gone {
+			self.gauges.requeued();
+			self.session.send_frame(Frame::nack(&ack_id));
+		}
+	}
+
+	/// Publish a message asking for a receipt, returning the receipt id.
 	fn publish(
-		&self,
+		&mut self,
 		subject: &str,
 		payload: &str,
 		reply_to: &str,
 		message_expiration_in_seconds: Option<u32>,
-	) {
-		let destination = format!("/queue/{}", subject);;
-
-		let message_expiration = match message_expiration_in_seconds {
-			Some(message_expiration_in_seconds @ 1...86400) => {
-				format!("{}", message_expiration_in_seconds * 1000)
-			}
-			_ => format!("{}", DEFAULT_MESSAGE_EXPIRATION * 1000),
-		};
+	) -> String {
+		let destination = format!("/queue/{}", subject);
+		let message = self.session.message(&destination, payload);
+		with_relay_headers(message, reply_to, message_expiration_in_seconds)
+			.with(GenerateReceipt)
+			.send()
+			.expect("message without a receipt request")
+	}
 
-		self.session
-			.lock()
-			.message(&destination, payload)
-			.with(Header::new(
-				HeaderName::from_str("x-expires"),
-				DEFAULT_QUEUE_EXPIRATION,
-			))
-			.with(Header::new(
-				HeaderName::from_str("expiration"),
-				&message_expiration,
-			))
-			.with(Header::new(
-				HeaderName::from_str(REPLY_TO_HEADER_NAME),
-				reply_to,
-			))
-			.send();
+	/// Publish every message in a single transaction, returning the receipt
+	/// id of the commit.
+	fn publish_batch(
+		&mut self,
+		messages: &[BatchMessage],
+		reply_to: &str,
+		message_expiration_in_seconds: Option<u32>,
+	) -> String {
+		let mut transaction = self.session.begin_transaction();
+		for message in messages {
+			let destination = format!("/queue/{}", message.subject);
+			let frame = transaction.message(&destination, message.payload.as_str());
+			with_relay_headers(frame, reply_to, message_expiration_in_seconds).send();
+		}
+		transaction.commit_with_receipt()
 	}
 
 	fn on_message(&mut self, frame: Frame) {
 		if let Some(subscription_id) = frame.headers.get(SUBSCRIPTION) {
-			match self
-				.subscription_id_to_consumer_id_lookup
-				.lock()
-				.get(subscription_id)
-			{
-				Some(consumer_id) => match self.consumers.lock().get(consumer_id) {
-					Some(consumer) => {
-						if let Some(reply_to) = frame
-							.headers
-							.get(HeaderName::from_str(REPLY_TO_HEADER_NAME))
-						{
-							let payload = std::str::from_utf8(&frame.body).unwrap();
-							let response = BrokerResponse::Message {
-								subject: consumer.subject.clone(),
-								payload: payload.to_string(),
-								reply_to: reply_to.to_string(),
-							};
-							if consumer.sender.unbounded_send(response).is_err() {
-								error!("failed sending broker message to channel!");
-							};
-						} else {
-							error!("reply_to header missing on message!");
-						}
+			if self.presence_subscription_id.as_deref() == Some(subscription_id) {
+				let payload = String::from_utf8_lossy(&frame.body).into_owned();
+				match self.presence_sender.try_send(payload) {
+					Ok(()) => {}
+					// the next heartbeat of its relay tells again
+					Err(TrySendError::Full(_)) => {
+						warn!("presence listener is behind, dropping presence event");
+						self.gauges.presence_dropped();
 					}
-					None => {
-						error!("missing consumer for message frame [{}]", subscription_id);
+					Err(TrySendError::Closed(_)) => {
+						error!("failed sending presence event to channel!")
 					}
-				},
+				}
+				return;
+			}
+
+			let consumers = &mut self.consumers;
+			let consumer = self
+				.subscription_id_to_consumer_id_lookup
+				.get(subscription_id)
+				.and_then(|consumer_id| consumers.get_mut(consumer_id));
+			let consumer = match consumer {
+				Some(consumer) => consumer,
 				None => {
 					error!("missing consumer for message frame [{}]", subscription_id);
+					return;
+				}
+			};
+
+			let reply_to = match frame
+				.headers
+				.get(HeaderName::from_str(REPLY_TO_HEADER_NAME))
+			{
+				Some(reply_to) => reply_to,
+				None => {
+					error!("reply_to header missing on message!");
+					return;
+				}
+			};
+			let ack_id = match frame.headers.get(ACK) {
+				Some(ack_id) => ack_id.to_string(),
+				None => {
+					error!("ack header missing on message!");
+					return;
 				}
+			};
+			// a body that is not utf-8 is no slate, the connection acknowledges
+			// it as an invalid payload
+			let payload = String::from_utf8_lossy(&frame.body).into_owned();
+			let response = BrokerResponse::Message {
+				subject: consumer.subject.clone(),
+				payload,
+				reply_to: reply_to.to_string(),
+				ack_id: ack_id.clone(),
+			};
+			// the client is too slow, hold the message until it catches up
+			// rather than requeueing it, as the broker would redeliver it at once
+			if consumer.backlog.is_empty() && consumer.sender.capacity() == 0 {
+				warn!("consumer [{}] is full, holding back", subscription_id);
 			}
+			consumer.backlog.push_back((ack_id, response));
+			let id = self.subscription_id_to_consumer_id_lookup[subscription_id].clone();
+			self.catch_up(&id);
 		}
 	}
 }
 
-impl Future for BrokerSession {
-	type Item = ();
-	type Error = std::io::Error;
+/// What a post refused with an ERROR frame, by its `message` header, tells
+/// its poster.
+fn broker_error(message: &str) -> GrinboxError {
+	match message.to_lowercase().as_str() {
+		"not_found" => GrinboxError::Offline,
+		"resource_locked" => GrinboxError::Busy,
+		"precondition_failed" | "invalid destination" | "message too large" => {
+			GrinboxError::InvalidRequest
+		}
+		_ => GrinboxError::BrokerUnavailable,
+	}
+}
 
-	fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-		let msg = match try_ready!(self.session.lock().poll()) {
-			None => {
-				return Ok(Async::Ready(()));
-			}
-			Some(msg) => msg,
+fn with_relay_headers<'a, T>(
+	message: MessageBuilder<'a, T>,
+	reply_to: &str,
+	message_expiration_in_seconds: Option<u32>,
+) -> MessageBuilder<'a, T>
+where
+	T: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+	let message_expiration = match message_expiration_in_seconds {
+		Some(message_expiration_in_seconds @ 1..=86400) => {
+			format!("{}", message_expiration_in_seconds * 1000)
+		}
+		_ => format!("{}", DEFAULT_MESSAGE_EXPIRATION * 1000),
+	};
+
+	message
+		.with(Header::new(
+			HeaderName::from_str("x-expires"),
+			DEFAULT_QUEUE_EXPIRATION,
+		))
+		.with(Header::new(
+			HeaderName::from_str("expiration"),
+			&message_expiration,
+		))
+		.with(Header::new(
+			HeaderName::from_str(REPLY_TO_HEADER_NAME),
+			reply_to,
+		))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::broker::stomp::frame::Command;
+	use crate::broker::stomp::session::mock::{connected_session, frame, poll, MockBroker};
+	use crate::broker::stomp::session::DisconnectionReason;
+	use tokio::io::DuplexStream;
+	use tokio::sync::oneshot;
+
+	fn broker_session() -> (BrokerSession<DuplexStream>, MockBroker) {
+		let (session, broker) = connected_session();
+		let (presence_sender, _) = channel(1);
+		let session = BrokerSession {
+			session,
+			session_number: 0,
+			consumers: HashMap::new(),
+			subject_to_consumer_id_lookup: HashMap::new(),
+			subscription_id_to_consumer_id_lookup: HashMap::new(),
+			consumer_shortname_to_subject_loopup: Arc::new(Mutex::new(HashMap::new())),
+			presence_sender,
+			presence_subscription_id: None,
+			deliveries: HashMap::new(),
+			gauges: Arc::new(QueueGauges::new()),
+			prefetch_count: 4,
 		};
+		(session, broker)
+	}
 
-		trace!("msg: {:?}", msg);
-		match msg {
-			SessionEvent::Connected => {
-				self.on_connected();
-			}
+	fn deliver(session: &mut BrokerSession<DuplexStream>, id: &str, ack_id: &str) {
+		let subscription_id = session.consumers[id].subscription_id.clone();
+		session.on_message(frame(
+			Command::Message,
+			&[
+				("subscription", &subscription_id),
+				(REPLY_TO_HEADER_NAME, "gn1b"),
+				("ack", ack_id),
+			],
+		));
+	}
 
-			SessionEvent::Message {
-				destination: _destination,
-				ack_mode: _ack_mode,
-				frame,
-			} => self.on_message(frame),
+	/// Post a message over the session, returning the receipt id the broker
+	/// sees and the poster's end of the delivery.
+	fn post(
+		session: &mut BrokerSession<DuplexStream>,
+		broker: &mut MockBroker,
+	) -> (
+		String,
+		oneshot::Receiver<std::result::Result<(), GrinboxError>>,
+	) {
+		let (delivery, delivered) = oneshot::channel();
+		session.on_request(BrokerRequest::PostMessage {
+			subject: "gn1a".to_string(),
+			payload: "slate".to_string(),
+			reply_to: "gn1b".to_string(),
+			message_expiration_in_seconds: None,
+			delivery,
+		});
+		poll(&mut session.session);
+		let frames = broker.received();
+		assert!(frames[0].starts_with("SEND\n"));
+		let receipt_id = frames[0]
+			.lines()
+			.find_map(|line| line.strip_prefix("receipt:"))
+			.expect("send without a receipt request")
+			.to_string();
+		(receipt_id, delivered)
+	}
 
-			SessionEvent::Error(frame) => {
-				error!("session error event: {}", frame);
-			}
+	/// Poll the session for its next event and handle it.
+	fn handle_next_event(session: &mut BrokerSession<DuplexStream>) -> bool {
+		let event = poll(&mut session.session).expect("no session event");
+		session.on_event(event)
+	}
 
-			SessionEvent::Disconnected(reason) => {
-				warn!(
-					"session [{}] disconnected due to [{:?}]",
-					self.session_number, reason
-				);
-				return Ok(Async::Ready(()));
-			}
+	#[test]
+	fn receipts_confirm_their_post() {
+		let (mut session, mut broker) = broker_session();
+		let (first, mut first_delivered) = post(&mut session, &mut broker);
+		let (_, mut second_delivered) = post(&mut session, &mut broker);
+
+		broker.send(frame(Command::Receipt, &[("receipt-id", &first)]));
+		assert!(handle_next_event(&mut session));
+		assert_eq!(first_delivered.try_recv(), Ok(Ok(())));
+		assert!(second_delivered.try_recv().is_err());
+		assert_eq!(session.deliveries.len(), 1);
+
+		// confirmed already
+		session.on_receipt(first, Ok(()));
+		assert_eq!(session.deliveries.len(), 1);
+	}
 
-			m => {
-				warn!("unexepcted msg: {:?}", m);
-			}
+	#[test]
+	fn errors_fail_their_post() {
+		let (mut session, mut broker) = broker_session();
+		let (first, mut first_delivered) = post(&mut session, &mut broker);
+		let (_, mut second_delivered) = post(&mut session, &mut broker);
+
+		broker.send(frame(
+			Command::Error,
+			&[("receipt-id", &first), ("message", "not_found")],
+		));
+		assert!(handle_next_event(&mut session));
+		assert_eq!(first_delivered.try_recv(), Ok(Err(GrinboxError::Offline)));
+		assert!(second_delivered.try_recv().is_err());
+		assert_eq!(session.deliveries.len(), 1);
+
+		// the broker closes the connection after an error
+		session.on_event(SessionEvent::Disconnected(DisconnectionReason::Requested));
+		let unavailable = Ok(Err(GrinboxError::BrokerUnavailable));
+		assert_eq!(second_delivered.try_recv(), unavailable);
+		assert!(session.deliveries.is_empty());
+	}
+
+	#[test]
+	fn commit_receipts_confirm_the_batch() {
+		let (mut session, mut broker) = broker_session();
+		let (delivery, mut delivered) = oneshot::channel();
+		let messages = ["gn1a", "gn1b"]
+			.iter()
+			.map(|subject| BatchMessage {
+				subject: subject.to_string(),
+				payload: "slate".to_string(),
+			})
+			.collect();
+		session.on_request(BrokerRequest::PostBatch {
+			messages,
+			reply_to: "gn1c".to_string(),
+			message_expiration_in_seconds: None,
+			delivery,
+		});
+		poll(&mut session.session);
+
+		let frames = broker.received();
+		assert_eq!(frames.len(), 4);
+		assert!(frames[0].starts_with("BEGIN\n"));
+		assert!(frames[1].starts_with("SEND\n") && frames[1].contains("\ntransaction:"));
+		assert!(frames[2].starts_with("SEND\n") && frames[2].contains("\ntransaction:"));
+		assert!(frames[3].starts_with("COMMIT\n"));
+		let receipt_id = frames[3]
+			.lines()
+			.find_map(|line| line.strip_prefix("receipt:"))
+			.expect("commit without a receipt request")
+			.to_string();
+		assert!(delivered.try_recv().is_err());
+
+		broker.send(frame(Command::Receipt, &[("receipt-id", &receipt_id)]));
+		assert!(handle_next_event(&mut session));
+		assert_eq!(delivered.try_recv(), Ok(Ok(())));
+	}
+
+	#[test]
+	fn presence_events_are_dropped_when_the_listener_is_behind() {
+		let (mut session, _broker) = broker_session();
+		let (presence_sender, mut events) = channel(1);
+		session.presence_sender = presence_sender;
+		session.presence_subscription_id = Some("presence".to_string());
+		session.on_message(frame(Command::Message, &[("subscription", "presence")]));
+		session.on_message(frame(Command::Message, &[("subscription", "presence")]));
+
+		assert!(events.try_recv().is_ok());
+		assert!(events.try_recv().is_err());
+		assert_eq!(session.gauges.report().presence_dropped, 1);
+	}
+
+	#[test]
+	fn full_consumers_catch_up_as_they_acknowledge() {
+		let (mut session, mut broker) = broker_session();
+		let (sender, mut responses) = channel(1);
+		session.subscribe("c".to_string(), "gn1a".to_string(), sender);
+		poll(&mut session.session);
+		broker.received();
+
+		deliver(&mut session, "c", "m-1");
+		deliver(&mut session, "c", "m-2");
+		assert!(responses.try_recv().is_ok());
+		assert!(responses.try_recv().is_err());
+		poll(&mut session.session);
+		// held back, not requeued
+		assert!(broker.received().is_empty());
+
+		session.acknowledge("c", "m-1");
+		match responses.try_recv() {
+			Ok(BrokerResponse::Message { ack_id, .. }) => assert_eq!(ack_id, "m-2"),
+			_ => panic!("held back message not handed over"),
 		}
+		drop(responses);
+		deliver(&mut session, "c", "m-3");
+		session.acknowledge("c", "m-2");
+		poll(&mut session.session);
+
+		let frames = broker.received();
+		assert_eq!(frames.len(), 3);
+		assert!(
+			frames[0].starts_with(
+				"ACK
+"
+			) && frames[0].contains(
+				":m-1
+"
+			)
+		);
+		assert!(
+			frames[1].starts_with(
+				"NACK
+"
+			) && frames[1].contains(
+				":m-3
+"
+			)
+		);
+		assert!(
+			frames[2].starts_with(
+				"ACK
+"
+			) && frames[2].contains(
+				":m-2
+"
+			)
+		);
+	}
 
-		Ok(Async::NotReady)
+	#[test]
+	fn drops_stale_acks() {
+		let (mut session, mut broker) = broker_session();
+		let (sender, mut responses) = channel(4);
+		session.subscribe("c".to_string(), "gn1a".to_string(), sender);
+		deliver(&mut session, "c", "m-1");
+		deliver(&mut session, "c", "m-2");
+		assert!(responses.try_recv().is_ok());
+		assert!(responses.try_recv().is_ok());
+		poll(&mut session.session);
+		let subscribe = broker.received();
+		assert!(subscribe[0].contains("\nprefetch-count:4\n"));
+
+		session.acknowledge("c", "m-1");
+		// acknowledged already
+		session.acknowledge("c", "m-1");
+		// never delivered to this consumer
+		session.acknowledge("d", "m-2");
+		// redelivered by the broker once the consumer is gone
+		session.unsubscribe("c");
+		session.acknowledge("c", "m-2");
+		poll(&mut session.session);
+
+		let frames = broker.received();
+		assert_eq!(frames.len(), 2);
+		assert!(frames[0].starts_with("ACK\n"));
+		assert!(frames[0].contains(":m-1\n"));
+		assert!(frames[1].starts_with("UNSUBSCRIBE\n"));
 	}
 }
@@ -1,18 +1,26 @@
-use bytes::BytesMut;
-use futures::prelude::*;
+use bytes::{Buf, BytesMut};
 use std::io::Error as IoError;
 use std::str;
-use tokio_io::codec::{Decoder, Encoder};
+use tokio_util::codec::{Decoder, Encoder};
 
 use super::frame::{Command, Frame, Transmission};
 use super::header::{Header, HeaderList, HeaderName, CONTENT_LENGTH};
 
+/// `Ok(None)` until enough bytes have arrived.
+type Parse<T> = Result<Option<T>, ParseError>;
+
 macro_rules! opt_nr {
 	($opt: expr) => {
 		match $opt {
 			Some(v) => v,
-			None => return Ok(Async::NotReady),
-			}
+			None => return Ok(None),
+		}
+	};
+}
+
+macro_rules! try_parse {
+	($parse: expr) => {
+		opt_nr!($parse?)
 	};
 }
 
@@ -30,13 +38,10 @@ impl std::fmt::Display for ParseError {
 }
 impl std::error::Error for ParseError {}
 
-fn parse_transmission(src0: &[u8]) -> Poll<(Transmission, usize), ParseError> {
-	let (command, mut src) = try_ready!(get_line(src0));
+fn parse_transmission(src0: &[u8]) -> Parse<(Transmission, usize)> {
+	let (command, mut src) = try_parse!(get_line(src0));
 	if command.is_empty() {
-		return Ok(Async::Ready((
-			Transmission::HeartBeat,
-			src0.len() - src.len(),
-		)));
+		return Ok(Some((Transmission::HeartBeat, src0.len() - src.len())));
 	}
 
 	let command = parse_command(command)?;
@@ -44,12 +49,12 @@ fn parse_transmission(src0: &[u8]) -> Poll<(Transmission, usize), ParseError> {
 	let mut headers = HeaderList::new();
 
 	loop {
-		let (line, src1) = try_ready!(get_line(src));
+		let (line, src1) = try_parse!(get_line(src));
 		src = src1;
 		if line.is_empty() {
 			break;
 		}
-		let header = try_ready!(parse_header(line));
+		let header = try_parse!(parse_header(line));
 		headers.push(header);
 	}
 
@@ -57,7 +62,7 @@ fn parse_transmission(src0: &[u8]) -> Poll<(Transmission, usize), ParseError> {
 		Some(len) => {
 			let len = len.parse().map_err(|_e| ParseError::ContentLength)?;
 			if src.len() <= len {
-				return Ok(Async::NotReady);
+				return Ok(None);
 			}
 			if src[len] != b'\0' {
 				return Err(ParseError::Invalid);
@@ -80,20 +85,20 @@ fn parse_transmission(src0: &[u8]) -> Poll<(Transmission, usize), ParseError> {
 		body,
 	};
 
-	Ok(Async::Ready((
+	Ok(Some((
 		Transmission::CompleteFrame(frame),
 		src0.len() - src.len(),
 	)))
 }
 
-fn parse_header(src: &[u8]) -> Poll<Header, ParseError> {
+fn parse_header(src: &[u8]) -> Parse<Header> {
 	let src = str::from_utf8(src).map_err(|_e| ParseError::Utf8)?;
 	let mut parts = src.split(':');
 
 	let key = opt_nr!(parts.next());
 	let value = opt_nr!(parts.next());
 
-	Ok(Async::Ready(Header::new(
+	Ok(Some(Header::new(
 		HeaderName::from_str(key),
 		&Header::decode_value(value),
 	)))
@@ -114,7 +119,7 @@ fn parse_command(src: &[u8]) -> Result<Command, ParseError> {
 	Ok(command)
 }
 
-fn get_line<'a>(src: &'a [u8]) -> Poll<(&'a [u8], &'a [u8]), ParseError> {
+fn get_line(src: &[u8]) -> Parse<(&[u8], &[u8])> {
 	let mut split = src.splitn(2, |b| *b == b'\n');
 
 	let mut line = opt_nr!(split.next());
@@ -123,13 +128,12 @@ fn get_line<'a>(src: &'a [u8]) -> Poll<(&'a [u8], &'a [u8]), ParseError> {
 	if !line.is_empty() && line[line.len() - 1] == b'\r' {
 		line = &line[..(line.len() - 1)];
 	}
-	Ok(Async::Ready((line, remain)))
+	Ok(Some((line, remain)))
 }
 
 pub struct Codec;
 
-impl Encoder for Codec {
-	type Item = Transmission;
+impl Encoder<Transmission> for Codec {
 	type Error = IoError;
 	fn encode(&mut self, item: Transmission, buffer: &mut BytesMut) -> Result<(), IoError> {
 		item.write(buffer);
@@ -142,13 +146,13 @@ impl Decoder for Codec {
 	type Error = IoError;
 
 	fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Transmission>, IoError> {
-		match parse_transmission(&src) {
-			Ok(Async::NotReady) => Ok(None),
-			Ok(Async::Ready((t, len))) => {
-				src.split_to(len);
+		match parse_transmission(src) {
+			Ok(None) => Ok(None),
+			Ok(Some((t, len))) => {
+				src.advance(len);
 				Ok(Some(t))
 			}
-			Err(e) => Err(std::io::Error::new(std::io::ErrorKind::Other, e)),
+			Err(e) => Err(std::io::Error::new(std::io::ErrorKind::InvalidData, e)),
 		}
 	}
 }
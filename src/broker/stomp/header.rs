@@ -186,6 +186,7 @@ standard_headers! {
 	(Host, HOST, "host");
 	(Id, ID, "id");
 	(Login, LOGIN, "login");
+	(Message, MESSAGE, "message");
 	(MessageId, MESSAGE_ID, "message-id");
 	(Passcode, PASSCODE, "passcode");
 	(Receipt, RECEIPT, "receipt");
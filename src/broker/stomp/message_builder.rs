@@ -10,7 +10,7 @@ pub struct MessageBuilder<'a, T: 'static> {
 
 impl<'a, T> MessageBuilder<'a, T>
 where
-	T: tokio_io::AsyncWrite + tokio_io::AsyncRead + Send + 'static,
+	T: tokio::io::AsyncWrite + tokio::io::AsyncRead + Send + Unpin + 'static,
 {
 	pub fn new(session: &'a mut Session<T>, frame: Frame) -> Self {
 		MessageBuilder {
@@ -20,15 +20,17 @@ where
 		}
 	}
 
-	pub fn send(self) {
-		if self.receipt_request.is_some() {
-			let request = self.receipt_request.unwrap();
-			self.session
-				.state
-				.outstanding_receipts
-				.insert(request.id, OutstandingReceipt::new(self.frame.clone()));
+	/// Send the message, returning the id of the receipt it asked for, if any.
+	pub fn send(self) -> Option<String> {
+		let receipt_id = self.receipt_request.map(|request| request.id);
+		if let Some(ref receipt_id) = receipt_id {
+			self.session.state.outstanding_receipts.insert(
+				receipt_id.clone(),
+				OutstandingReceipt::new(self.frame.clone()),
+			);
 		}
-		self.session.send_frame(self.frame)
+		self.session.send_frame(self.frame);
+		receipt_id
 	}
 
 	pub fn with<O>(self, option_setter: O) -> MessageBuilder<'a, T>
@@ -3,7 +3,7 @@ use super::header::*;
 use super::message_builder::MessageBuilder;
 use super::session::{GenerateReceipt, ReceiptRequest};
 use super::session_builder::SessionBuilder;
-use super::subscription::AckMode;
+use super::subscription::{AckMode, PrefetchCount};
 use super::subscription_builder::SubscriptionBuilder;
 
 pub trait OptionSetter<T> {
@@ -84,9 +84,17 @@ impl<'a, T> OptionSetter<SubscriptionBuilder<'a, T>> for AckMode {
 	}
 }
 
+impl<'a, T> OptionSetter<SubscriptionBuilder<'a, T>> for PrefetchCount {
+	fn set_option(self, mut builder: SubscriptionBuilder<'a, T>) -> SubscriptionBuilder<'a, T> {
+		let PrefetchCount(count) = self;
+		builder.prefetch_count = Some(count);
+		builder
+	}
+}
+
 impl<'a, T> OptionSetter<MessageBuilder<'a, T>> for GenerateReceipt
 where
-	T: tokio_io::AsyncWrite + tokio_io::AsyncRead + Send + 'static,
+	T: tokio::io::AsyncWrite + tokio::io::AsyncRead + Send + Unpin + 'static,
 {
 	fn set_option(self, mut builder: MessageBuilder<'a, T>) -> MessageBuilder<'a, T> {
 		let next_id = builder.session.generate_receipt_id();
@@ -102,7 +110,7 @@ where
 
 impl<'a, T> OptionSetter<SubscriptionBuilder<'a, T>> for GenerateReceipt
 where
-	T: tokio_io::AsyncWrite + tokio_io::AsyncRead + Send + 'static,
+	T: tokio::io::AsyncWrite + tokio::io::AsyncRead + Send + Unpin + 'static,
 {
 	fn set_option(self, mut builder: SubscriptionBuilder<'a, T>) -> SubscriptionBuilder<'a, T> {
 		let next_id = builder.session.generate_receipt_id();
@@ -1,14 +1,15 @@
-use futures::*;
+use futures::{Sink, Stream};
 use std::collections::hash_map::HashMap;
 use std::collections::VecDeque;
+use std::future::Future;
 use std::io::Error as IoError;
-use std::io::ErrorKind;
 use std::io::Result;
-use std::time::{Duration, Instant};
-use tokio_codec::Decoder;
-use tokio_codec::Framed;
-use tokio_io::{AsyncRead, AsyncWrite};
-use tokio_timer::Delay;
+use std::pin::Pin;
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::time::{sleep, Instant, Sleep};
+use tokio_util::codec::{Decoder, Framed};
 
 use super::codec::Codec;
 use super::connection::{self, select_heartbeat};
@@ -47,29 +48,23 @@ impl ReceiptRequest {
 
 struct HeartBeatDelay {
 	interval: u32,
-	delay: Delay,
+	delay: Pin<Box<Sleep>>,
 }
 impl HeartBeatDelay {
 	fn new(interval: u32) -> Self {
-		let delay = Delay::new(Instant::now() + Duration::from_millis(interval as _));
+		let delay = Box::pin(sleep(Duration::from_millis(interval as _)));
 		Self { interval, delay }
 	}
-}
-impl Future for HeartBeatDelay {
-	type Item = ();
-	type Error = IoError;
-
-	fn poll(&mut self) -> Poll<(), IoError> {
-		match self.delay.poll() {
-			Err(_e) => Err(IoError::new(ErrorKind::Other, "timer")),
-			Ok(res) => Ok(res),
-		}
+
+	fn reset(&mut self) {
+		let deadline = Instant::now() + Duration::from_millis(self.interval as _);
+		self.delay.as_mut().reset(deadline);
 	}
 }
-fn poll_heartbeat(mut heartbeat: Option<&mut HeartBeatDelay>) -> Poll<(), IoError> {
+fn poll_heartbeat(heartbeat: Option<&mut HeartBeatDelay>, cx: &mut Context) -> Poll<()> {
 	match heartbeat {
-		Some(ref mut inner) => inner.poll(),
-		None => Ok(Async::NotReady),
+		Some(inner) => inner.delay.as_mut().poll(cx),
+		None => Poll::Pending,
 	}
 }
 
@@ -102,7 +97,7 @@ impl SessionState {
 // *** Public API ***
 impl<T> Session<T>
 where
-	T: AsyncWrite + AsyncRead + Send + 'static,
+	T: AsyncWrite + AsyncRead + Send + Unpin + 'static,
 {
 	pub fn send_frame(&mut self, fr: Frame) {
 		self.send(Transmission::CompleteFrame(fr))
@@ -126,13 +121,13 @@ where
 
 	pub fn begin_transaction<'b>(&'b mut self) -> Transaction<'b, T> {
 		let mut transaction = Transaction::new(self);
-		let _ = transaction.begin();
+		transaction.begin();
 		transaction
 	}
 
 	pub fn unsubscribe(&mut self, sub_id: &str) {
 		self.state.subscriptions.remove(sub_id);
-		let unsubscribe_frame = Frame::unsubscribe(sub_id.as_ref());
+		let unsubscribe_frame = Frame::unsubscribe(sub_id);
 		self.send(CompleteFrame(unsubscribe_frame))
 	}
 
@@ -152,18 +147,20 @@ where
 	}
 }
 
-pub type ConnectFuture<T> = Box<Future<Item = T, Error = IoError> + Send>;
+pub type ConnectFuture<T> = Pin<Box<dyn Future<Output = Result<T>> + Send>>;
 
 // *** pub(crate) API ***
 impl<T> Session<T>
 where
-	T: AsyncWrite + AsyncRead + Send + 'static,
+	T: AsyncWrite + AsyncRead + Send + Unpin + 'static,
 {
 	pub(crate) fn new(config: SessionConfig, stream: ConnectFuture<T>) -> Self {
 		Self {
 			config,
 			state: SessionState::new(),
 			events: VecDeque::new(),
+			outbox: VecDeque::new(),
+			waker: None,
 			stream: StreamState::Connecting(stream),
 		}
 	}
@@ -192,58 +189,48 @@ pub struct Session<T> {
 	pub(crate) state: SessionState,
 	stream: StreamState<T>,
 	events: VecDeque<SessionEvent>,
+	/// Transmissions waiting for the next poll to be written
+	outbox: VecDeque<Transmission>,
+	/// Wakes the task polling the session when something is queued
+	waker: Option<Waker>,
 }
 
 // *** Internal API ***
 impl<T> Session<T>
 where
-	T: AsyncWrite + AsyncRead + Send + 'static,
+	T: AsyncWrite + AsyncRead + Send + Unpin + 'static,
 {
-	fn _send(&mut self, tx: Transmission) -> Result<()> {
-		if let StreamState::Connected(ref mut st) = self.stream {
-			st.start_send(tx)?;
-			st.poll_complete()?;
+	fn send(&mut self, tx: Transmission) {
+		if let StreamState::Connected(_) = self.stream {
+			self.outbox.push_back(tx);
+			if let Some(waker) = self.waker.take() {
+				waker.wake();
+			}
 		} else {
 			warn!("sending {:?} whilst disconnected", tx);
 		}
-		Ok(())
 	}
 
-	fn send(&mut self, tx: Transmission) {
-		if let Err(e) = self._send(tx) {
-			self.on_disconnect(DisconnectionReason::SendFailed(e));
+	fn register_tx_heartbeat_timeout(&mut self) {
+		if let Some(ref mut hb) = self.state.tx_heartbeat {
+			hb.reset();
 		}
 	}
 
-	fn register_tx_heartbeat_timeout(&mut self) -> Result<()> {
-		if let Some(mut hb) = self.state.tx_heartbeat.take() {
-			hb.delay = Delay::new(Instant::now() + Duration::from_millis(hb.interval as _));
-			self.state.tx_heartbeat = Some(hb);
+	fn register_rx_heartbeat_timeout(&mut self) {
+		if let Some(ref mut hb) = self.state.rx_heartbeat {
+			hb.reset();
 		}
-		Ok(())
 	}
 
-	fn register_rx_heartbeat_timeout(&mut self) -> Result<()> {
-		if let Some(mut hb) = self.state.rx_heartbeat.take() {
-			hb.delay = Delay::new(Instant::now() + Duration::from_millis(hb.interval as _));
-			self.state.rx_heartbeat = Some(hb);
-		}
-
-		Ok(())
-	}
-
-	fn on_recv_data(&mut self) -> Result<()> {
-		if self.state.rx_heartbeat.is_some() {
-			self.register_rx_heartbeat_timeout()?;
-		}
-		Ok(())
+	fn on_recv_data(&mut self) {
+		self.register_rx_heartbeat_timeout();
 	}
 
-	fn reply_to_heartbeat(&mut self) -> Result<()> {
+	fn reply_to_heartbeat(&mut self) {
 		debug!("Sending heartbeat");
 		self.send(HeartBeat);
-		self.register_tx_heartbeat_timeout()?;
-		Ok(())
+		self.register_tx_heartbeat_timeout();
 	}
 
 	fn on_disconnect(&mut self, reason: DisconnectionReason) {
@@ -252,6 +239,7 @@ where
 
 		// drop will disconnect undering AsyncIo
 		self.stream = StreamState::Failed;
+		self.outbox.clear();
 		self.state.tx_heartbeat = None;
 		self.state.rx_heartbeat = None;
 	}
@@ -291,7 +279,7 @@ where
 	fn on_message(&mut self, frame: Frame) {
 		let mut sub_data = None;
 		if let Some(sub_id) = frame.headers.get(SUBSCRIPTION) {
-			if let Some(ref sub) = self.state.subscriptions.get(sub_id) {
+			if let Some(sub) = self.state.subscriptions.get(sub_id) {
 				sub_data = Some((sub.destination.clone(), sub.ack_mode));
 			}
 		}
@@ -306,7 +294,7 @@ where
 		}
 	}
 
-	fn on_connected_frame_received(&mut self, connected_frame: Frame) -> Result<()> {
+	fn on_connected_frame_received(&mut self, connected_frame: Frame) {
 		// The Client's requested tx/rx HeartBeat timeouts
 		let connection::HeartBeat(client_tx_ms, client_rx_ms) = self.config.heartbeat;
 
@@ -330,21 +318,21 @@ where
 			ms => Some(HeartBeatDelay::new(ms)),
 		};
 
-		self.register_tx_heartbeat_timeout()?;
-		self.register_rx_heartbeat_timeout()?;
+		self.register_tx_heartbeat_timeout();
+		self.register_rx_heartbeat_timeout();
 
 		self.events.push_back(SessionEvent::Connected);
-
-		Ok(())
 	}
+	fn handle_error(&mut self, frame: Frame) {
+		// the frame the error is about will not get its receipt
+		if let Some(receipt_id) = frame.headers.get(RECEIPT_ID) {
+			self.state.outstanding_receipts.remove(receipt_id);
+		}
+		self.events.push_back(SessionEvent::Error(frame));
+	}
+
 	fn handle_receipt(&mut self, frame: Frame) {
-		let receipt_id = {
-			if let Some(receipt_id) = frame.headers.get(RECEIPT_ID) {
-				Some(receipt_id.to_owned())
-			} else {
-				None
-			}
-		};
+		let receipt_id = frame.headers.get(RECEIPT_ID).map(|id| id.to_owned());
 		if let Some(receipt_id) = receipt_id {
 			if receipt_id == "msg/disconnect" {
 				self.on_disconnect(DisconnectionReason::Requested);
@@ -360,12 +348,12 @@ where
 		}
 	}
 
-	fn poll_stream_complete(&mut self) {
+	fn poll_stream_complete(&mut self, cx: &mut Context) {
 		let res = {
 			if let StreamState::Connected(ref mut fr) = self.stream {
-				fr.poll_complete()
+				Self::write_outbox(fr, &mut self.outbox, cx)
 			} else {
-				Ok(Async::NotReady)
+				Ok(())
 			}
 		};
 		if let Err(e) = res {
@@ -373,46 +361,67 @@ where
 		}
 	}
 
-	fn poll_stream(&mut self) -> Async<Option<Transmission>> {
+	fn write_outbox(
+		fr: &mut Framed<T, Codec>,
+		outbox: &mut VecDeque<Transmission>,
+		cx: &mut Context,
+	) -> Result<()> {
+		while !outbox.is_empty() {
+			match Pin::new(&mut *fr).poll_ready(cx) {
+				Poll::Ready(Ok(())) => {
+					let tx = outbox.pop_front().unwrap();
+					Pin::new(&mut *fr).start_send(tx)?;
+				}
+				Poll::Ready(Err(e)) => return Err(e),
+				Poll::Pending => return Ok(()),
+			}
+		}
+		match Pin::new(fr).poll_flush(cx) {
+			Poll::Ready(Err(e)) => Err(e),
+			_ => Ok(()),
+		}
+	}
+
+	fn poll_stream(&mut self, cx: &mut Context) -> Poll<Option<Transmission>> {
 		use self::StreamState::*;
 		match ::std::mem::replace(&mut self.stream, Failed) {
-			Connected(mut fr) => match fr.poll() {
-				Ok(Async::Ready(Some(r))) => {
+			Connected(mut fr) => match Pin::new(&mut fr).poll_next(cx) {
+				Poll::Ready(Some(Ok(r))) => {
 					self.stream = Connected(fr);
-					Async::Ready(Some(r))
+					Poll::Ready(Some(r))
 				}
-				Ok(Async::Ready(None)) => {
+				Poll::Ready(None) => {
 					self.on_disconnect(DisconnectionReason::ClosedByOtherSide);
-					Async::NotReady
+					Poll::Pending
 				}
-				Ok(Async::NotReady) => {
+				Poll::Pending => {
 					self.stream = Connected(fr);
-					Async::NotReady
+					Poll::Pending
 				}
-				Err(e) => {
+				Poll::Ready(Some(Err(e))) => {
 					self.on_disconnect(DisconnectionReason::RecvFailed(e));
-					Async::NotReady
+					Poll::Pending
 				}
 			},
 
-			Connecting(mut tsn) => match tsn.poll() {
-				Ok(Async::Ready(s)) => {
+			Connecting(mut tsn) => match tsn.as_mut().poll(cx) {
+				Poll::Ready(Ok(s)) => {
 					let fr = Codec.framed(s);
 					self.stream = Connected(fr);
 					self.on_stream_ready();
-					self.poll_stream()
+					self.poll_stream(cx)
 				}
-				Ok(Async::NotReady) => {
+				Poll::Pending => {
 					self.stream = Connecting(tsn);
-					Async::NotReady
+					Poll::Pending
 				}
-				Err(e) => {
+				Poll::Ready(Err(e)) => {
 					self.on_disconnect(DisconnectionReason::ConnectFailed(e));
-					Async::NotReady
+					Poll::Pending
 				}
 			},
 
-			Failed => Async::NotReady,
+			Failed => Poll::Pending,
 		}
 	}
 }
@@ -454,51 +463,177 @@ pub(crate) enum StreamState<T> {
 
 impl<T> Stream for Session<T>
 where
-	T: AsyncWrite + AsyncRead + Send + 'static,
+	T: AsyncWrite + AsyncRead + Send + Unpin + 'static,
 {
 	type Item = SessionEvent;
-	type Error = IoError;
 
-	fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
-		while let Async::Ready(Some(val)) = self.poll_stream() {
+	fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+		let this = self.get_mut();
+		this.waker = Some(cx.waker().clone());
+
+		while let Poll::Ready(Some(val)) = this.poll_stream(cx) {
 			match val {
 				HeartBeat => {
 					debug!("Received heartbeat.");
-					self.on_recv_data()?;
+					this.on_recv_data();
 				}
 				CompleteFrame(frame) => {
 					trace!(
 						"Received frame: {}",
 						serde_json::to_string_pretty(&frame).unwrap()
 					);
-					self.on_recv_data()?;
+					this.on_recv_data();
 					match frame.command {
-						Command::Error => self.events.push_back(SessionEvent::Error(frame)),
-						Command::Receipt => self.handle_receipt(frame),
-						Command::Connected => self.on_connected_frame_received(frame)?,
-						Command::Message => self.on_message(frame),
-						_ => self.events.push_back(SessionEvent::Unknown(frame)),
+						Command::Error => this.handle_error(frame),
+						Command::Receipt => this.handle_receipt(frame),
+						Command::Connected => this.on_connected_frame_received(frame),
+						Command::Message => this.on_message(frame),
+						_ => this.events.push_back(SessionEvent::Unknown(frame)),
 					};
 				}
 			}
 		}
 
-		if let Async::Ready(_) = poll_heartbeat(self.state.rx_heartbeat.as_mut())? {
-			self.on_disconnect(DisconnectionReason::HeartBeatTimeout);
+		if let Poll::Ready(()) = poll_heartbeat(this.state.rx_heartbeat.as_mut(), cx) {
+			this.on_disconnect(DisconnectionReason::HeartBeatTimeout);
 		}
 
-		if let Async::Ready(_) = poll_heartbeat(self.state.tx_heartbeat.as_mut())? {
-			self.reply_to_heartbeat()?;
+		// polling again after the reset registers the next deadline
+		while let Poll::Ready(()) = poll_heartbeat(this.state.tx_heartbeat.as_mut(), cx) {
+			this.reply_to_heartbeat();
 		}
 
-		self.poll_stream_complete();
+		this.poll_stream_complete(cx);
+
+		match this.events.pop_front() {
+			Some(ev) => Poll::Ready(Some(ev)),
+			None => match this.stream {
+				StreamState::Failed => Poll::Ready(None),
+				_ => Poll::Pending,
+			},
+		}
+	}
+}
+
+#[cfg(test)]
+pub(crate) mod mock {
+	use super::*;
+	use crate::broker::stomp::session_builder::SessionBuilder;
+	use bytes::BytesMut;
+	use futures::{FutureExt, StreamExt};
+	use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+	/// The broker's end of a session over an in-memory transport.
+	pub(crate) struct MockBroker(DuplexStream);
+
+	impl MockBroker {
+		/// Frames the session wrote since the last call, as text.
+		pub(crate) fn received(&mut self) -> Vec<String> {
+			let mut data = Vec::new();
+			let mut buffer = [0; 4096];
+			while let Some(Ok(read)) = self.0.read(&mut buffer).now_or_never() {
+				if read == 0 {
+					break;
+				}
+				data.extend_from_slice(&buffer[..read]);
+			}
+			String::from_utf8_lossy(&data)
+				.split('\0')
+				.map(|frame| frame.trim_start_matches('\n').to_string())
+				.filter(|frame| !frame.is_empty())
+				.collect()
+		}
+
+		pub(crate) fn send(&mut self, frame: Frame) {
+			let mut data = BytesMut::new();
+			frame.write(&mut data);
+			self.0
+				.write_all(&data)
+				.now_or_never()
+				.expect("transport full")
+				.unwrap();
+		}
+	}
+
+	/// A session whose transport is up, its CONNECT frame already consumed.
+	pub(crate) fn connected_session() -> (Session<DuplexStream>, MockBroker) {
+		let (client, broker) = tokio::io::duplex(64 * 1024);
+		let mut session = SessionBuilder::new().build(Box::pin(async move { Ok(client) }));
+		let mut broker = MockBroker(broker);
+		assert!(poll(&mut session).is_none());
+		assert!(broker.received()[0].starts_with("CONNECT\n"));
+		(session, broker)
+	}
+
+	/// Poll the session once, writing out what it queued, and return the
+	/// event it had ready, if any.
+	pub(crate) fn poll(session: &mut Session<DuplexStream>) -> Option<SessionEvent> {
+		session.next().now_or_never().flatten()
+	}
+
+	pub(crate) fn frame(command: Command, headers: &[(&str, &str)]) -> Frame {
+		let mut header_list = HeaderList::new();
+		for (name, value) in headers {
+			header_list.push(Header::new(HeaderName::from_str(name), value));
+		}
+		Frame {
+			command,
+			headers: header_list,
+			body: Vec::new(),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::mock::{connected_session, frame, poll};
+	use super::*;
+
+	#[test]
+	fn receipts_are_matched_to_their_frame() {
+		let (mut session, mut broker) = connected_session();
+		let receipt_id = session
+			.message("/queue/a", "slate")
+			.with(GenerateReceipt)
+			.send()
+			.unwrap();
+		poll(&mut session);
+		assert!(broker.received()[0].contains(&format!("\nreceipt:{}\n", receipt_id)));
+
+		// unknown receipts are ignored
+		broker.send(frame(Command::Receipt, &[("receipt-id", "message/99")]));
+		broker.send(frame(Command::Receipt, &[("receipt-id", &receipt_id)]));
+		match poll(&mut session) {
+			Some(SessionEvent::Receipt { id, original, .. }) => {
+				assert_eq!(id, receipt_id);
+				assert!(matches!(original.command, Command::Send));
+			}
+			event => panic!("unexpected event {:?}", event),
+		}
+		assert!(poll(&mut session).is_none());
+		assert!(session.state.outstanding_receipts.is_empty());
+	}
 
-		match self.events.pop_front() {
-			None => Ok(Async::NotReady),
-			Some(ev) => {
-				task::current().notify();
-				Ok(Async::Ready(Some(ev)))
+	#[test]
+	fn errors_end_their_outstanding_receipt() {
+		let (mut session, mut broker) = connected_session();
+		let receipt_id = session
+			.message("/queue/a", "slate")
+			.with(GenerateReceipt)
+			.send()
+			.unwrap();
+		poll(&mut session);
+
+		broker.send(frame(
+			Command::Error,
+			&[("receipt-id", &receipt_id), ("message", "not_found")],
+		));
+		match poll(&mut session) {
+			Some(SessionEvent::Error(frame)) => {
+				assert_eq!(frame.headers.get(RECEIPT_ID), Some(receipt_id.as_str()))
 			}
+			event => panic!("unexpected event {:?}", event),
 		}
+		assert!(session.state.outstanding_receipts.is_empty());
 	}
 }
@@ -24,17 +24,17 @@ impl SessionBuilder {
 				CONTENT_LENGTH => "0"
 			],
 		};
-		SessionBuilder { config: config }
+		SessionBuilder { config }
 	}
 
 	pub fn build<T>(self, conn: ConnectFuture<T>) -> Session<T>
 	where
-		T: tokio_io::AsyncWrite + tokio_io::AsyncRead + Send + 'static,
+		T: tokio::io::AsyncWrite + tokio::io::AsyncRead + Send + Unpin + 'static,
 	{
 		Session::new(self.config, conn)
 	}
 
-	pub fn with<'b, O>(self, option_setter: O) -> SessionBuilder
+	pub fn with<O>(self, option_setter: O) -> SessionBuilder
 	where
 		O: OptionSetter<SessionBuilder>,
 	{
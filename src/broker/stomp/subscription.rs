@@ -17,6 +17,12 @@ impl AckMode {
 	}
 }
 
+/// RabbitMQ's `prefetch-count`, the number of messages sent to a subscription
+/// before the broker waits for acknowledgements. It has no effect with
+/// `AckMode::Auto`, which acknowledges every message on delivery.
+#[derive(Copy, Clone, Debug)]
+pub struct PrefetchCount(pub u32);
+
 #[derive(Clone, Copy)]
 pub enum AckOrNack {
 	Ack,
@@ -35,8 +41,8 @@ impl Subscription {
 		Subscription {
 			id: format!("stomp-rs/{}", id),
 			destination: destination.to_string(),
-			ack_mode: ack_mode,
-			headers: headers,
+			ack_mode,
+			headers,
 		}
 	}
 }
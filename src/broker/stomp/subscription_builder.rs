@@ -1,5 +1,5 @@
 use super::frame::Frame;
-use super::header::HeaderList;
+use super::header::{Header, HeaderList, HeaderName};
 use super::option_setter::OptionSetter;
 use super::session::{OutstandingReceipt, ReceiptRequest, Session};
 use super::subscription::{AckMode, Subscription};
@@ -8,19 +8,21 @@ pub struct SubscriptionBuilder<'a, T: 'static> {
 	pub session: &'a mut Session<T>,
 	pub destination: String,
 	pub ack_mode: AckMode,
+	pub prefetch_count: Option<u32>,
 	pub headers: HeaderList,
 	pub receipt_request: Option<ReceiptRequest>,
 }
 
 impl<'a, T> SubscriptionBuilder<'a, T>
 where
-	T: tokio_io::AsyncWrite + tokio_io::AsyncRead + Send + 'static,
+	T: tokio::io::AsyncWrite + tokio::io::AsyncRead + Send + Unpin + 'static,
 {
 	pub fn new(session: &'a mut Session<T>, destination: String) -> Self {
 		SubscriptionBuilder {
-			session: session,
-			destination: destination,
+			session,
+			destination,
 			ack_mode: AckMode::Auto,
+			prefetch_count: None,
 			headers: HeaderList::new(),
 			receipt_request: None,
 		}
@@ -37,6 +39,12 @@ where
 		);
 		let mut subscribe_frame =
 			Frame::subscribe(&subscription.id, &self.destination, self.ack_mode);
+		if let Some(prefetch_count) = self.prefetch_count {
+			subscribe_frame.headers.push(Header::new(
+				HeaderName::from_str("prefetch-count"),
+				&prefetch_count.to_string(),
+			));
+		}
 
 		subscribe_frame.headers.concat(&mut self.headers);
 
@@ -51,8 +59,7 @@ where
 			.state
 			.subscriptions
 			.insert(subscription.id.to_string(), subscription);
-		if self.receipt_request.is_some() {
-			let request = self.receipt_request.unwrap();
+		if let Some(request) = self.receipt_request {
 			self.session
 				.state
 				.outstanding_receipts
@@ -68,3 +75,29 @@ where
 		option_setter.set_option(self)
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::super::session::mock::{connected_session, poll};
+	use super::super::subscription::PrefetchCount;
+	use super::*;
+
+	#[test]
+	fn sends_the_prefetch_count() {
+		let (mut session, mut broker) = connected_session();
+		session
+			.subscription("/queue/a")
+			.with(AckMode::ClientIndividual)
+			.with(PrefetchCount(16))
+			.start();
+		session.subscription("/queue/b").start();
+		poll(&mut session);
+
+		let frames = broker.received();
+		assert_eq!(frames.len(), 2);
+		assert!(frames[0].starts_with("SUBSCRIBE\n"));
+		assert!(frames[0].contains("\nack:client-individual\n"));
+		assert!(frames[0].contains("\nprefetch-count:16\n"));
+		assert!(!frames[1].contains("prefetch-count"));
+	}
+}
@@ -2,7 +2,7 @@ use super::frame::Frame;
 use super::frame::ToFrameBody;
 use super::header::*;
 use super::message_builder::MessageBuilder;
-use super::session::Session;
+use super::session::{OutstandingReceipt, Session};
 
 pub struct Transaction<'tx, T: 'static> {
 	pub id: String,
@@ -11,7 +11,7 @@ pub struct Transaction<'tx, T: 'static> {
 
 impl<'tx, T: 'static> Transaction<'tx, T>
 where
-	T: tokio_io::AsyncWrite + tokio_io::AsyncRead + Send + 'static,
+	T: tokio::io::AsyncWrite + tokio::io::AsyncRead + Send + Unpin + 'static,
 {
 	pub fn new(session: &'tx mut Session<T>) -> Transaction<'tx, T> {
 		Transaction {
@@ -44,6 +44,21 @@ where
 		self.session.send_frame(commit_frame)
 	}
 
+	/// Commit, asking for a receipt, and return its id.
+	pub fn commit_with_receipt(self) -> String {
+		let receipt_id = format!("message/{}", self.session.generate_receipt_id());
+		let mut commit_frame = Frame::commit(self.id.as_ref());
+		commit_frame
+			.headers
+			.push(Header::new(RECEIPT, receipt_id.as_ref()));
+		self.session.state.outstanding_receipts.insert(
+			receipt_id.clone(),
+			OutstandingReceipt::new(commit_frame.clone()),
+		);
+		self.session.send_frame(commit_frame);
+		receipt_id
+	}
+
 	pub fn abort(self) {
 		let abort_frame = Frame::abort(self.id.as_ref());
 		self.session.send_frame(abort_frame)
@@ -15,27 +15,31 @@
 
 #[macro_use]
 extern crate log;
-#[macro_use]
-extern crate futures;
 
 mod broker;
 mod server;
-
-use crate::broker::Broker;
-use crate::server::AsyncServer;
+mod store;
+
+use crate::broker::{Broker, QueueGauges};
+use crate::server::{
+	AliasStore, Presence, PresenceEvent, RelayResolver, ServerContext, StubResolver,
+	SubscriptionPolicy, WellKnownResolver, PRESENCE_EXCHANGE, PRESENCE_QUEUE_SIZE,
+	SUBSCRIPTION_QUEUE_SIZE,
+};
+use crate::store::{LogStore, StateStore};
 use colored::*;
-use grinrelaylib::types::{set_running_mode, ChainTypes};
+use grinrelaylib::types::ChainTypes;
 use parking_lot::Mutex;
 use std::collections::hash_map::Entry;
 use std::collections::HashMap;
-use std::default::Default;
-use std::net::{TcpListener, ToSocketAddrs};
+use std::net::ToSocketAddrs;
 use std::sync::Arc;
 use std::thread;
+use tokio::net::TcpListener;
+use tokio::sync::mpsc::{channel, Receiver};
 
 use std::fs::File;
 use std::io::Read;
-use std::rc::Rc;
 
 use openssl::pkey::PKey;
 use openssl::ssl::{SslAcceptor, SslMethod};
@@ -59,36 +63,78 @@ fn read_file(name: &str) -> std::io::Result<Vec<u8>> {
 	Ok(buf)
 }
 
-fn initial_consumers(login: String, password: String) -> HashMap<String, Vec<String>> {
+/// A boolean environment setting, `None` if it is neither true nor false.
+fn parse_flag(value: &str) -> Option<bool> {
+	match value.trim().to_lowercase().as_str() {
+		"1" | "true" | "yes" | "on" => Some(true),
+		"0" | "false" | "no" | "off" => Some(false),
+		_ => None,
+	}
+}
+
+/// A prefetch count, `None` unless it is within the messages a connection
+/// buffers, past which the rest bounces back to the queue.
+fn parse_prefetch_count(value: &str) -> Option<u32> {
+	match value.trim().parse::<u32>() {
+		Ok(count) if count >= 1 && count as usize <= SUBSCRIPTION_QUEUE_SIZE => Some(count),
+		_ => None,
+	}
+}
+
+fn is_relay_queue(queue: &str, chain_types: &[ChainTypes]) -> bool {
+	chain_types
+		.iter()
+		.any(|chain_type| queue.starts_with(&format!("{}1", chain_type.hrp())))
+}
+
+/// The relay queues consumed on the broker, `None` if the management api can
+/// not be reached.
+fn broker_consumers(
+	login: String,
+	password: String,
+	chain_types: &[ChainTypes],
+) -> Option<HashMap<String, Vec<String>>> {
 	let mut map = HashMap::new();
 
-	let client = reqwest::Client::new();
-	let mut resp = client
+	let client = reqwest::blocking::Client::new();
+	let resp = match client
 		.get("http://localhost:15672/api/consumers")
 		.basic_auth(login, Some(password))
 		.send()
-		.unwrap();
-
-	if resp.status().is_success() {
-		let data: Value = serde_json::from_str(resp.text().unwrap().as_str()).unwrap();
-		let obj_array = data.as_array().unwrap();
-
-		for obj in obj_array {
-			let queue = obj.get("queue").unwrap().as_object().unwrap();
-			let name = queue.get("name").unwrap().as_str().unwrap();
-			let str_name: String = String::from(name);
-			let len = str_name.len();
-			let key = str_name.clone()[len - 6..].to_owned();
-
-			match map.entry(key) {
-				Entry::Vacant(e) => {
-					e.insert(vec![str_name]);
-				}
-				Entry::Occupied(mut e) => {
-					e.get_mut().push(str_name);
-					e.get_mut().sort_unstable();
-					e.get_mut().dedup();
-				}
+	{
+		Ok(resp) => resp,
+		Err(e) => {
+			warn!("could not query the rabbitmq management api: {}", e);
+			return None;
+		}
+	};
+
+	if !resp.status().is_success() {
+		warn!("rabbitmq management api answered {}", resp.status());
+		return None;
+	}
+
+	let data: Value = serde_json::from_str(resp.text().unwrap().as_str()).unwrap();
+	let obj_array = data.as_array().unwrap();
+
+	for obj in obj_array {
+		let queue = obj.get("queue").unwrap().as_object().unwrap();
+		let name = queue.get("name").unwrap().as_str().unwrap();
+		if !is_relay_queue(name, chain_types) {
+			continue;
+		}
+		let str_name: String = String::from(name);
+		let len = str_name.len();
+		let key = str_name.clone()[len - 6..].to_owned();
+
+		match map.entry(key) {
+			Entry::Vacant(e) => {
+				e.insert(vec![str_name]);
+			}
+			Entry::Occupied(mut e) => {
+				e.get_mut().push(str_name);
+				e.get_mut().sort_unstable();
+				e.get_mut().dedup();
 			}
 		}
 	}
@@ -102,19 +148,15 @@ fn initial_consumers(login: String, password: String) -> HashMap<String, Vec<Str
 		}
 	}
 
-	map
+	Some(map)
 }
 
 fn rabbit_consumer_monitor(
-	consumers: Arc<Mutex<HashMap<String, Vec<String>>>>,
+	presence: Arc<Presence>,
 	login: String,
 	password: String,
+	chain_types: Arc<Vec<ChainTypes>>,
 ) {
-	let map = initial_consumers(login.clone(), password.clone());
-	for (key, value) in map.to_owned() {
-		consumers.lock().insert(key, value);
-	}
-
 	info!("rabbit_consumer_monitor start");
 	let options = Options {
 		host: "127.0.0.1".to_string(),
@@ -167,6 +209,22 @@ fn rabbit_consumer_monitor(
 		info!("queue bind successfully");
 	}
 
+	// relays of a cluster share their subscribers through this exchange
+	let exchange_declare = channel.exchange_declare(
+		PRESENCE_EXCHANGE.to_owned(),
+		"fanout".to_owned(),
+		false,
+		true,
+		false,
+		false,
+		false,
+		Table::new(),
+	);
+	if exchange_declare.is_err() {
+		error!("grin relay presence exchange failed to declare!");
+		std::process::exit(1);
+	}
+
 	let closure_consumer = move |_chan: &mut Channel,
 	                             deliver: basic::Deliver,
 	                             headers: basic::BasicProperties,
@@ -178,21 +236,9 @@ fn rabbit_consumer_monitor(
 				_ => String::new(),
 			};
 
-			if queue.starts_with("gn1") || queue.starts_with("tn1") {
+			if is_relay_queue(&queue, &chain_types) {
 				info!("consumer.created ---- {}", queue);
-
-				let tail = queue.len().saturating_sub(6);
-				let key = queue[tail..].to_string();
-				match consumers.lock().entry(key) {
-					Entry::Vacant(e) => {
-						e.insert(vec![queue]);
-					}
-					Entry::Occupied(mut e) => {
-						e.get_mut().push(queue);
-						e.get_mut().sort_unstable();
-						e.get_mut().dedup();
-					}
-				}
+				presence.consumer_created(&queue);
 			}
 		}
 
@@ -204,14 +250,9 @@ fn rabbit_consumer_monitor(
 				_ => String::new(),
 			};
 
-			if queue.starts_with("gn1") || queue.starts_with("tn1") {
+			if is_relay_queue(&queue, &chain_types) {
 				info!("consumer.deleted ---- {}", queue);
-
-				let tail = queue.len().saturating_sub(6);
-				let key = &queue[tail..];
-				if consumers.lock().contains_key(key) {
-					consumers.lock().remove(key);
-				}
+				presence.consumer_deleted(&queue);
 			}
 		}
 	};
@@ -266,6 +307,18 @@ fn log_build_info() {
 	debug!("{}", detailed_info);
 }
 
+fn presence_listener(presence: Arc<Presence>, mut events: Receiver<String>) {
+	tokio::spawn(async move {
+		while let Some(payload) = events.recv().await {
+			match serde_json::from_str::<PresenceEvent>(&payload) {
+				Ok(event) => presence.apply(event),
+				Err(e) => warn!("invalid presence event: {}", e),
+			}
+		}
+		info!("presence listener exit");
+	});
+}
+
 fn main() {
 	env_logger::init();
 
@@ -298,7 +351,7 @@ fn main() {
 			PKey::private_key_from_pem(data.as_ref()).unwrap()
 		};
 
-		Some(Rc::new({
+		Some(Arc::new({
 			let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
 			builder.set_private_key(&pkey).unwrap();
 			builder.set_certificate(&cert).unwrap();
@@ -314,27 +367,52 @@ fn main() {
 
 	let grinrelay_domain = std::env::var("GRINRELAY_DOMAIN").unwrap_or("127.0.0.1".to_string());
 	let grinrelay_port = std::env::var("GRINRELAY_PORT").unwrap_or("13420".to_string());
-	let grinrelay_port =
-		u16::from_str_radix(&grinrelay_port, 10).expect("invalid GRINRELAY_PORT given!");
+	let grinrelay_port = grinrelay_port
+		.parse::<u16>()
+		.expect("invalid GRINRELAY_PORT given!");
 
 	let is_mainnet = std::env::var("GRINRELAY_IS_MAINNET")
 		.map(|_| true)
 		.unwrap_or(false);
-	if is_mainnet {
-		set_running_mode(ChainTypes::Mainnet);
+	// Queue names are the bech32 addresses themselves, so the "gn" and "tn" prefixes
+	// already keep each chain in its own queue namespace when serving both.
+	let serve_all_chains = std::env::var("GRINRELAY_SERVE_ALL_CHAINS")
+		.map(|value| parse_flag(&value).expect("invalid GRINRELAY_SERVE_ALL_CHAINS given!"))
+		.unwrap_or(false);
+	let chain_types = Arc::new(if serve_all_chains {
+		vec![ChainTypes::Mainnet, ChainTypes::Floonet]
+	} else if is_mainnet {
+		vec![ChainTypes::Mainnet]
 	} else {
-		set_running_mode(ChainTypes::Floonet);
-	}
+		vec![ChainTypes::Floonet]
+	});
+	info!("Serving chain types: {:?}", chain_types);
 
 	if broker_uri.is_none() {
 		error!("could not resolve broker uri!");
 		panic!();
 	}
 
+	let prefetch_count = std::env::var("GRINRELAY_PREFETCH_COUNT")
+		.map(|value| {
+			parse_prefetch_count(&value).unwrap_or_else(|| {
+				panic!(
+					"invalid GRINRELAY_PREFETCH_COUNT given, expected 1 to {}!",
+					SUBSCRIPTION_QUEUE_SIZE
+				)
+			})
+		})
+		.unwrap_or(16);
+	info!("Prefetch count: {}", prefetch_count);
+
+	let state_store_path =
+		std::env::var("GRINRELAY_STATE_STORE").unwrap_or("grinrelay-state.log".to_string());
+	info!("State store: {}", state_store_path);
+	let state: Arc<dyn StateStore> =
+		Arc::new(LogStore::open(&state_store_path).expect("failed opening state store"));
+
 	let consumers = Arc::new(Mutex::new(HashMap::new()));
-	let rabbit_consumers = consumers.clone();
 	let async_consumers = consumers.clone();
-	rabbit_consumer_monitor(rabbit_consumers, username.clone(), password.clone());
 
 	let broker_uri = broker_uri.unwrap();
 	let bind_address =
@@ -343,40 +421,100 @@ fn main() {
 	info!("Broker URI: {}", broker_uri);
 	info!("Bind address: {}", bind_address);
 
-	let mut broker = Broker::new(broker_uri, username, password, consumers);
-	let sender = broker.start().expect("failed initiating broker session");
-	let response_handlers_sender = AsyncServer::init();
+	let aliases = Arc::new(AliasStore::new(state.clone()));
 
 	thread::spawn(|| {
 		// for server selection service only
-		let listener = TcpListener::bind("0.0.0.0:3419").unwrap();
+		let listener = std::net::TcpListener::bind("0.0.0.0:3419").unwrap();
 
 		// accept connections and process them serially
-		for stream in listener.incoming() {
-			if let Ok(stream) = stream {
-				trace!("server selection from {}", stream.peer_addr().unwrap());
-			}
+		for stream in listener.incoming().flatten() {
+			trace!("server selection from {}", stream.peer_addr().unwrap());
 		}
 	});
 
-	ws::Builder::new()
-		.with_settings(ws::Settings {
-			encrypt_server: !grinrelay_protocol_unsecure,
-			..ws::Settings::default()
-		})
-		.build(|out: ws::Sender| {
-			AsyncServer::new(
-				out,
-				sender.clone(),
-				response_handlers_sender.clone(),
-				&grinrelay_domain,
-				grinrelay_port,
-				grinrelay_protocol_unsecure,
-				acceptor.clone(),
-				async_consumers.clone(),
-			)
-		})
-		.unwrap()
-		.listen(&bind_address[..])
-		.unwrap();
+	let runtime = tokio::runtime::Builder::new_multi_thread()
+		.enable_all()
+		.thread_name("grinrelay")
+		.build()
+		.expect("failed building the runtime");
+
+	runtime.block_on(async move {
+		let (presence_tx, presence_rx) = channel::<String>(PRESENCE_QUEUE_SIZE);
+		let gauges = Arc::new(QueueGauges::new());
+		let mut broker = Broker::new(
+			broker_uri,
+			username.clone(),
+			password.clone(),
+			consumers.clone(),
+			presence_tx,
+			gauges.clone(),
+			prefetch_count,
+		);
+		let sender = broker.start().expect("failed initiating broker session");
+
+		let relay_id = format!(
+			"{}-{}",
+			gethostname::gethostname().into_string().unwrap(),
+			Uuid::new_v4()
+		);
+		info!("Relay id: {}", relay_id);
+		let subscription_policy: SubscriptionPolicy =
+			std::env::var("GRINRELAY_SUBSCRIPTION_POLICY")
+				.unwrap_or("takeover".to_string())
+				.parse()
+				.expect("invalid GRINRELAY_SUBSCRIPTION_POLICY");
+		info!("Subscription policy: {:?}", subscription_policy);
+
+		let presence = Arc::new(Presence::new(
+			relay_id,
+			sender.clone(),
+			consumers,
+			state,
+			subscription_policy,
+		));
+		presence.recover_consumers();
+		Presence::start(presence.clone());
+		AliasStore::start(aliases.clone());
+		presence_listener(presence.clone(), presence_rx);
+		let monitor_presence = presence.clone();
+		let monitor_chain_types = chain_types.clone();
+		tokio::task::spawn_blocking(move || {
+			let consumers =
+				broker_consumers(username.clone(), password.clone(), &monitor_chain_types);
+			if let Some(consumers) = consumers {
+				monitor_presence.reconcile_consumers(consumers);
+			}
+			rabbit_consumer_monitor(monitor_presence, username, password, monitor_chain_types)
+		});
+
+		let resolver: Arc<dyn RelayResolver> = match std::env::var("GRINRELAY_RESOLVER_STUB") {
+			Ok(path) => {
+				info!("Relay discovery stub: {}", path);
+				let stub = StubResolver::from_file(&path, Some(Box::new(WellKnownResolver::new())))
+					.expect("failed loading GRINRELAY_RESOLVER_STUB file");
+				Arc::new(stub)
+			}
+			Err(_) => Arc::new(WellKnownResolver::new()),
+		};
+
+		let context = ServerContext {
+			nats_sender: sender,
+			grinrelay_domain,
+			grinrelay_port,
+			grinrelay_protocol_unsecure,
+			consumers: async_consumers,
+			chain_types,
+			resolver,
+			aliases,
+			presence,
+			subscription_policy,
+			gauges,
+		};
+
+		let listener = TcpListener::bind(&bind_address[..])
+			.await
+			.expect("failed binding the websocket listener");
+		server::serve(listener, acceptor, context).await;
+	});
 }
@@ -0,0 +1,178 @@
+// Copyright 2019 The Gotts Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Aliases registered on this relay, kept in the state store so they outlive
+//! both the owner's connection and relay restarts.
+
+use parking_lot::Mutex;
+use std::sync::Arc;
+use std::time::Duration;
+
+use grinrelaylib::types::{unix_now, AliasRecord, GrinboxError, ALIAS_MAX_PER_ADDRESS};
+
+use crate::store::{StateStore, Table};
+
+const ALIASES_TABLE: &str = "aliases";
+/// How often expired registrations are dropped from the state store
+const PRUNE_INTERVAL: Duration = Duration::from_secs(600);
+
+pub struct AliasStore {
+	aliases: Table<AliasRecord>,
+	// serializes the check and the update of a registration
+	lock: Mutex<()>,
+}
+
+impl AliasStore {
+	pub fn new(store: Arc<dyn StateStore>) -> AliasStore {
+		AliasStore {
+			aliases: Table::new(store, ALIASES_TABLE),
+			lock: Mutex::new(()),
+		}
+	}
+
+	/// Drop expired registrations, right away and then every `PRUNE_INTERVAL`,
+	/// on a task of its own.
+	pub fn start(aliases: Arc<AliasStore>) {
+		tokio::spawn(async move {
+			let mut prune = tokio::time::interval(PRUNE_INTERVAL);
+			loop {
+				prune.tick().await;
+				aliases.prune(unix_now());
+			}
+		});
+	}
+
+	/// Drop the registrations expired at `now`.
+	pub fn prune(&self, now: u64) {
+		let _guard = self.lock.lock();
+		for (alias, record) in self.aliases.entries() {
+			if record.is_expired(now) {
+				if let Err(e) = self.aliases.remove(&alias) {
+					error!("could not remove expired alias {}: {}", alias, e);
+				}
+			}
+		}
+	}
+
+	/// Register or renew an alias. An alias held by another address can only
+	/// be taken over once it expired, and a renewal must be signed after the
+	/// registration it replaces.
+	pub fn register(&self, record: AliasRecord, now: u64) -> Result<(), GrinboxError> {
+		let _guard = self.lock.lock();
+		if let Some(current) = self.aliases.get(&record.alias) {
+			if current.address != record.address && !current.is_expired(now) {
+				return Err(GrinboxError::AliasTaken);
+			}
+			if current.address == record.address && record.signed_at <= current.signed_at {
+				return Err(GrinboxError::InvalidChallenge);
+			}
+		}
+
+		let held = self
+			.aliases
+			.entries()
+			.into_iter()
+			.filter(|(alias, held)| {
+				*alias != record.alias && held.address == record.address && !held.is_expired(now)
+			})
+			.count();
+		if held >= ALIAS_MAX_PER_ADDRESS {
+			return Err(GrinboxError::TooManyAliases);
+		}
+
+		self.aliases.put(&record.alias, &record).map_err(|e| {
+			error!("could not save alias {}: {}", record.alias, e);
+			GrinboxError::UnknownError
+		})
+	}
+
+	pub fn lookup(&self, alias: &str, now: u64) -> Option<AliasRecord> {
+		self.aliases.get(alias).filter(|r| !r.is_expired(now))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::store::MemoryStore;
+
+	fn in_memory() -> AliasStore {
+		AliasStore::new(Arc::new(MemoryStore::new()))
+	}
+
+	fn record(alias: &str, address: &str, expires_at: u64) -> AliasRecord {
+		AliasRecord {
+			alias: alias.to_string(),
+			address: address.to_string(),
+			expires_at,
+			signed_at: expires_at,
+		}
+	}
+
+	#[test]
+	fn aliases_are_unique_until_expired() {
+		let store = in_memory();
+		store.register(record("shop", "gn1owner", 100), 0).unwrap();
+		// the owner renews
+		store.register(record("shop", "gn1owner", 200), 50).unwrap();
+		assert_eq!(
+			store.register(record("shop", "gn1other", 300), 150),
+			Err(GrinboxError::AliasTaken)
+		);
+		assert_eq!(store.lookup("shop", 150).unwrap().address, "gn1owner");
+
+		assert_eq!(store.lookup("shop", 200), None);
+		store
+			.register(record("shop", "gn1other", 300), 200)
+			.unwrap();
+		assert_eq!(store.lookup("shop", 250).unwrap().address, "gn1other");
+
+		store.prune(300);
+		assert_eq!(store.lookup("shop", 0), None);
+	}
+
+	#[test]
+	fn registrations_can_not_be_replayed() {
+		let store = in_memory();
+		store.register(record("shop", "gn1owner", 100), 0).unwrap();
+		assert_eq!(
+			store.register(record("shop", "gn1owner", 100), 10),
+			Err(GrinboxError::InvalidChallenge)
+		);
+		store.register(record("shop", "gn1owner", 101), 10).unwrap();
+	}
+
+	#[test]
+	fn aliases_per_address_are_capped() {
+		let store = in_memory();
+		for i in 0..ALIAS_MAX_PER_ADDRESS {
+			store
+				.register(record(&format!("shop{}", i), "gn1owner", 100), 0)
+				.unwrap();
+		}
+		assert_eq!(
+			store.register(record("one-more", "gn1owner", 100), 0),
+			Err(GrinboxError::TooManyAliases)
+		);
+		// renewals and other addresses are not affected
+		store.register(record("shop0", "gn1owner", 200), 0).unwrap();
+		store
+			.register(record("one-more", "gn1other", 100), 0)
+			.unwrap();
+		// expired aliases do not count
+		store
+			.register(record("later", "gn1owner", 300), 100)
+			.unwrap();
+	}
+}
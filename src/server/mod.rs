@@ -13,58 +13,237 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+mod alias_store;
+mod presence;
+mod resolver;
+
+pub use self::alias_store::AliasStore;
+pub use self::presence::{Presence, PresenceEvent, PRESENCE_EXCHANGE, PRESENCE_QUEUE_SIZE};
+pub use self::resolver::{RelayEndpoint, RelayResolver, StubResolver, WellKnownResolver};
+
+use self::resolver::public_addrs;
+
 use colored::*;
-use futures::{
-	future::lazy,
-	sync::mpsc::{unbounded, UnboundedReceiver, UnboundedSender},
-	Future, Stream,
-};
+use futures::future::join_all;
+use futures::{SinkExt, StreamExt};
 use parking_lot::Mutex;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::pin::Pin;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::{channel, error::TrySendError, Sender};
+use tokio::sync::oneshot;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
 use uuid::Uuid;
 
-use ws::{
-	connect, CloseCode, Handler, Handshake, Message, Request, Response, Result as WsResult, Sender,
-};
-
-use openssl::ssl::{SslAcceptor, SslStream};
-use std::rc::Rc;
-use ws::util::TcpStream;
+use openssl::ssl::{Ssl, SslAcceptor};
+use tokio_openssl::SslStream;
 
-use grinrelaylib::error::{ErrorKind, Result};
-use grinrelaylib::types::{GrinboxAddress, GrinboxError, GrinboxRequest, GrinboxResponse};
-use grinrelaylib::utils::crypto::{verify_signature, AddrBech32, Hex};
-use grinrelaylib::utils::secp::{PublicKey, Signature};
+use grinrelaylib::error::{Error, ErrorKind, Result};
+use grinrelaylib::types::{
+	alias_challenge, is_fresh_signature, is_valid_alias, unix_now, AliasRecord, BatchSlate,
+	ChainTypes, GrinboxAddress, GrinboxError, GrinboxRequest, GrinboxResponse, SigningMode,
+	ALIAS_DEFAULT_TTL_IN_SECONDS, ALIAS_MAX_TTL_IN_SECONDS,
+};
+use grinrelaylib::utils::crypto::{SignatureScheme, SignatureVerifier};
 
-use crate::broker::{BrokerRequest, BrokerResponse};
+use crate::broker::{BatchMessage, BrokerRequest, BrokerResponse, QueueGauges};
 
 static MAX_SUBSCRIPTIONS: usize = 1;
+/// Recipients of a single `PostSlateBatch`
+const MAX_BATCH_SIZE: usize = 32;
+/// How long a challenge may be signed over after it was issued
+const CHALLENGE_TTL: Duration = Duration::from_secs(600);
+/// How long a post waits for the broker to confirm its message
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long the relays of every recipient of a batch may take to resolve
+const BATCH_RESOLVE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a slate forwarded to another relay may take, connection included.
+/// Longer than `DELIVERY_TIMEOUT` the other relay waits for its own broker.
+const FORWARD_TIMEOUT: Duration = Duration::from_secs(20);
+/// How long each step of accepting a connection may take: the tls handshake,
+/// the first bytes of its request and the websocket handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Largest request head looked at to tell plain http from websocket upgrades
+const MAX_REQUEST_HEAD: usize = 8192;
+const PLAIN_HTTP_RESPONSE: &[u8] =
+	b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+/// Broker messages buffered per connection before the broker session holds
+/// them back
+pub const SUBSCRIPTION_QUEUE_SIZE: usize = 64;
+const SUBSCRIPTION_REVOKED_REASON: &str = "subscription taken over by another connection";
 const GRINRELAY_ABBR_ADDRESS_REGEX: &str = r"^(?P<abbr_addr>[02-9ac-hj-np-z]{6})$";
 
-pub struct BrokerResponseHandler {
-	inner: std::sync::Arc<std::sync::Mutex<Server>>,
-	response_receiver: UnboundedReceiver<BrokerResponse>,
+/// What to do when an address subscribes while already subscribed from
+/// another connection, on this relay or on another relay of the cluster.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SubscriptionPolicy {
+	/// Refuse the new subscription with `AlreadySubscribed`.
+	Reject,
+	/// Hand the subscription over to the new connection. The old one gets a
+	/// `SubscriptionRevoked` message and is closed.
+	TakeOver,
+}
+
+impl std::str::FromStr for SubscriptionPolicy {
+	type Err = String;
+
+	fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+		match s.to_lowercase().as_str() {
+			"reject" => Ok(SubscriptionPolicy::Reject),
+			"takeover" | "take_over" => Ok(SubscriptionPolicy::TakeOver),
+			_ => Err(format!("unknown subscription policy: {}", s)),
+		}
+	}
+}
+
+impl SubscriptionPolicy {
+	/// Whether an address may subscribe, given whether it is already
+	/// subscribed from another connection.
+	fn admit(self, already_subscribed: bool) -> std::result::Result<(), GrinboxError> {
+		match self {
+			SubscriptionPolicy::Reject if already_subscribed => {
+				Err(GrinboxError::AlreadySubscribed)
+			}
+			_ => Ok(()),
+		}
+	}
+}
+
+/// What every connection of the relay shares.
+#[derive(Clone)]
+pub struct ServerContext {
+	pub nats_sender: Sender<BrokerRequest>,
+	pub grinrelay_domain: String,
+	pub grinrelay_port: u16,
+	pub grinrelay_protocol_unsecure: bool,
+	pub consumers: Arc<Mutex<HashMap<String, Vec<String>>>>,
+	pub chain_types: Arc<Vec<ChainTypes>>,
+	pub resolver: Arc<dyn RelayResolver>,
+	pub aliases: Arc<AliasStore>,
+	pub presence: Arc<Presence>,
+	pub subscription_policy: SubscriptionPolicy,
+	pub gauges: Arc<QueueGauges>,
+}
+
+/// Accept websocket connections, each served by its own task.
+pub async fn serve(listener: TcpListener, ssl: Option<Arc<SslAcceptor>>, context: ServerContext) {
+	loop {
+		let (stream, peer) = match listener.accept().await {
+			Ok(connection) => connection,
+			Err(e) => {
+				error!("could not accept connection: {}", e);
+				continue;
+			}
+		};
+		let ssl = ssl.clone();
+		let context = context.clone();
+		tokio::spawn(async move {
+			match ssl {
+				Some(acceptor) => {
+					let handshake = accept_tls(&acceptor, stream);
+					match tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake).await {
+						Ok(Ok(stream)) => {
+							if let Some(stream) = answer_plain_http(stream).await {
+								AsyncServer::run(stream, &context).await
+							}
+						}
+						Ok(Err(e)) => debug!("tls handshake with {} failed: {}", peer, e),
+						Err(_) => debug!("tls handshake with {} timed out", peer),
+					}
+				}
+				None => {
+					if let Some(stream) = answer_plain_http(stream).await {
+						AsyncServer::run(stream, &context).await
+					}
+				}
+			}
+		});
+	}
+}
+
+/// Answer plain http requests, e.g. load balancer health checks, with a 200.
+/// Anything else is handed back for the websocket handshake, with its first
+/// bytes still buffered.
+async fn answer_plain_http<S>(stream: S) -> Option<BufReader<S>>
+where
+	S: AsyncRead + AsyncWrite + Unpin,
+{
+	let mut stream = BufReader::with_capacity(MAX_REQUEST_HEAD, stream);
+	let plain = match tokio::time::timeout(HANDSHAKE_TIMEOUT, stream.fill_buf()).await {
+		Ok(Ok(head)) => is_websocket_upgrade(head) == Some(false),
+		Ok(Err(_)) | Err(_) => return None,
+	};
+	if !plain {
+		return Some(stream);
+	}
+
+	let stream = stream.get_mut();
+	if stream.write_all(PLAIN_HTTP_RESPONSE).await.is_ok() {
+		let _ = stream.shutdown().await;
+	}
+	None
+}
+
+/// Whether the http request starting with `head` asks for a websocket, `None`
+/// if its headers are not all there.
+fn is_websocket_upgrade(head: &[u8]) -> Option<bool> {
+	let end = head.windows(4).position(|w| w == b"\r\n\r\n")?;
+	let head = String::from_utf8_lossy(&head[..end]);
+	let upgrade = head.split("\r\n").skip(1).any(|line| match line.find(':') {
+		Some(colon) => {
+			line[..colon].trim().eq_ignore_ascii_case("upgrade")
+				&& line[colon + 1..].to_lowercase().contains("websocket")
+		}
+		None => false,
+	});
+	Some(upgrade)
+}
+
+async fn accept_tls(
+	acceptor: &SslAcceptor,
+	stream: TcpStream,
+) -> std::io::Result<SslStream<TcpStream>> {
+	let to_io = |e| std::io::Error::new(std::io::ErrorKind::ConnectionAborted, e);
+	let ssl = Ssl::new(acceptor.context()).map_err(to_io)?;
+	let mut stream = SslStream::new(ssl, stream).map_err(to_io)?;
+	Pin::new(&mut stream)
+		.accept()
+		.await
+		.map_err(|e| std::io::Error::new(std::io::ErrorKind::ConnectionAborted, e))?;
+	Ok(stream)
 }
 
 pub struct AsyncServer {
 	id: String,
-	inner: std::sync::Arc<std::sync::Mutex<Server>>,
-	nats_sender: UnboundedSender<BrokerRequest>,
-	response_handlers_sender: UnboundedSender<BrokerResponseHandler>,
+	/// Websocket messages waiting to be written to the client
+	out: Vec<Message>,
+	/// Broker messages to acknowledge once `out` reached the client
+	acks: Vec<String>,
+	/// Where the broker delivers messages of this connection's subscriptions
+	response_sender: Sender<BrokerResponse>,
+	nats_sender: Sender<BrokerRequest>,
 	subscriptions: HashMap<String, Subscription>,
+	/// Challenge issued to this connection, signed over by its client
+	challenge: String,
+	challenge_expires_at: Instant,
 	consumers: Arc<Mutex<HashMap<String, Vec<String>>>>,
 	grinrelay_domain: String,
 	grinrelay_port: u16,
 	grinrelay_protocol_unsecure: bool,
-	ssl: Option<Rc<SslAcceptor>>,
-}
-
-pub struct Server {
-	id: String,
-	out: Sender,
+	chain_types: Arc<Vec<ChainTypes>>,
+	resolver: Arc<dyn RelayResolver>,
+	aliases: Arc<AliasStore>,
+	presence: Arc<Presence>,
+	subscription_policy: SubscriptionPolicy,
+	gauges: Arc<QueueGauges>,
 }
 
 struct Subscription {}
@@ -74,197 +253,346 @@ struct SignedPayload {
 	str: String,
 	challenge: String,
 	signature: String,
+	#[serde(default)]
+	signature_scheme: SignatureScheme,
+	#[serde(default)]
+	signing_mode: Option<SigningMode>,
+}
+
+/// A slate as posted by its sender, with the sender's signature over it.
+struct SignedSlate {
+	str: String,
+	signature: String,
+	signature_scheme: SignatureScheme,
+	/// What the signature covers, as claimed by the sender.
+	signing_mode: Option<SigningMode>,
+	/// Challenge the slate was signed over, when forwarded by another relay
+	/// rather than signed over this connection's challenge.
+	challenge: Option<String>,
+}
+
+impl SignedPayload {
+	fn signing_mode(&self) -> SigningMode {
+		self.signing_mode
+			.unwrap_or_else(|| SigningMode::from_challenge(&self.challenge))
+	}
 }
 
 impl Drop for AsyncServer {
 	fn drop(&mut self) {
-		for (_subject, _subscription) in &self.subscriptions {
-			if self
-				.nats_sender
-				.unbounded_send(BrokerRequest::Unsubscribe {
-					id: self.id.clone(),
-				})
-				.is_err()
-			{
-				error!("failed to unsubscribe while dropping server!");
+		for address in self.subscriptions.keys() {
+			self.presence.local_offline(address);
+			let request = BrokerRequest::Unsubscribe {
+				id: self.id.clone(),
 			};
+			match self.nats_sender.try_send(request) {
+				Ok(()) => {}
+				// must not be lost, wait for the broker to catch up
+				Err(TrySendError::Full(request)) => {
+					let sender = self.nats_sender.clone();
+					tokio::spawn(async move { sender.send(request).await });
+				}
+				Err(TrySendError::Closed(_)) => {
+					error!("failed to unsubscribe while dropping server!");
+				}
+			}
 		}
 	}
 }
 
 impl AsyncServer {
-	pub fn new(
-		out: Sender,
-		nats_sender: UnboundedSender<BrokerRequest>,
-		response_handlers_sender: UnboundedSender<BrokerResponseHandler>,
-		grinrelay_domain: &str,
-		grinrelay_port: u16,
-		grinrelay_protocol_unsecure: bool,
-		ssl: Option<Rc<SslAcceptor>>,
-		consumers: Arc<Mutex<HashMap<String, Vec<String>>>>,
-	) -> AsyncServer {
-		let id = Uuid::new_v4().to_string();
-
-		let server = Server {
-			id: id.clone(),
-			out,
-		};
-
+	pub fn new(response_sender: Sender<BrokerResponse>, context: &ServerContext) -> AsyncServer {
 		AsyncServer {
-			id: id.clone(),
-			inner: std::sync::Arc::new(std::sync::Mutex::new(server)),
-			nats_sender,
-			response_handlers_sender,
+			id: Uuid::new_v4().to_string(),
+			out: vec![],
+			acks: vec![],
+			response_sender,
+			nats_sender: context.nats_sender.clone(),
 			subscriptions: HashMap::new(),
-			consumers: consumers,
-			grinrelay_domain: grinrelay_domain.to_string(),
-			grinrelay_port,
-			grinrelay_protocol_unsecure,
-			ssl,
-		}
-	}
-
-	pub fn init() -> UnboundedSender<BrokerResponseHandler> {
-		let (fut_tx, fut_rx) = unbounded::<BrokerResponseHandler>();
-
-		std::thread::spawn(move || {
-			info!("broker handler started");
-			let fut_loop = fut_rx
-				.for_each(move |handler| {
-					let clone = handler.inner.clone();
-					let response_loop = handler.response_receiver.for_each(move |m| {
-						match m {
-							BrokerResponse::Message {
-								subject: _,
-								payload,
-								reply_to,
-							} => {
-								let signed_payload =
-									serde_json::from_str::<SignedPayload>(&payload);
-								if signed_payload.is_ok() {
-									let signed_payload = signed_payload.unwrap();
-									let response = GrinboxResponse::Slate {
-										from: reply_to,
-										str: signed_payload.str,
-										challenge: signed_payload.challenge,
-										signature: signed_payload.signature,
-									};
-									let guard = clone.lock().unwrap();
-									let ref server = *guard;
-									info!("[{}] <- {}", server.id.bright_green(), response);
-									if server
-										.out
-										.send(serde_json::to_string(&response).unwrap())
-										.is_err()
-									{
-										error!("failed sending slate to client!");
-									};
-								} else {
-									error!("invalid payload!");
-								}
-							}
-						}
-						Ok(())
-					});
-
-					std::thread::spawn(move || {
-						tokio::run(lazy(|| {
-							tokio::spawn(response_loop);
-							Ok(())
-						}));
-					});
-					Ok(())
-				})
-				.map_err(|_| {});
-
-			tokio::run(lazy(move || tokio::spawn(fut_loop)));
-			debug!("future thread ended...");
-		});
-		fut_tx
+			challenge: AsyncServer::new_challenge(),
+			challenge_expires_at: Instant::now() + CHALLENGE_TTL,
+			consumers: context.consumers.clone(),
+			grinrelay_domain: context.grinrelay_domain.clone(),
+			grinrelay_port: context.grinrelay_port,
+			grinrelay_protocol_unsecure: context.grinrelay_protocol_unsecure,
+			chain_types: context.chain_types.clone(),
+			resolver: context.resolver.clone(),
+			aliases: context.aliases.clone(),
+			presence: context.presence.clone(),
+			subscription_policy: context.subscription_policy,
+			gauges: context.gauges.clone(),
+		}
+	}
+
+	/// Serve one websocket connection until either side closes it.
+	async fn run<S>(stream: S, context: &ServerContext)
+	where
+		S: AsyncRead + AsyncWrite + Unpin,
+	{
+		let handshake = tokio_tungstenite::accept_async(stream);
+		let ws = match tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake).await {
+			Ok(Ok(ws)) => ws,
+			Ok(Err(e)) => {
+				debug!("websocket handshake failed: {}", e);
+				return;
+			}
+			Err(_) => {
+				debug!("websocket handshake timed out");
+				return;
+			}
+		};
+		let (mut sink, mut source) = ws.split();
+		let (response_sender, mut responses) = channel(SUBSCRIPTION_QUEUE_SIZE);
+		let mut server = AsyncServer::new(response_sender, context);
+
+		server.on_open();
+		loop {
+			tokio::select! {
+				incoming = source.next() => match incoming {
+					Some(Ok(Message::Text(text))) => server.on_message(&text).await,
+					Some(Ok(Message::Close(frame))) => {
+						server.on_close(frame);
+						break;
+					}
+					// pings are answered by the websocket itself
+					Some(Ok(_)) => {}
+					Some(Err(e)) => {
+						server.on_error(e);
+						break;
+					}
+					None => break,
+				},
+				Some(response) = responses.recv() => server.route(response),
+			}
+
+			let mut failed = false;
+			for message in server.out.drain(..) {
+				if let Err(e) = sink.send(message).await {
+					error!("could not write to client: {}", e);
+					failed = true;
+					break;
+				}
+			}
+			if failed {
+				break;
+			}
+			server.acknowledge().await;
+		}
+		// flushes the reply to a close
+		let _ = sink.close().await;
+	}
+
+	fn send(&mut self, response: &GrinboxResponse) {
+		self.out
+			.push(Message::Text(serde_json::to_string(response).unwrap()));
+	}
+
+	/// Let the broker send the next messages, now that these reached the client.
+	async fn acknowledge(&mut self) {
+		for ack_id in self.acks.drain(..) {
+			let request = BrokerRequest::Ack {
+				id: self.id.clone(),
+				ack_id,
+			};
+			if self.nats_sender.send(request).await.is_err() {
+				error!("could not acknowledge message!");
+			}
+		}
+	}
+
+	/// Forward a broker response to the websocket client.
+	fn route(&mut self, m: BrokerResponse) {
+		match m {
+			BrokerResponse::Message {
+				subject: _,
+				payload,
+				reply_to,
+				ack_id,
+			} => {
+				// an invalid payload is acknowledged too, it would come back otherwise
+				self.acks.push(ack_id);
+				let signed_payload = serde_json::from_str::<SignedPayload>(&payload);
+				if let Ok(signed_payload) = signed_payload {
+					let signing_mode = signed_payload.signing_mode();
+					let response = GrinboxResponse::Slate {
+						from: reply_to,
+						str: signed_payload.str,
+						challenge: signed_payload.challenge,
+						signature: signed_payload.signature,
+						signature_scheme: signed_payload.signature_scheme,
+						signing_mode: Some(signing_mode),
+					};
+					info!("[{}] <- {}", self.id.bright_green(), response);
+					self.send(&response);
+				} else {
+					error!("invalid payload!");
+				}
+			}
+			BrokerResponse::Revoked { subject } => {
+				let response = GrinboxResponse::SubscriptionRevoked {
+					address: subject.trim_start_matches("/queue/").to_string(),
+				};
+				info!("[{}] <- {}", self.id.bright_green(), response);
+				self.send(&response);
+				self.out.push(Message::Close(Some(CloseFrame {
+					code: CloseCode::Normal,
+					reason: SUBSCRIPTION_REVOKED_REASON.into(),
+				})));
+			}
+		}
 	}
 
 	fn error(kind: GrinboxError) -> GrinboxResponse {
 		let description = format!("{}", kind);
-		GrinboxResponse::Error { kind, description }
+		let code = kind.code();
+		let retry_after = kind.retry_after();
+		GrinboxResponse::Error {
+			kind,
+			description,
+			code,
+			retry_after,
+		}
 	}
 
 	fn ok() -> GrinboxResponse {
 		GrinboxResponse::Ok
 	}
 
+	fn new_challenge() -> String {
+		Uuid::new_v4().to_simple().to_string()
+	}
+
 	fn get_challenge_raw(&self) -> &str {
-		"7WUDtkSaKyGRUnQ22rE3QUXChV8DmA6NnunDYP4vheTpc"
+		&self.challenge
+	}
+
+	/// The challenge of this connection, as long as it may still be signed.
+	fn fresh_challenge(&self) -> std::result::Result<&str, GrinboxError> {
+		if Instant::now() >= self.challenge_expires_at {
+			return Err(GrinboxError::ChallengeExpired);
+		}
+		Ok(self.get_challenge_raw())
 	}
 
-	fn get_challenge(&self) -> GrinboxResponse {
+	/// Issue a new challenge to this connection, replacing the previous one.
+	fn get_challenge(&mut self) -> GrinboxResponse {
+		self.challenge = AsyncServer::new_challenge();
+		self.challenge_expires_at = Instant::now() + CHALLENGE_TTL;
 		GrinboxResponse::Challenge {
 			str: String::from(self.get_challenge_raw()),
+			signature_schemes: vec![SignatureScheme::Ecdsa, SignatureScheme::Schnorr],
 		}
 	}
 
-	fn verify_signature(&self, public_key: &str, challenge: &str, signature: &str) -> Result<()> {
-		let (public_key, _) = PublicKey::from_bech32_check_raw(public_key)?;
-		let signature = Signature::from_hex(signature)?;
-		verify_signature(challenge, &signature, &public_key)
+	/// Check `signature` against the candidate `challenges` in a single pass
+	/// and return the index of the one the client signed.
+	fn verify_signature(
+		&self,
+		public_key: &str,
+		challenges: &[&str],
+		signature: &str,
+		signature_scheme: SignatureScheme,
+	) -> Result<usize> {
+		let verifier = SignatureVerifier::shared();
+		let (public_key, _) = verifier
+			.public_key_from_bech32(public_key)
+			.map_err(|_| ErrorKind::GrinboxProtocolError(GrinboxError::InvalidAddress))?;
+		let signed = verifier
+			.match_challenge(signature_scheme, challenges, signature, &public_key)
 			.map_err(|_| ErrorKind::GrinboxProtocolError(GrinboxError::InvalidSignature))?;
-		Ok(())
+		Ok(signed)
+	}
+
+	fn protocol_error(e: &Error) -> GrinboxError {
+		match e.downcast_ref::<ErrorKind>() {
+			Some(ErrorKind::GrinboxProtocolError(kind)) => kind.clone(),
+			_ => GrinboxError::UnknownError,
+		}
 	}
 
-	fn subscribe(&mut self, address: String, signature: String) -> GrinboxResponse {
-		let result = self.verify_signature(&address, self.get_challenge_raw(), &signature);
+	fn check_chain_type(&self, address: &GrinboxAddress) -> std::result::Result<(), GrinboxError> {
+		match address.network() {
+			Some(ref chain_type) if self.chain_types.contains(chain_type) => Ok(()),
+			_ => Err(GrinboxError::WrongChain),
+		}
+	}
+
+	async fn subscribe(
+		&mut self,
+		address: String,
+		signature: String,
+		signature_scheme: SignatureScheme,
+	) -> GrinboxResponse {
+		// queues, presence and subscriptions are keyed by the bare public key,
+		// as posts address them
+		let address = match GrinboxAddress::from_str_raw(&address) {
+			Ok(subscriber) => {
+				if let Err(e) = self.check_chain_type(&subscriber) {
+					return AsyncServer::error(e);
+				}
+				subscriber.public_key
+			}
+			Err(_) => return AsyncServer::error(GrinboxError::InvalidAddress),
+		};
+
+		let challenge = match self.fresh_challenge() {
+			Ok(challenge) => challenge,
+			Err(e) => return AsyncServer::error(e),
+		};
+		let result = self.verify_signature(&address, &[challenge], &signature, signature_scheme);
 		match result {
-			Ok(()) => {
+			Ok(_) => {
+				let admitted = self
+					.subscription_policy
+					.admit(self.presence.is_online(&address));
 				if self.subscriptions.len() == MAX_SUBSCRIPTIONS {
 					AsyncServer::error(GrinboxError::TooManySubscriptions)
+				} else if let Err(e) = admitted {
+					AsyncServer::error(e)
 				} else {
-					let (res_tx, res_rx) = unbounded::<BrokerResponse>();
 					if self
 						.nats_sender
-						.unbounded_send(BrokerRequest::Subscribe {
+						.send(BrokerRequest::Subscribe {
 							id: self.id.clone(),
-							subject: "/queue/".to_owned() + &address.clone(),
-							response_sender: res_tx,
+							subject: "/queue/".to_owned() + &address,
+							response_sender: self.response_sender.clone(),
 						})
+						.await
 						.is_err()
 					{
 						error!("could not issue subscribe request!");
-						return AsyncServer::error(GrinboxError::UnknownError);
-					};
-
-					if self
-						.response_handlers_sender
-						.unbounded_send(BrokerResponseHandler {
-							inner: self.inner.clone(),
-							response_receiver: res_rx,
-						})
-						.is_err()
-					{
-						error!("could not register subscription handler!");
-						return AsyncServer::error(GrinboxError::UnknownError);
+						return AsyncServer::error(GrinboxError::BrokerUnavailable);
 					};
 
+					self.presence.local_online(&address);
 					self.subscriptions.insert(address.clone(), Subscription {});
 
 					AsyncServer::ok()
 				}
 			}
-			Err(_) => AsyncServer::error(GrinboxError::UnknownError),
+			Err(e) => AsyncServer::error(AsyncServer::protocol_error(&e)),
 		}
 	}
 
-	fn unsubscribe(&mut self, address: String) -> GrinboxResponse {
+	async fn unsubscribe(&mut self, address: String) -> GrinboxResponse {
+		let address = match GrinboxAddress::from_str_raw(&address) {
+			Ok(address) => address.public_key,
+			Err(_) => return AsyncServer::error(GrinboxError::InvalidAddress),
+		};
 		let result = self.subscriptions.remove(&address);
 		match result {
 			Some(_subscription) => {
+				self.presence.local_offline(&address);
 				if self
 					.nats_sender
-					.unbounded_send(BrokerRequest::Unsubscribe {
+					.send(BrokerRequest::Unsubscribe {
 						id: self.id.clone(),
 					})
+					.await
 					.is_err()
 				{
 					error!("could not unsubscribe!");
-					return AsyncServer::error(GrinboxError::UnknownError);
+					return AsyncServer::error(GrinboxError::BrokerUnavailable);
 				};
 
 				AsyncServer::ok()
@@ -289,139 +617,436 @@ impl AsyncServer {
 		}
 	}
 
-	fn post_slate(
+	fn register_alias(
+		&self,
+		alias: String,
+		address: String,
+		signature: String,
+		signature_scheme: SignatureScheme,
+		ttl_in_seconds: Option<u32>,
+		timestamp: u64,
+	) -> GrinboxResponse {
+		if !is_valid_alias(&alias) {
+			return AsyncServer::error(GrinboxError::InvalidAlias);
+		}
+
+		let owner = match GrinboxAddress::from_str_raw(&address) {
+			Ok(owner) => owner,
+			Err(_) => return AsyncServer::error(GrinboxError::InvalidAddress),
+		};
+		if let Err(e) = self.check_chain_type(&owner) {
+			return AsyncServer::error(e);
+		}
+
+		let now = unix_now();
+		if !is_fresh_signature(timestamp, now) {
+			return AsyncServer::error(GrinboxError::InvalidChallenge);
+		}
+
+		let challenge = match self.fresh_challenge() {
+			Ok(challenge) => challenge,
+			Err(e) => return AsyncServer::error(e),
+		};
+		let ttl = ttl_in_seconds.unwrap_or(ALIAS_DEFAULT_TTL_IN_SECONDS);
+		let challenge = alias_challenge(&alias, &address, ttl, timestamp, challenge);
+		if let Err(e) = self.verify_signature(
+			&owner.public_key,
+			&[&challenge],
+			&signature,
+			signature_scheme,
+		) {
+			return AsyncServer::error(AsyncServer::protocol_error(&e));
+		}
+
+		let ttl = ttl.min(ALIAS_MAX_TTL_IN_SECONDS);
+		let record = AliasRecord {
+			alias,
+			address: owner.public_key.clone(),
+			expires_at: now + ttl as u64,
+			signed_at: timestamp,
+		};
+		match self.aliases.register(record.clone(), now) {
+			Ok(()) => GrinboxResponse::Alias {
+				address: self.local_address(&record.address),
+				alias: record.alias,
+				expires_at: record.expires_at,
+			},
+			Err(e) => AsyncServer::error(e),
+		}
+	}
+
+	fn resolve_alias(&self, alias: String) -> GrinboxResponse {
+		match self.aliases.lookup(&alias, unix_now()) {
+			Some(record) => GrinboxResponse::Alias {
+				address: self.local_address(&record.address),
+				alias: record.alias,
+				expires_at: record.expires_at,
+			},
+			None => AsyncServer::error(GrinboxError::UnknownAlias),
+		}
+	}
+
+	/// The address of `public_key` at this relay, where it subscribes.
+	fn local_address(&self, public_key: &str) -> String {
+		let address = GrinboxAddress {
+			public_key: public_key.to_string(),
+			domain: self.grinrelay_domain.clone(),
+			port: self.grinrelay_port,
+			hrp_bytes: None,
+		};
+		address.stripped()
+	}
+
+	async fn post_slate(
 		&self,
 		from: String,
 		to: String,
-		str: String,
-		signature: String,
+		slate: SignedSlate,
 		message_expiration_in_seconds: Option<u32>,
 	) -> GrinboxResponse {
 		let from_address = GrinboxAddress::from_str_raw(&from);
 		if from_address.is_err() {
-			return AsyncServer::error(GrinboxError::InvalidRequest);
+			return AsyncServer::error(GrinboxError::InvalidAddress);
 		}
 		let from_address = from_address.unwrap();
 
 		let to_address = GrinboxAddress::from_str_raw(&to);
 		if to_address.is_err() {
-			return AsyncServer::error(GrinboxError::InvalidRequest);
+			return AsyncServer::error(GrinboxError::InvalidAddress);
 		}
 		let to_address = to_address.unwrap();
 
-		let mut challenge = String::new();
-		challenge.push_str(&str);
-
-		let mut result = self.verify_signature(&from_address.public_key, &challenge, &signature);
-
-		let mut challenge_raw = "";
-		if result.is_err() {
-			challenge.push_str(self.get_challenge_raw());
-			challenge_raw = self.get_challenge_raw();
-			result = self.verify_signature(&from_address.public_key, &challenge, &signature);
-		}
-
-		if result.is_err() {
-			return AsyncServer::error(GrinboxError::InvalidSignature);
+		if let Err(e) = self
+			.check_chain_type(&from_address)
+			.and_then(|_| self.check_chain_type(&to_address))
+		{
+			return AsyncServer::error(e);
 		}
 
-		if to_address.port == self.grinrelay_port
-			&& self.grinrelay_domain.ends_with(to_address.domain.as_str())
-		{
-			let signed_payload = SignedPayload {
-				str,
-				challenge: challenge_raw.to_string(),
-				signature,
-			};
+		let signing_mode = match self.verify_slate(&from_address, &slate) {
+			Ok(signing_mode) => signing_mode,
+			Err(e) => return AsyncServer::error(e),
+		};
 
-			let signed_payload = serde_json::to_string(&signed_payload).unwrap();
+		let endpoint = self.resolve_relay(&to_address).await;
+		if self.is_local(&endpoint.host, endpoint.port) {
+			let signed_payload = self.signed_payload(slate, signing_mode);
 
-			if self
-				.nats_sender
-				.unbounded_send(BrokerRequest::PostMessage {
-					subject: to_address.public_key,
-					payload: signed_payload,
-					reply_to: from_address.stripped(),
-					message_expiration_in_seconds,
-				})
-				.is_err()
-			{
-				error!("could not post message to broker!");
-				return AsyncServer::error(GrinboxError::UnknownError);
+			let (delivery, delivered) = oneshot::channel();
+			let request = BrokerRequest::PostMessage {
+				subject: to_address.public_key,
+				payload: signed_payload,
+				reply_to: from_address.stripped(),
+				message_expiration_in_seconds,
+				delivery,
 			};
-
-			AsyncServer::ok()
+			self.post(request, delivered).await
 		} else {
+			let slate = SignedSlate {
+				signing_mode: Some(signing_mode),
+				..slate
+			};
 			self.post_slate_federated(
+				&endpoint,
 				&from_address,
 				&to_address,
-				str,
-				signature,
+				slate,
 				message_expiration_in_seconds,
 			)
+			.await
+		}
+	}
+
+	/// Check the signature of a slate, returning the mode it was signed in.
+	fn verify_slate(
+		&self,
+		from_address: &GrinboxAddress,
+		slate: &SignedSlate,
+	) -> std::result::Result<SigningMode, GrinboxError> {
+		// clients which do not tell what they signed get both modes tried
+		let modes = match slate.signing_mode {
+			Some(mode) => vec![mode],
+			None => vec![SigningMode::Slate, SigningMode::SlateAndChallenge],
+		};
+		let candidates: Vec<String> = modes
+			.iter()
+			.map(|mode| mode.signed_text(&slate.str, self.slate_challenge(slate)))
+			.collect();
+		let candidates: Vec<&str> = candidates.iter().map(|c| c.as_str()).collect();
+		let result = self.verify_signature(
+			&from_address.public_key,
+			&candidates,
+			&slate.signature,
+			slate.signature_scheme,
+		);
+
+		match result {
+			Ok(signed) => match modes[signed] {
+				// a slate forwarded by another relay was checked against its challenge there
+				SigningMode::SlateAndChallenge if slate.challenge.is_none() => {
+					self.fresh_challenge()?;
+					Ok(SigningMode::SlateAndChallenge)
+				}
+				mode => Ok(mode),
+			},
+			Err(e) => Err(AsyncServer::protocol_error(&e)),
+		}
+	}
+
+	/// The challenge `slate` was signed over, if signed in `SlateAndChallenge` mode.
+	fn slate_challenge<'a>(&'a self, slate: &'a SignedSlate) -> &'a str {
+		match slate.challenge {
+			Some(ref challenge) => challenge,
+			None => self.get_challenge_raw(),
+		}
+	}
+
+	/// What the recipient of a verified slate receives from the broker.
+	fn signed_payload(&self, slate: SignedSlate, signing_mode: SigningMode) -> String {
+		let challenge_raw = match signing_mode {
+			SigningMode::Slate => String::new(),
+			SigningMode::SlateAndChallenge => self.slate_challenge(&slate).to_string(),
+		};
+		let signed_payload = SignedPayload {
+			str: slate.str,
+			challenge: challenge_raw,
+			signature: slate.signature,
+			signature_scheme: slate.signature_scheme,
+			signing_mode: Some(signing_mode),
+		};
+		serde_json::to_string(&signed_payload).unwrap()
+	}
+
+	/// Deliver every slate or none, through a single broker transaction. Only
+	/// recipients served by this relay can take part, each of them once.
+	async fn post_slate_batch(
+		&self,
+		from: String,
+		slates: Vec<BatchSlate>,
+		signature_scheme: SignatureScheme,
+		signing_mode: Option<SigningMode>,
+		message_expiration_in_seconds: Option<u32>,
+	) -> GrinboxResponse {
+		if slates.is_empty() || slates.len() > MAX_BATCH_SIZE {
+			return AsyncServer::error(GrinboxError::InvalidRequest);
+		}
+
+		let from_address = match GrinboxAddress::from_str_raw(&from) {
+			Ok(from_address) => from_address,
+			Err(_) => return AsyncServer::error(GrinboxError::InvalidAddress),
+		};
+		if let Err(e) = self.check_chain_type(&from_address) {
+			return AsyncServer::error(e);
+		}
+
+		let mut recipients: Vec<(GrinboxAddress, BatchSlate)> = Vec::with_capacity(slates.len());
+		for slate in slates {
+			let to_address = match GrinboxAddress::from_str_raw(&slate.to) {
+				Ok(to_address) => to_address,
+				Err(_) => return AsyncServer::error(GrinboxError::InvalidAddress),
+			};
+			if let Err(e) = self.check_chain_type(&to_address) {
+				return AsyncServer::error(e);
+			}
+			let duplicate = recipients
+				.iter()
+				.any(|(recipient, _)| recipient.public_key == to_address.public_key);
+			if duplicate {
+				return AsyncServer::error(GrinboxError::InvalidRequest);
+			}
+			recipients.push((to_address, slate));
+		}
+
+		let mut messages = Vec::with_capacity(recipients.len());
+		for (to_address, slate) in &recipients {
+			let slate = SignedSlate {
+				str: slate.str.clone(),
+				signature: slate.signature.clone(),
+				signature_scheme,
+				signing_mode,
+				challenge: None,
+			};
+			let signing_mode = match self.verify_slate(&from_address, &slate) {
+				Ok(signing_mode) => signing_mode,
+				Err(e) => return AsyncServer::error(e),
+			};
+			messages.push(BatchMessage {
+				subject: to_address.public_key.clone(),
+				payload: self.signed_payload(slate, signing_mode),
+			});
+		}
+
+		// a transaction of our broker can not span another relay
+		let endpoints = recipients
+			.iter()
+			.map(|(to_address, _)| self.resolve_relay(to_address));
+		let endpoints = match tokio::time::timeout(BATCH_RESOLVE_TIMEOUT, join_all(endpoints)).await
+		{
+			Ok(endpoints) => endpoints,
+			Err(_) => {
+				warn!("could not resolve the relays of a batch in time");
+				return AsyncServer::error(GrinboxError::RemoteRelayUnreachable);
+			}
+		};
+		if endpoints
+			.iter()
+			.any(|endpoint| !self.is_local(&endpoint.host, endpoint.port))
+		{
+			return AsyncServer::error(GrinboxError::InvalidRequest);
+		}
+
+		let (delivery, delivered) = oneshot::channel();
+		let request = BrokerRequest::PostBatch {
+			messages,
+			reply_to: from_address.stripped(),
+			message_expiration_in_seconds,
+			delivery,
+		};
+		self.post(request, delivered).await
+	}
+
+	/// Hand `request` to the broker and wait for it to confirm the delivery.
+	async fn post(
+		&self,
+		request: BrokerRequest,
+		delivered: oneshot::Receiver<std::result::Result<(), GrinboxError>>,
+	) -> GrinboxResponse {
+		match self.nats_sender.try_send(request) {
+			Ok(()) => {}
+			Err(TrySendError::Full(_)) => {
+				warn!("broker is behind, refusing post");
+				self.gauges.rejected();
+				return AsyncServer::error(GrinboxError::Busy);
+			}
+			Err(TrySendError::Closed(_)) => {
+				error!("could not post message to broker!");
+				return AsyncServer::error(GrinboxError::BrokerUnavailable);
+			}
+		}
+
+		match tokio::time::timeout(DELIVERY_TIMEOUT, delivered).await {
+			Ok(Ok(Ok(()))) => AsyncServer::ok(),
+			Ok(Ok(Err(kind))) => AsyncServer::error(kind),
+			// the broker session ended, or never answered
+			Ok(Err(_)) | Err(_) => {
+				error!("broker did not confirm the post!");
+				AsyncServer::error(GrinboxError::BrokerUnavailable)
+			}
 		}
 	}
 
-	fn post_slate_federated(
+	fn is_local(&self, host: &str, port: u16) -> bool {
+		port == self.grinrelay_port && self.grinrelay_domain.ends_with(host)
+	}
+
+	async fn resolve_relay(&self, address: &GrinboxAddress) -> RelayEndpoint {
+		let default = RelayEndpoint {
+			host: address.domain.clone(),
+			port: address.port,
+			tls: !self.grinrelay_protocol_unsecure,
+			addrs: None,
+		};
+		if self.is_local(&default.host, default.port) {
+			return default;
+		}
+		match self.resolver.resolve(&address.domain).await {
+			Some(endpoint) => endpoint,
+			// the domain of an address is the sender's choice, not the operator's
+			None => RelayEndpoint {
+				addrs: Some(
+					public_addrs(&default.host, default.port)
+						.await
+						.unwrap_or_default(),
+				),
+				..default
+			},
+		}
+	}
+
+	async fn post_slate_federated(
 		&self,
+		endpoint: &RelayEndpoint,
 		from_address: &GrinboxAddress,
 		to_address: &GrinboxAddress,
-		str: String,
-		signature: String,
+		slate: SignedSlate,
 		message_expiration_in_seconds: Option<u32>,
 	) -> GrinboxResponse {
-		let url = match self.grinrelay_protocol_unsecure {
-			false => format!("wss://{}:{}", to_address.domain, to_address.port),
-			true => format!("ws://{}:{}", to_address.domain, to_address.port),
+		let url = match endpoint.tls {
+			true => format!("wss://{}:{}", endpoint.host, endpoint.port),
+			false => format!("ws://{}:{}", endpoint.host, endpoint.port),
 		};
 
-		let str = str.clone();
-		let signature = signature.clone();
-		let result = connect(url, move |sender| {
-			let str = str.clone();
-			let signature = signature.clone();
-			move |msg: Message| {
-				let response = serde_json::from_str::<GrinboxResponse>(&msg.to_string())
-					.expect("could not parse response!");
-
-				match response {
-					GrinboxResponse::Challenge { str: _ } => {
-						let request = GrinboxRequest::PostSlate {
-							from: from_address.stripped(),
-							to: to_address.stripped(),
-							str: str.clone(),
-							signature: signature.clone(),
-							message_expiration_in_seconds,
-						};
-
-						sender
-							.send(serde_json::to_string(&request).unwrap())
-							.unwrap();
-					}
-					GrinboxResponse::Error {
-						kind: _,
-						description: _,
-					} => {
-						let _ = sender.close(CloseCode::Abnormal);
-					}
-					GrinboxResponse::Ok => {
-						let _ = sender.close(CloseCode::Normal);
-					}
-					_ => {}
-				}
-				Ok(())
+		let challenge = match slate.signing_mode {
+			Some(SigningMode::SlateAndChallenge) => Some(self.slate_challenge(&slate).to_string()),
+			_ => None,
+		};
+		let request = GrinboxRequest::PostSlate {
+			from: from_address.stripped(),
+			to: to_address.stripped(),
+			str: slate.str,
+			signature: slate.signature,
+			signature_scheme: slate.signature_scheme,
+			signing_mode: slate.signing_mode,
+			message_expiration_in_seconds,
+			challenge,
+		};
+
+		match tokio::time::timeout(
+			FORWARD_TIMEOUT,
+			AsyncServer::forward(&url, endpoint.addrs.as_deref(), &request),
+		)
+		.await
+		{
+			Ok(Ok(response)) => response,
+			Ok(Err(e)) => {
+				warn!("could not post slate to {}: {}", url, e);
+				AsyncServer::error(GrinboxError::RemoteRelayUnreachable)
 			}
-		});
+			Err(_) => {
+				warn!("{} did not answer the posted slate in time", url);
+				AsyncServer::error(GrinboxError::RemoteRelayUnreachable)
+			}
+		}
+	}
 
-		match result {
-			Ok(()) => AsyncServer::ok(),
-			Err(_) => AsyncServer::error(GrinboxError::UnknownError),
+	/// Answer the challenge of a remote relay with `request`, returning the
+	/// relay's verdict. The relay is reached at `addrs` if given, instead of
+	/// resolving the host of `url`.
+	async fn forward(
+		url: &str,
+		addrs: Option<&[SocketAddr]>,
+		request: &GrinboxRequest,
+	) -> std::result::Result<GrinboxResponse, WsError> {
+		let (mut ws, _) = match addrs {
+			Some(addrs) => {
+				let stream = TcpStream::connect(addrs).await?;
+				tokio_tungstenite::client_async_tls(url, stream).await?
+			}
+			None => tokio_tungstenite::connect_async(url).await?,
+		};
+		while let Some(message) = ws.next().await {
+			let text = match message? {
+				Message::Text(text) => text,
+				Message::Close(_) => break,
+				_ => continue,
+			};
+			match serde_json::from_str::<GrinboxResponse>(&text) {
+				Ok(GrinboxResponse::Challenge { .. }) => {
+					let request = serde_json::to_string(request).unwrap();
+					ws.send(Message::Text(request)).await?;
+				}
+				Ok(response @ GrinboxResponse::Ok)
+				| Ok(response @ GrinboxResponse::Error { .. }) => {
+					let _ = ws.close(None).await;
+					return Ok(response);
+				}
+				_ => {}
+			}
 		}
+		Err(WsError::ConnectionClosed)
 	}
-}
 
-impl Handler for AsyncServer {
-	fn on_open(&mut self, _: Handshake) -> WsResult<()> {
+	fn on_open(&mut self) {
 		info!(
 			"[{}] {}",
 			self.id.bright_green(),
@@ -430,37 +1055,75 @@ impl Handler for AsyncServer {
 
 		let response = self.get_challenge();
 		debug!("[{}] <- {}", self.id.bright_green(), response);
-		let server = self.inner.lock().unwrap();
-		if server
-			.out
-			.send(serde_json::to_string(&response).unwrap())
-			.is_err()
-		{
-			error!("could not send challenge to client!");
-		};
-		Ok(())
+		self.send(&response);
 	}
 
-	fn on_message(&mut self, msg: Message) -> WsResult<()> {
-		let request = serde_json::from_str(&msg.to_string());
+	async fn on_message(&mut self, msg: &str) {
+		let request = serde_json::from_str(msg);
 
-		let response = if request.is_ok() {
-			let request = request.unwrap();
+		let response = if let Ok(request) = request {
 			info!("[{}] -> {}", self.id.bright_green(), request);
 			match request {
 				GrinboxRequest::Challenge => self.get_challenge(),
-				GrinboxRequest::Subscribe { address, signature } => {
-					self.subscribe(address, signature)
-				}
+				GrinboxRequest::Subscribe {
+					address,
+					signature,
+					signature_scheme,
+				} => self.subscribe(address, signature, signature_scheme).await,
 				GrinboxRequest::RetrieveRelayAddr { abbr } => self.retrieve_relay_addr(abbr),
 				GrinboxRequest::PostSlate {
 					from,
 					to,
 					str,
 					signature,
+					signature_scheme,
+					signing_mode,
+					message_expiration_in_seconds,
+					challenge,
+				} => {
+					let slate = SignedSlate {
+						str,
+						signature,
+						signature_scheme,
+						signing_mode,
+						challenge,
+					};
+					self.post_slate(from, to, slate, message_expiration_in_seconds)
+						.await
+				}
+				GrinboxRequest::PostSlateBatch {
+					from,
+					slates,
 					message_expiration_in_seconds,
-				} => self.post_slate(from, to, str, signature, message_expiration_in_seconds),
-				GrinboxRequest::Unsubscribe { address } => self.unsubscribe(address),
+					signature_scheme,
+					signing_mode,
+				} => {
+					self.post_slate_batch(
+						from,
+						slates,
+						signature_scheme,
+						signing_mode,
+						message_expiration_in_seconds,
+					)
+					.await
+				}
+				GrinboxRequest::Unsubscribe { address } => self.unsubscribe(address).await,
+				GrinboxRequest::RegisterAlias {
+					alias,
+					address,
+					signature,
+					signature_scheme,
+					ttl_in_seconds,
+					timestamp,
+				} => self.register_alias(
+					alias,
+					address,
+					signature,
+					signature_scheme,
+					ttl_in_seconds,
+					timestamp,
+				),
+				GrinboxRequest::ResolveAlias { alias } => self.resolve_alias(alias),
 			}
 		} else {
 			debug!(
@@ -472,12 +1135,14 @@ impl Handler for AsyncServer {
 		};
 
 		info!("[{}] <- {}", self.id.bright_green(), response);
-		let server = self.inner.lock().unwrap();
-		server.out.send(serde_json::to_string(&response).unwrap())
+		self.send(&response);
 	}
 
-	fn on_close(&mut self, code: CloseCode, _reason: &str) {
-		let code = format!("{:?}", code);
+	fn on_close(&mut self, frame: Option<CloseFrame<'_>>) {
+		let code = match frame {
+			Some(frame) => format!("{:?}", frame.code),
+			None => "Status".to_string(),
+		};
 		info!(
 			"[{}] {} [{}]",
 			self.id.bright_green(),
@@ -486,28 +1151,409 @@ impl Handler for AsyncServer {
 		);
 	}
 
-	fn on_error(&mut self, err: ws::Error) {
+	fn on_error(&mut self, err: WsError) {
 		error!("the server encountered an error: {:?}", err);
 	}
+}
 
-	fn on_request(&mut self, req: &Request) -> WsResult<Response> {
-		let res = Response::from_request(req);
-		if let Err(_) = res {
-			let response = Response::new(200, "", vec![]);
-			Ok(response)
-		} else {
-			Ok(res.unwrap())
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::store::MemoryStore;
+	use grinrelaylib::types::GRINRELAY_ADDRESS_HRP_MAINNET;
+	use grinrelaylib::utils::crypto::{public_key_from_secret_key, sign_challenge, Hex};
+	use grinrelaylib::utils::secp::SecretKey;
+	use tokio::sync::mpsc::Receiver;
+
+	const ADDRESS: &str = "gn1qdcmlr9qnc4dsrwqnaluvc8w2m5a6gqpumlx4t9q8mqcqhcudu3jsra9d82";
+
+	fn server() -> (AsyncServer, Receiver<BrokerRequest>) {
+		let (nats_sender, requests) = channel(16);
+		let (presence_sender, _) = channel(16);
+		let (response_sender, _) = channel(16);
+		let consumers = Arc::new(Mutex::new(HashMap::new()));
+		let state = Arc::new(MemoryStore::new());
+		let presence = Presence::new(
+			"a".to_string(),
+			presence_sender,
+			consumers.clone(),
+			state.clone(),
+			SubscriptionPolicy::Reject,
+		);
+		let context = ServerContext {
+			nats_sender,
+			grinrelay_domain: "relay.grin.icu".to_string(),
+			grinrelay_port: 3418,
+			grinrelay_protocol_unsecure: false,
+			consumers,
+			chain_types: Arc::new(vec![ChainTypes::Mainnet]),
+			resolver: Arc::new(StubResolver::new(HashMap::new(), None)),
+			aliases: Arc::new(AliasStore::new(state)),
+			presence: Arc::new(presence),
+			subscription_policy: SubscriptionPolicy::Reject,
+			gauges: Arc::new(QueueGauges::new()),
+		};
+		(AsyncServer::new(response_sender, &context), requests)
+	}
+
+	fn keys(byte: &str) -> (SecretKey, GrinboxAddress) {
+		let secret_key = SecretKey::from_hex(&byte.repeat(32)).unwrap();
+		let public_key = public_key_from_secret_key(&secret_key).unwrap();
+		let address =
+			GrinboxAddress::new_raw(public_key, None, None, GRINRELAY_ADDRESS_HRP_MAINNET.into());
+		(secret_key, address)
+	}
+
+	fn batch_slate(secret_key: &SecretKey, to: &GrinboxAddress) -> BatchSlate {
+		let str = format!("slate for {}", to.public_key);
+		BatchSlate {
+			to: to.stripped(),
+			signature: sign_challenge(&str, secret_key).unwrap().to_hex(),
+			str,
 		}
 	}
 
-	fn upgrade_ssl_server(&mut self, sock: TcpStream) -> ws::Result<SslStream<TcpStream>> {
-		if self.ssl.is_some() {
-			self.ssl.clone().unwrap().accept(sock).map_err(From::from)
-		} else {
-			Err(ws::Error::new(
-				ws::ErrorKind::Internal,
-				"ssl not set up".to_owned(),
-			))?
+	async fn post_batch(
+		server: &AsyncServer,
+		from: &GrinboxAddress,
+		slates: Vec<BatchSlate>,
+	) -> GrinboxResponse {
+		server
+			.post_slate_batch(
+				from.stripped(),
+				slates,
+				SignatureScheme::Ecdsa,
+				Some(SigningMode::Slate),
+				None,
+			)
+			.await
+	}
+
+	fn error_kind(response: GrinboxResponse) -> Option<GrinboxError> {
+		match response {
+			GrinboxResponse::Error { kind, .. } => Some(kind),
+			_ => None,
 		}
 	}
+
+	#[test]
+	fn subscription_policy_from_str() {
+		assert_eq!("reject".parse(), Ok(SubscriptionPolicy::Reject));
+		assert_eq!("Reject".parse(), Ok(SubscriptionPolicy::Reject));
+		assert_eq!("takeover".parse(), Ok(SubscriptionPolicy::TakeOver));
+		assert_eq!("take_over".parse(), Ok(SubscriptionPolicy::TakeOver));
+		assert_eq!("TAKEOVER".parse(), Ok(SubscriptionPolicy::TakeOver));
+		assert!("take-over".parse::<SubscriptionPolicy>().is_err());
+		assert!("".parse::<SubscriptionPolicy>().is_err());
+	}
+
+	#[test]
+	fn reject_refuses_an_address_online_elsewhere() {
+		let (tx, _) = channel(16);
+		let presence = Presence::new(
+			"a".to_string(),
+			tx,
+			Arc::new(Mutex::new(HashMap::new())),
+			Arc::new(MemoryStore::new()),
+			SubscriptionPolicy::Reject,
+		);
+		let policy = SubscriptionPolicy::Reject;
+		assert_eq!(policy.admit(presence.is_online(ADDRESS)), Ok(()));
+
+		// online on another relay of the cluster
+		presence.apply(PresenceEvent::Online {
+			relay: "b".to_string(),
+			address: ADDRESS.to_string(),
+		});
+		assert_eq!(
+			policy.admit(presence.is_online(ADDRESS)),
+			Err(GrinboxError::AlreadySubscribed)
+		);
+		assert_eq!(
+			SubscriptionPolicy::TakeOver.admit(presence.is_online(ADDRESS)),
+			Ok(())
+		);
+
+		// online from another connection of this relay
+		presence.apply(PresenceEvent::Offline {
+			relay: "b".to_string(),
+			address: ADDRESS.to_string(),
+		});
+		presence.local_online(ADDRESS);
+		assert_eq!(
+			policy.admit(presence.is_online(ADDRESS)),
+			Err(GrinboxError::AlreadySubscribed)
+		);
+	}
+
+	#[test]
+	fn tells_websocket_upgrades_from_plain_http() {
+		let upgrade =
+			b"GET / HTTP/1.1\r\nHost: relay\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\r\n";
+		assert_eq!(is_websocket_upgrade(upgrade), Some(true));
+		let upgrade = b"GET / HTTP/1.1\r\nupgrade:WebSocket\r\n\r\n";
+		assert_eq!(is_websocket_upgrade(upgrade), Some(true));
+		let health = b"GET /health HTTP/1.1\r\nHost: relay\r\n\r\n";
+		assert_eq!(is_websocket_upgrade(health), Some(false));
+		let partial = b"GET / HTTP/1.1\r\nUpgrade: websocket\r\n";
+		assert_eq!(is_websocket_upgrade(partial), None);
+	}
+
+	#[tokio::test]
+	async fn answers_plain_http_with_200() {
+		let (mut client, server) = tokio::io::duplex(1024);
+		client
+			.write_all(b"GET /health HTTP/1.1\r\nHost: relay\r\n\r\n")
+			.await
+			.unwrap();
+		assert!(answer_plain_http(server).await.is_none());
+
+		let mut response = Vec::new();
+		tokio::io::AsyncReadExt::read_to_end(&mut client, &mut response)
+			.await
+			.unwrap();
+		assert_eq!(response, PLAIN_HTTP_RESPONSE);
+	}
+
+	#[tokio::test]
+	async fn hands_websocket_upgrades_on_untouched() {
+		let request = b"GET / HTTP/1.1\r\nUpgrade: websocket\r\n\r\n";
+		let (mut client, server) = tokio::io::duplex(1024);
+		client.write_all(request).await.unwrap();
+		let mut stream = answer_plain_http(server).await.unwrap();
+
+		let mut head = vec![0; request.len()];
+		tokio::io::AsyncReadExt::read_exact(&mut stream, &mut head)
+			.await
+			.unwrap();
+		assert_eq!(&head[..], &request[..]);
+	}
+
+	#[tokio::test]
+	async fn refuses_empty_and_oversize_batches() {
+		let (server, mut requests) = server();
+		let (secret_key, sender) = keys("11");
+
+		let response = post_batch(&server, &sender, vec![]).await;
+		assert_eq!(error_kind(response), Some(GrinboxError::InvalidRequest));
+
+		let slates = (0..=MAX_BATCH_SIZE)
+			.map(|i| batch_slate(&secret_key, &keys(&format!("{:02x}", i + 32)).1))
+			.collect();
+		let response = post_batch(&server, &sender, slates).await;
+		assert_eq!(error_kind(response), Some(GrinboxError::InvalidRequest));
+		assert!(requests.try_recv().is_err());
+	}
+
+	#[tokio::test]
+	async fn refuses_duplicate_recipients() {
+		let (server, mut requests) = server();
+		let (secret_key, sender) = keys("11");
+		let (_, recipient) = keys("22");
+		let slates = vec![
+			batch_slate(&secret_key, &recipient),
+			batch_slate(&secret_key, &recipient),
+		];
+
+		let response = post_batch(&server, &sender, slates).await;
+		assert_eq!(error_kind(response), Some(GrinboxError::InvalidRequest));
+		assert!(requests.try_recv().is_err());
+	}
+
+	#[tokio::test]
+	async fn refuses_remote_recipients() {
+		let (server, mut requests) = server();
+		let (secret_key, sender) = keys("11");
+		let (_, local) = keys("22");
+		let remote = GrinboxAddress {
+			domain: "other.relay".to_string(),
+			..keys("33").1
+		};
+		let slates = vec![
+			batch_slate(&secret_key, &local),
+			batch_slate(&secret_key, &remote),
+		];
+
+		let response = post_batch(&server, &sender, slates).await;
+		assert_eq!(error_kind(response), Some(GrinboxError::InvalidRequest));
+		assert!(requests.try_recv().is_err());
+	}
+
+	#[tokio::test]
+	async fn expired_challenges_are_refused_until_renewed() {
+		let (mut server, _requests) = server();
+		let (secret_key, address) = keys("11");
+		let sign = |challenge: &str| sign_challenge(challenge, &secret_key).unwrap().to_hex();
+
+		let signature = sign(server.get_challenge_raw());
+		server.challenge_expires_at = Instant::now();
+		let response = server
+			.subscribe(address.stripped(), signature, SignatureScheme::Ecdsa)
+			.await;
+		assert_eq!(error_kind(response), Some(GrinboxError::ChallengeExpired));
+
+		let expired = server.get_challenge_raw().to_string();
+		server.get_challenge();
+		assert_ne!(server.get_challenge_raw(), expired);
+		let signature = sign(server.get_challenge_raw());
+		let response = server
+			.subscribe(address.stripped(), signature, SignatureScheme::Ecdsa)
+			.await;
+		assert!(matches!(response, GrinboxResponse::Ok));
+	}
+
+	#[tokio::test]
+	async fn subscriptions_use_the_bare_public_key() {
+		let (mut server, mut requests) = server();
+		let (secret_key, address) = keys("11");
+		let signature = sign_challenge(server.get_challenge_raw(), &secret_key)
+			.unwrap()
+			.to_hex();
+		let located = format!("{}@relay.grin.icu:3418", address.public_key);
+
+		let response = server
+			.subscribe(located.clone(), signature, SignatureScheme::Ecdsa)
+			.await;
+		assert!(matches!(response, GrinboxResponse::Ok));
+		match requests.try_recv() {
+			Ok(BrokerRequest::Subscribe { subject, .. }) => {
+				assert_eq!(subject, format!("/queue/{}", address.public_key))
+			}
+			request => panic!("unexpected request {:?}", request),
+		}
+		assert!(server.presence.is_online(&address.public_key));
+		assert!(server.subscriptions.contains_key(&address.public_key));
+
+		let response = server.unsubscribe(located).await;
+		assert!(matches!(response, GrinboxResponse::Ok));
+		assert!(!server.presence.is_online(&address.public_key));
+	}
+
+	#[test]
+	fn aliases_are_registered_to_the_bare_public_key() {
+		let (server, _) = server();
+		let (secret_key, address) = keys("11");
+		let located = format!("{}@relay.grin.icu", address.public_key);
+		let timestamp = unix_now();
+		let challenge = alias_challenge(
+			"shop",
+			&located,
+			ALIAS_DEFAULT_TTL_IN_SECONDS,
+			timestamp,
+			server.get_challenge_raw(),
+		);
+		let signature = sign_challenge(&challenge, &secret_key).unwrap().to_hex();
+
+		let response = server.register_alias(
+			"shop".to_string(),
+			located,
+			signature,
+			SignatureScheme::Ecdsa,
+			None,
+			timestamp,
+		);
+		match response {
+			GrinboxResponse::Alias { address: owner, .. } => assert_eq!(owner, address.public_key),
+			response => panic!("unexpected response {:?}", response),
+		}
+		let record = server.aliases.lookup("shop", timestamp).unwrap();
+		assert_eq!(record.address, address.public_key);
+	}
+
+	#[test]
+	fn expired_aliases_do_not_resolve() {
+		let (server, _) = server();
+		let (_, address) = keys("11");
+		let now = unix_now();
+		let record = AliasRecord {
+			alias: "shop".to_string(),
+			address: address.public_key.clone(),
+			expires_at: now,
+			signed_at: now - 1,
+		};
+		server.aliases.register(record, now - 1).unwrap();
+
+		let response = server.resolve_alias("shop".to_string());
+		assert_eq!(error_kind(response), Some(GrinboxError::UnknownAlias));
+	}
+
+	#[test]
+	fn forwarded_slates_keep_their_challenge() {
+		let (mut server, _) = server();
+		let (secret_key, sender) = keys("11");
+		server.challenge_expires_at = Instant::now();
+
+		let str = "slate".to_string();
+		let signed_text = SigningMode::SlateAndChallenge.signed_text(&str, "remote");
+		let slate = SignedSlate {
+			str,
+			signature: sign_challenge(&signed_text, &secret_key).unwrap().to_hex(),
+			signature_scheme: SignatureScheme::Ecdsa,
+			signing_mode: Some(SigningMode::SlateAndChallenge),
+			challenge: Some("remote".to_string()),
+		};
+		assert_eq!(
+			server.verify_slate(&sender, &slate),
+			Ok(SigningMode::SlateAndChallenge)
+		);
+
+		let payload = server.signed_payload(slate, SigningMode::SlateAndChallenge);
+		let payload: SignedPayload = serde_json::from_str(&payload).unwrap();
+		assert_eq!(payload.challenge, "remote");
+	}
+
+	#[tokio::test]
+	async fn answers_once_the_broker_confirmed_the_batch() {
+		let (server, mut requests) = server();
+		let (secret_key, sender) = keys("11");
+		let (_, first) = keys("22");
+		let (_, second) = keys("33");
+		let slates = vec![
+			batch_slate(&secret_key, &first),
+			batch_slate(&secret_key, &second),
+		];
+
+		let reply_to = sender.stripped();
+		let broker = tokio::spawn(async move {
+			match requests.recv().await {
+				Some(BrokerRequest::PostBatch {
+					messages,
+					reply_to: batch_reply_to,
+					delivery,
+					..
+				}) => {
+					let subjects: Vec<&str> = messages.iter().map(|m| m.subject.as_str()).collect();
+					assert_eq!(
+						subjects,
+						vec![first.public_key.as_str(), second.public_key.as_str()]
+					);
+					assert_eq!(batch_reply_to, reply_to);
+					delivery.send(Ok(())).unwrap();
+				}
+				request => panic!("unexpected request {:?}", request),
+			}
+		});
+
+		let response = post_batch(&server, &sender, slates).await;
+		assert!(matches!(response, GrinboxResponse::Ok));
+		broker.await.unwrap();
+	}
+
+	#[tokio::test]
+	async fn fails_a_batch_the_broker_did_not_confirm() {
+		let (server, mut requests) = server();
+		let (secret_key, sender) = keys("11");
+		let (_, recipient) = keys("22");
+
+		let broker = tokio::spawn(async move {
+			if let Some(BrokerRequest::PostBatch { delivery, .. }) = requests.recv().await {
+				delivery.send(Err(GrinboxError::BrokerUnavailable)).unwrap();
+			}
+		});
+
+		let slates = vec![batch_slate(&secret_key, &recipient)];
+		let response = post_batch(&server, &sender, slates).await;
+		assert_eq!(error_kind(response), Some(GrinboxError::BrokerUnavailable));
+		broker.await.unwrap();
+	}
 }
@@ -0,0 +1,534 @@
+// Copyright 2019 The Gotts Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Subscribers online on any relay of a cluster.
+//!
+//! Each relay publishes the subscribe and unsubscribe events of its own
+//! connections to a fanout exchange every relay listens to, plus a periodic
+//! heartbeat carrying its whole online set. Relays which stop sending
+//! heartbeats are forgotten, together with their subscribers.
+//!
+//! Under `SubscriptionPolicy::TakeOver`, an address coming online on another
+//! relay revokes its local subscription, so a single connection consumes it.
+//!
+//! `Presence` is the only writer of the abbreviation lookup. An address is
+//! listed while it is online anywhere in the cluster or while the broker
+//! reports a consumer for its queue.
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{error::TrySendError, Sender};
+
+use super::SubscriptionPolicy;
+use crate::broker::BrokerRequest;
+use crate::store::{StateStore, Table};
+
+pub const PRESENCE_EXCHANGE: &str = "grinrelay.presence";
+/// Presence events of other relays buffered before the broker drops them
+pub const PRESENCE_QUEUE_SIZE: usize = 1024;
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+const PRESENCE_TTL: Duration = Duration::from_secs(90);
+const RELAY_ABBR_LEN: usize = 6;
+const CONSUMERS_TABLE: &str = "consumers";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type")]
+pub enum PresenceEvent {
+	Online {
+		relay: String,
+		address: String,
+	},
+	Offline {
+		relay: String,
+		address: String,
+	},
+	Heartbeat {
+		relay: String,
+		addresses: Vec<String>,
+	},
+}
+
+struct RemoteRelay {
+	seen: Instant,
+	addresses: HashSet<String>,
+}
+
+pub struct Presence {
+	relay_id: String,
+	broker: Sender<BrokerRequest>,
+	/// Local subscriptions per address
+	local: Mutex<HashMap<String, usize>>,
+	remote: Mutex<HashMap<String, RemoteRelay>>,
+	/// Abbreviation to addresses lookup answering `RetrieveRelayAddr`
+	consumers: Arc<Mutex<HashMap<String, Vec<String>>>>,
+	/// Relay queues with a consumer on the broker, by abbreviation, kept so
+	/// they survive a restart while the management api is unreachable
+	consumed: Table<Vec<String>>,
+	policy: SubscriptionPolicy,
+}
+
+impl Presence {
+	pub fn new(
+		relay_id: String,
+		broker: Sender<BrokerRequest>,
+		consumers: Arc<Mutex<HashMap<String, Vec<String>>>>,
+		state: Arc<dyn StateStore>,
+		policy: SubscriptionPolicy,
+	) -> Presence {
+		Presence {
+			relay_id,
+			broker,
+			policy,
+			local: Mutex::new(HashMap::new()),
+			remote: Mutex::new(HashMap::new()),
+			consumers,
+			consumed: Table::new(state, CONSUMERS_TABLE),
+		}
+	}
+
+	/// Send heartbeats and forget silent relays, on a task of its own.
+	pub fn start(presence: Arc<Presence>) {
+		tokio::spawn(async move {
+			let first = tokio::time::Instant::now() + HEARTBEAT_INTERVAL;
+			let mut heartbeat = tokio::time::interval_at(first, HEARTBEAT_INTERVAL);
+			loop {
+				heartbeat.tick().await;
+				let addresses = presence.local.lock().keys().cloned().collect();
+				presence.publish(PresenceEvent::Heartbeat {
+					relay: presence.relay_id.clone(),
+					addresses,
+				});
+				presence.prune();
+			}
+		});
+	}
+
+	pub fn local_online(&self, address: &str) {
+		let first = {
+			let mut local = self.local.lock();
+			let count = local.entry(address.to_string()).or_insert(0);
+			*count += 1;
+			*count == 1
+		};
+		if first {
+			self.index(address);
+			self.publish(PresenceEvent::Online {
+				relay: self.relay_id.clone(),
+				address: address.to_string(),
+			});
+		}
+	}
+
+	pub fn local_offline(&self, address: &str) {
+		let last = {
+			let mut local = self.local.lock();
+			match local.get_mut(address) {
+				Some(count) if *count > 1 => {
+					*count -= 1;
+					false
+				}
+				Some(_) => {
+					local.remove(address);
+					true
+				}
+				None => false,
+			}
+		};
+		if last {
+			self.index(address);
+			self.publish(PresenceEvent::Offline {
+				relay: self.relay_id.clone(),
+				address: address.to_string(),
+			});
+		}
+	}
+
+	/// Take the queues consumed on the broker from the state store, as they
+	/// were before the restart.
+	pub fn recover_consumers(&self) {
+		let consumed = self.consumed.entries();
+		info!(
+			"recovered {} consumers from the state store",
+			consumed.len()
+		);
+		for (_, queues) in consumed {
+			for queue in queues {
+				self.index(&queue);
+			}
+		}
+	}
+
+	/// Replace the recovered consumers with the ones the broker lists, catching
+	/// up with the changes made while the relay was down.
+	pub fn reconcile_consumers(&self, consumed: HashMap<String, Vec<String>>) {
+		for (key, queues) in self.consumed.entries() {
+			if !consumed.contains_key(&key) {
+				if let Err(e) = self.consumed.remove(&key) {
+					error!("could not forget consumer {}: {}", key, e);
+				}
+				for queue in queues {
+					self.index(&queue);
+				}
+			}
+		}
+		for (key, queues) in consumed.iter() {
+			let previous = self.consumed.get(key).unwrap_or_default();
+			if let Err(e) = self.consumed.put(key, queues) {
+				error!("could not save consumer {}: {}", key, e);
+			}
+			for queue in previous.iter().chain(queues) {
+				self.index(queue);
+			}
+		}
+	}
+
+	/// The broker reports a consumer for the queue of `address`.
+	pub fn consumer_created(&self, address: &str) {
+		let key = abbr(address);
+		let mut queues = self.consumed.get(key).unwrap_or_default();
+		queues.push(address.to_string());
+		queues.sort_unstable();
+		queues.dedup();
+		if let Err(e) = self.consumed.put(key, &queues) {
+			error!("could not save consumer {}: {}", key, e);
+		}
+		self.index(address);
+	}
+
+	/// The broker reports the consumer of the queue of `address` gone. The
+	/// address stays listed while it is online on another relay.
+	pub fn consumer_deleted(&self, address: &str) {
+		let key = abbr(address);
+		if let Some(mut queues) = self.consumed.get(key) {
+			queues.retain(|queue| queue != address);
+			let result = match queues.is_empty() {
+				true => self.consumed.remove(key),
+				false => self.consumed.put(key, &queues),
+			};
+			if let Err(e) = result {
+				error!("could not save consumer {}: {}", key, e);
+			}
+		}
+		self.index(address);
+	}
+
+	fn is_consumed(&self, address: &str) -> bool {
+		match self.consumed.get(abbr(address)) {
+			Some(queues) => queues.iter().any(|queue| queue == address),
+			None => false,
+		}
+	}
+
+	/// Whether `address` is subscribed on any relay of the cluster.
+	pub fn is_online(&self, address: &str) -> bool {
+		self.local.lock().contains_key(address) || self.is_online_elsewhere(address)
+	}
+
+	/// Whether `address` is subscribed on another relay of the cluster.
+	pub fn is_online_elsewhere(&self, address: &str) -> bool {
+		self.remote
+			.lock()
+			.values()
+			.any(|relay| relay.addresses.contains(address))
+	}
+
+	/// Apply an event received from the presence exchange.
+	pub fn apply(&self, event: PresenceEvent) {
+		let relay_id = match event {
+			PresenceEvent::Online { ref relay, .. }
+			| PresenceEvent::Offline { ref relay, .. }
+			| PresenceEvent::Heartbeat { ref relay, .. } => relay.clone(),
+		};
+		// our own events come back through the fanout
+		if relay_id == self.relay_id {
+			return;
+		}
+		let came_online = match event {
+			PresenceEvent::Online { ref address, .. } => Some(address.clone()),
+			_ => None,
+		};
+
+		let changed: Vec<String> = {
+			let mut remote = self.remote.lock();
+			let relay = remote.entry(relay_id).or_insert_with(|| RemoteRelay {
+				seen: Instant::now(),
+				addresses: HashSet::new(),
+			});
+			relay.seen = Instant::now();
+			match event {
+				PresenceEvent::Online { address, .. } => {
+					relay.addresses.insert(address.clone());
+					vec![address]
+				}
+				PresenceEvent::Offline { address, .. } => {
+					relay.addresses.remove(&address);
+					vec![address]
+				}
+				PresenceEvent::Heartbeat { addresses, .. } => {
+					let addresses: HashSet<String> = addresses.into_iter().collect();
+					let changed = relay
+						.addresses
+						.symmetric_difference(&addresses)
+						.cloned()
+						.collect();
+					relay.addresses = addresses;
+					changed
+				}
+			}
+		};
+		for address in changed {
+			self.index(&address);
+		}
+		if let Some(address) = came_online {
+			let local = self.local.lock().contains_key(&address);
+			if local && self.policy == SubscriptionPolicy::TakeOver {
+				self.revoke(&address);
+			}
+		}
+	}
+
+	fn prune(&self) {
+		let expired: Vec<RemoteRelay> = {
+			let mut remote = self.remote.lock();
+			let silent: Vec<String> = remote
+				.iter()
+				.filter(|(_, relay)| relay.seen.elapsed() > PRESENCE_TTL)
+				.map(|(id, _)| id.clone())
+				.collect();
+			silent
+				.iter()
+				.filter_map(|id| {
+					warn!("relay {} stopped sending presence heartbeats", id);
+					remote.remove(id)
+				})
+				.collect()
+		};
+		for relay in expired {
+			for address in relay.addresses {
+				self.index(&address);
+			}
+		}
+	}
+
+	/// Bring the abbreviation lookup of `address` in line with its presence.
+	fn index(&self, address: &str) {
+		let listed = self.is_online(address) || self.is_consumed(address);
+		let key = abbr(address);
+
+		let mut consumers = self.consumers.lock();
+		if listed {
+			let queues = consumers.entry(key.to_string()).or_default();
+			queues.push(address.to_string());
+			queues.sort_unstable();
+			queues.dedup();
+		} else if let Some(queues) = consumers.get_mut(key) {
+			queues.retain(|queue| queue != address);
+			if queues.is_empty() {
+				consumers.remove(key);
+			}
+		}
+	}
+
+	fn revoke(&self, address: &str) {
+		info!("{} subscribed on another relay, revoking", address);
+		let subject = "/queue/".to_owned() + address;
+		match self.broker.try_send(BrokerRequest::Revoke { subject }) {
+			Ok(()) => {}
+			// must not be lost, wait for the broker to catch up
+			Err(TrySendError::Full(request)) => {
+				let broker = self.broker.clone();
+				tokio::spawn(async move { broker.send(request).await });
+			}
+			Err(TrySendError::Closed(_)) => error!("could not revoke subscription!"),
+		}
+	}
+
+	fn publish(&self, event: PresenceEvent) {
+		let payload = serde_json::to_string(&event).unwrap();
+		match self
+			.broker
+			.try_send(BrokerRequest::PublishPresence { payload })
+		{
+			Ok(()) => {}
+			// the next heartbeat tells the other relays
+			Err(TrySendError::Full(_)) => warn!("broker is behind, dropping presence event"),
+			Err(TrySendError::Closed(_)) => error!("could not publish presence event!"),
+		}
+	}
+}
+
+/// The relay abbreviation of `address`, its last characters.
+fn abbr(address: &str) -> &str {
+	let tail = address.len().saturating_sub(RELAY_ABBR_LEN);
+	&address[tail..]
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::store::MemoryStore;
+	use tokio::sync::mpsc::channel;
+
+	const ADDRESS: &str = "gn1qdcmlr9qnc4dsrwqnaluvc8w2m5a6gqpumlx4t9q8mqcqhcudu3jsra9d82";
+
+	fn presence(relay_id: &str) -> Presence {
+		let (tx, _) = channel(16);
+		Presence::new(
+			relay_id.to_string(),
+			tx,
+			Arc::new(Mutex::new(HashMap::new())),
+			Arc::new(MemoryStore::new()),
+			SubscriptionPolicy::TakeOver,
+		)
+	}
+
+	#[test]
+	fn remote_subscription_takes_over() {
+		let (tx, mut rx) = channel(16);
+		let presence = Presence::new(
+			"a".to_string(),
+			tx,
+			Arc::new(Mutex::new(HashMap::new())),
+			Arc::new(MemoryStore::new()),
+			SubscriptionPolicy::TakeOver,
+		);
+		presence.local_online(ADDRESS);
+		presence.apply(PresenceEvent::Online {
+			relay: "b".to_string(),
+			address: ADDRESS.to_string(),
+		});
+		drop(presence);
+
+		let mut revoked = vec![];
+		while let Ok(request) = rx.try_recv() {
+			if let BrokerRequest::Revoke { subject } = request {
+				revoked.push(subject);
+			}
+		}
+		assert_eq!(revoked, vec![format!("/queue/{}", ADDRESS)]);
+	}
+
+	#[test]
+	fn remote_events_update_the_lookup() {
+		let presence = presence("a");
+		presence.apply(PresenceEvent::Online {
+			relay: "b".to_string(),
+			address: ADDRESS.to_string(),
+		});
+		assert!(presence.is_online_elsewhere(ADDRESS));
+		assert_eq!(
+			presence.consumers.lock().get(&ADDRESS[ADDRESS.len() - 6..]),
+			Some(&vec![ADDRESS.to_string()])
+		);
+
+		presence.apply(PresenceEvent::Heartbeat {
+			relay: "b".to_string(),
+			addresses: vec![],
+		});
+		assert!(!presence.is_online(ADDRESS));
+		assert!(presence.consumers.lock().is_empty());
+	}
+
+	#[test]
+	fn own_events_are_ignored() {
+		let presence = presence("a");
+		presence.local_online(ADDRESS);
+		presence.apply(PresenceEvent::Offline {
+			relay: "a".to_string(),
+			address: ADDRESS.to_string(),
+		});
+		assert!(presence.is_online(ADDRESS));
+		assert!(!presence.is_online_elsewhere(ADDRESS));
+
+		presence.local_offline(ADDRESS);
+		assert!(!presence.is_online(ADDRESS));
+	}
+
+	#[test]
+	fn remote_address_survives_a_local_consumer_deleted() {
+		let presence = presence("a");
+		let key = &ADDRESS[ADDRESS.len() - 6..];
+		presence.consumer_created(ADDRESS);
+		presence.apply(PresenceEvent::Online {
+			relay: "b".to_string(),
+			address: ADDRESS.to_string(),
+		});
+
+		// the local consumer went away, the address moved to relay b
+		presence.consumer_deleted(ADDRESS);
+		assert_eq!(
+			presence.consumers.lock().get(key),
+			Some(&vec![ADDRESS.to_string()])
+		);
+
+		presence.apply(PresenceEvent::Offline {
+			relay: "b".to_string(),
+			address: ADDRESS.to_string(),
+		});
+		assert!(presence.consumers.lock().is_empty());
+	}
+
+	#[test]
+	fn consumer_deleted_keeps_other_addresses_of_the_abbreviation() {
+		let presence = presence("a");
+		let other = format!(
+			"{}q{}",
+			&ADDRESS[..ADDRESS.len() - 7],
+			&ADDRESS[ADDRESS.len() - 6..]
+		);
+		let key = &ADDRESS[ADDRESS.len() - 6..];
+		presence.consumer_created(ADDRESS);
+		presence.consumer_created(&other);
+
+		presence.consumer_deleted(ADDRESS);
+		assert_eq!(
+			presence.consumers.lock().get(key),
+			Some(&vec![other.clone()])
+		);
+		assert_eq!(presence.consumed.get(key), Some(vec![other]));
+	}
+
+	#[test]
+	fn consumers_are_recovered() {
+		let state: Arc<dyn StateStore> = Arc::new(MemoryStore::new());
+		let key = &ADDRESS[ADDRESS.len() - 6..];
+		let presence = |state: &Arc<dyn StateStore>| {
+			let (tx, _) = channel(16);
+			Presence::new(
+				"a".to_string(),
+				tx,
+				Arc::new(Mutex::new(HashMap::new())),
+				state.clone(),
+				SubscriptionPolicy::TakeOver,
+			)
+		};
+
+		presence(&state).consumer_created(ADDRESS);
+
+		let restarted = presence(&state);
+		restarted.recover_consumers();
+		assert_eq!(
+			restarted.consumers.lock().get(key),
+			Some(&vec![ADDRESS.to_string()])
+		);
+
+		// the consumer went away while the relay was down
+		restarted.reconcile_consumers(HashMap::new());
+		assert!(restarted.consumers.lock().is_empty());
+		assert!(restarted.consumed.entries().is_empty());
+	}
+}
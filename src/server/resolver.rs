@@ -0,0 +1,403 @@
+// Copyright 2019 The Gotts Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Discovery of the relay endpoint serving an address domain.
+//!
+//! A domain publishes its relay at `https://<domain>/.well-known/grinrelay` as
+//! `{"host": "relay.example.com", "port": 13420, "tls": true}`. Domains without
+//! such a document are assumed to run the relay themselves.
+//!
+//! Both the queried domain and the published host must resolve to public
+//! addresses only, so a sender cannot point the relay at its own network.
+//! Connections go to the addresses checked, never to a second lookup of the
+//! same name, which could answer differently.
+
+use futures::future::{self, BoxFuture};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+const WELL_KNOWN_PATH: &str = "/.well-known/grinrelay";
+const WELL_KNOWN_CACHE_TTL: Duration = Duration::from_secs(600);
+const WELL_KNOWN_TIMEOUT: Duration = Duration::from_secs(5);
+const WELL_KNOWN_CACHE_SIZE: usize = 1024;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RelayEndpoint {
+	pub host: String,
+	pub port: u16,
+	#[serde(default = "default_tls")]
+	pub tls: bool,
+	/// Public addresses `host` resolved to when checked, to connect to
+	/// instead of resolving it again. Empty if it did not resolve to public
+	/// addresses only, `None` for hosts configured by the operator.
+	#[serde(skip)]
+	pub addrs: Option<Vec<SocketAddr>>,
+}
+
+fn default_tls() -> bool {
+	true
+}
+
+pub trait RelayResolver: Send + Sync {
+	/// The relay serving `domain`, or `None` if the domain publishes nothing.
+	fn resolve<'a>(&'a self, domain: &'a str) -> BoxFuture<'a, Option<RelayEndpoint>>;
+}
+
+/// Discovery answers by domain, expiring after `ttl` and holding at most
+/// `capacity` domains.
+struct DiscoveryCache {
+	ttl: Duration,
+	capacity: usize,
+	entries: HashMap<String, (Instant, Option<RelayEndpoint>)>,
+}
+
+impl DiscoveryCache {
+	fn new(ttl: Duration, capacity: usize) -> DiscoveryCache {
+		DiscoveryCache {
+			ttl,
+			capacity,
+			entries: HashMap::new(),
+		}
+	}
+
+	/// The cached answer for `domain`, `None` if unknown or expired.
+	fn get(&self, domain: &str, now: Instant) -> Option<Option<RelayEndpoint>> {
+		match self.entries.get(domain) {
+			Some((fetched, endpoint)) if now.duration_since(*fetched) < self.ttl => {
+				Some(endpoint.clone())
+			}
+			_ => None,
+		}
+	}
+
+	fn insert(&mut self, domain: &str, endpoint: Option<RelayEndpoint>, now: Instant) {
+		if !self.entries.contains_key(domain) && self.entries.len() >= self.capacity {
+			let ttl = self.ttl;
+			self.entries
+				.retain(|_, (fetched, _)| now.duration_since(*fetched) < ttl);
+			if self.entries.len() >= self.capacity {
+				let oldest = self
+					.entries
+					.iter()
+					.min_by_key(|(_, (fetched, _))| *fetched)
+					.map(|(domain, _)| domain.clone());
+				if let Some(oldest) = oldest {
+					self.entries.remove(&oldest);
+				}
+			}
+		}
+		self.entries.insert(domain.to_string(), (now, endpoint));
+	}
+}
+
+/// Whether `ip` is reachable on the public internet.
+fn is_public(ip: IpAddr) -> bool {
+	match ip {
+		IpAddr::V4(ip) => {
+			let octets = ip.octets();
+			!(ip.is_private()
+				|| ip.is_loopback()
+				|| ip.is_link_local()
+				|| ip.is_unspecified()
+				|| ip.is_broadcast()
+				|| ip.is_documentation()
+				|| ip.is_multicast()
+				|| octets[0] == 0
+				// shared address space, 100.64.0.0/10
+				|| (octets[0] == 100 && octets[1] & 0xc0 == 64))
+		}
+		IpAddr::V6(ip) => {
+			if let Some(mapped) = ip.to_ipv4() {
+				if ip.segments()[..5].iter().all(|s| *s == 0) {
+					return is_public(IpAddr::V4(mapped));
+				}
+			}
+			let first = ip.segments()[0];
+			!(ip.is_loopback()
+				|| ip.is_unspecified()
+				|| ip.is_multicast()
+				// unique local, fc00::/7
+				|| first & 0xfe00 == 0xfc00
+				// link-local, fe80::/10
+				|| first & 0xffc0 == 0xfe80)
+		}
+	}
+}
+
+/// The addresses `host` resolves to, if every one of them is public.
+pub async fn public_addrs(host: &str, port: u16) -> Option<Vec<SocketAddr>> {
+	let addrs = match tokio::net::lookup_host((host, port)).await {
+		Ok(addrs) => addrs.collect::<Vec<_>>(),
+		Err(_) => return None,
+	};
+	if addrs.is_empty() || !addrs.iter().all(|addr| is_public(addr.ip())) {
+		return None;
+	}
+	Some(addrs)
+}
+
+/// Looks up `/.well-known/grinrelay` over https, caching answers for a while.
+pub struct WellKnownResolver {
+	cache: Mutex<DiscoveryCache>,
+}
+
+impl WellKnownResolver {
+	pub fn new() -> WellKnownResolver {
+		WellKnownResolver {
+			cache: Mutex::new(DiscoveryCache::new(
+				WELL_KNOWN_CACHE_TTL,
+				WELL_KNOWN_CACHE_SIZE,
+			)),
+		}
+	}
+
+	/// A client connecting to `domain` at `addrs` only.
+	fn pinned_client(domain: &str, addrs: &[SocketAddr]) -> reqwest::Result<reqwest::Client> {
+		reqwest::Client::builder()
+			.timeout(WELL_KNOWN_TIMEOUT)
+			.redirect(reqwest::redirect::Policy::none())
+			.resolve_to_addrs(domain, addrs)
+			.build()
+	}
+
+	async fn fetch(&self, domain: &str) -> Option<RelayEndpoint> {
+		let addrs = match public_addrs(domain, 443).await {
+			Some(addrs) => addrs,
+			None => {
+				debug!("skipping relay discovery for non public domain {}", domain);
+				return None;
+			}
+		};
+		let url = format!("https://{}{}", domain, WELL_KNOWN_PATH);
+		let client = WellKnownResolver::pinned_client(domain, &addrs).ok()?;
+		let resp = client.get(&url).send().await.ok()?;
+		if !resp.status().is_success() {
+			return None;
+		}
+		let endpoint = match resp.json::<RelayEndpoint>().await {
+			Ok(endpoint) => endpoint,
+			Err(e) => {
+				warn!("invalid relay discovery document at {}: {}", url, e);
+				return None;
+			}
+		};
+		match public_addrs(&endpoint.host, endpoint.port).await {
+			Some(addrs) => Some(RelayEndpoint {
+				addrs: Some(addrs),
+				..endpoint
+			}),
+			None => {
+				warn!(
+					"relay discovery document at {} points at non public host {}",
+					url, endpoint.host
+				);
+				None
+			}
+		}
+	}
+}
+
+impl RelayResolver for WellKnownResolver {
+	fn resolve<'a>(&'a self, domain: &'a str) -> BoxFuture<'a, Option<RelayEndpoint>> {
+		Box::pin(async move {
+			if let Some(endpoint) = self.cache.lock().get(domain, Instant::now()) {
+				return endpoint;
+			}
+
+			let endpoint = self.fetch(domain).await;
+			debug!("relay discovery for {}: {:?}", domain, endpoint);
+			self.cache
+				.lock()
+				.insert(domain, endpoint.clone(), Instant::now());
+			endpoint
+		})
+	}
+}
+
+/// Fixed domain to endpoint table, loaded from a json file, for testing and
+/// for private deployments. Unknown domains fall through to `fallback`.
+pub struct StubResolver {
+	entries: HashMap<String, RelayEndpoint>,
+	fallback: Option<Box<dyn RelayResolver>>,
+}
+
+impl StubResolver {
+	pub fn new(
+		entries: HashMap<String, RelayEndpoint>,
+		fallback: Option<Box<dyn RelayResolver>>,
+	) -> StubResolver {
+		StubResolver { entries, fallback }
+	}
+
+	pub fn from_file(
+		path: &str,
+		fallback: Option<Box<dyn RelayResolver>>,
+	) -> std::io::Result<StubResolver> {
+		let file = File::open(path)?;
+		let entries = serde_json::from_reader(file)?;
+		Ok(StubResolver::new(entries, fallback))
+	}
+}
+
+impl RelayResolver for StubResolver {
+	fn resolve<'a>(&'a self, domain: &'a str) -> BoxFuture<'a, Option<RelayEndpoint>> {
+		match (self.entries.get(domain), &self.fallback) {
+			(Some(endpoint), _) => Box::pin(future::ready(Some(endpoint.clone()))),
+			(None, Some(fallback)) => fallback.resolve(domain),
+			(None, None) => Box::pin(future::ready(None)),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn endpoint(host: &str) -> RelayEndpoint {
+		RelayEndpoint {
+			host: host.to_string(),
+			port: 13420,
+			tls: true,
+			addrs: None,
+		}
+	}
+
+	#[tokio::test]
+	async fn stub_resolver_answers_from_its_table() {
+		let mut entries = HashMap::new();
+		entries.insert("example.com".to_string(), endpoint("relay.example.com"));
+		let resolver = StubResolver::new(entries, None);
+
+		assert_eq!(
+			resolver.resolve("example.com").await,
+			Some(endpoint("relay.example.com"))
+		);
+		assert_eq!(resolver.resolve("example.org").await, None);
+	}
+
+	#[tokio::test]
+	async fn stub_resolver_falls_through_to_fallback() {
+		let mut fallback_entries = HashMap::new();
+		fallback_entries.insert("example.org".to_string(), endpoint("relay.example.org"));
+		let fallback = StubResolver::new(fallback_entries, None);
+
+		let mut entries = HashMap::new();
+		entries.insert("example.com".to_string(), endpoint("relay.example.com"));
+		let resolver = StubResolver::new(entries, Some(Box::new(fallback)));
+
+		assert_eq!(
+			resolver.resolve("example.com").await,
+			Some(endpoint("relay.example.com"))
+		);
+		assert_eq!(
+			resolver.resolve("example.org").await,
+			Some(endpoint("relay.example.org"))
+		);
+		assert_eq!(resolver.resolve("example.net").await, None);
+	}
+
+	#[test]
+	fn relay_endpoint_defaults_to_tls() {
+		let endpoint: RelayEndpoint =
+			serde_json::from_str(r#"{"host": "relay.example.com", "port": 13420}"#).unwrap();
+		assert!(endpoint.tls);
+	}
+
+	#[test]
+	fn cache_entries_expire() {
+		let mut cache = DiscoveryCache::new(Duration::from_secs(10), 8);
+		let start = Instant::now();
+		cache.insert("example.com", Some(endpoint("relay.example.com")), start);
+
+		assert_eq!(
+			cache.get("example.com", start + Duration::from_secs(5)),
+			Some(Some(endpoint("relay.example.com")))
+		);
+		assert_eq!(
+			cache.get("example.com", start + Duration::from_secs(10)),
+			None
+		);
+		assert_eq!(cache.get("example.org", start), None);
+	}
+
+	#[test]
+	fn cache_remembers_negative_answers() {
+		let mut cache = DiscoveryCache::new(Duration::from_secs(10), 8);
+		let start = Instant::now();
+		cache.insert("example.com", None, start);
+		assert_eq!(cache.get("example.com", start), Some(None));
+	}
+
+	#[test]
+	fn cache_is_bounded() {
+		let mut cache = DiscoveryCache::new(Duration::from_secs(10), 2);
+		let start = Instant::now();
+		cache.insert("a.com", None, start);
+		cache.insert("b.com", None, start + Duration::from_secs(1));
+		cache.insert("c.com", None, start + Duration::from_secs(2));
+
+		assert_eq!(cache.entries.len(), 2);
+		assert_eq!(cache.get("a.com", start + Duration::from_secs(2)), None);
+		assert!(cache.get("c.com", start + Duration::from_secs(2)).is_some());
+
+		// expired entries go first, before the oldest live one
+		cache.insert("d.com", None, start + Duration::from_secs(11));
+		cache.insert("e.com", None, start + Duration::from_secs(12));
+		assert_eq!(cache.entries.len(), 2);
+		assert!(cache
+			.get("d.com", start + Duration::from_secs(12))
+			.is_some());
+		assert!(cache
+			.get("e.com", start + Duration::from_secs(12))
+			.is_some());
+	}
+
+	#[tokio::test]
+	async fn public_addrs_are_kept_for_the_connection() {
+		assert_eq!(
+			public_addrs("8.8.8.8", 443).await,
+			Some(vec!["8.8.8.8:443".parse().unwrap()])
+		);
+		assert_eq!(public_addrs("127.0.0.1", 443).await, None);
+		assert_eq!(public_addrs("::ffff:10.0.0.1", 443).await, None);
+	}
+
+	#[test]
+	fn private_targets_are_not_public() {
+		for ip in &[
+			"127.0.0.1",
+			"10.1.2.3",
+			"172.16.0.1",
+			"192.168.1.1",
+			"169.254.169.254",
+			"100.64.0.1",
+			"0.0.0.0",
+			"::1",
+			"::",
+			"fc00::1",
+			"fe80::1",
+			"::ffff:127.0.0.1",
+			"::ffff:169.254.169.254",
+		] {
+			assert!(!is_public(ip.parse().unwrap()), "{} is not public", ip);
+		}
+		for ip in &["8.8.8.8", "1.1.1.1", "2606:4700:4700::1111"] {
+			assert!(is_public(ip.parse().unwrap()), "{} is public", ip);
+		}
+	}
+}
@@ -0,0 +1,392 @@
+// Copyright 2019 The Gotts Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! An append-only log of json lines, one per change. The log is replayed and
+//! compacted when opened, a last line cut short by a crash is dropped.
+//!
+//! Changes are applied in memory right away and written by a thread of its
+//! own, so callers on the runtime never wait for the disk. That thread
+//! compacts the log again once most of its records are dead.
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+
+use super::StateStore;
+
+/// Records in the log before a compaction is considered
+const COMPACT_MIN_RECORDS: usize = 1024;
+/// Share of dead records in the log which triggers a compaction
+const COMPACT_DEAD_RATIO: f64 = 0.5;
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op")]
+enum LogRecord {
+	Put {
+		table: String,
+		key: String,
+		value: String,
+	},
+	Remove {
+		table: String,
+		key: String,
+	},
+}
+
+type Entries = BTreeMap<(String, String), String>;
+
+struct Inner {
+	entries: Entries,
+	/// Changes on their way to the writer, in the order they were applied
+	records: Sender<LogRecord>,
+}
+
+pub struct LogStore {
+	inner: Mutex<Inner>,
+	writer: Option<JoinHandle<()>>,
+}
+
+impl LogStore {
+	pub fn open<P: AsRef<Path>>(path: P) -> io::Result<LogStore> {
+		let path = path.as_ref();
+		let entries = LogStore::replay(path)?;
+		LogStore::compact(path, &entries)?;
+		let log = OpenOptions::new().append(true).open(path)?;
+		info!(
+			"state store {} opened with {} entries",
+			path.display(),
+			entries.len()
+		);
+
+		let (records, received) = channel();
+		let writer = Writer {
+			path: path.to_path_buf(),
+			log,
+			entries: entries.clone(),
+			written: entries.len(),
+		};
+		let writer = thread::Builder::new()
+			.name("state-store".to_string())
+			.spawn(move || writer.run(received))?;
+
+		Ok(LogStore {
+			inner: Mutex::new(Inner { entries, records }),
+			writer: Some(writer),
+		})
+	}
+
+	fn replay(path: &Path) -> io::Result<Entries> {
+		let mut entries = BTreeMap::new();
+		let data = match fs::read(path) {
+			Ok(data) => data,
+			Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(entries),
+			Err(e) => return Err(e),
+		};
+
+		// every record ends with a newline, anything after the last one is torn
+		let complete = match data.iter().rposition(|b| *b == b'\n') {
+			Some(end) => &data[..=end],
+			None => &data[..0],
+		};
+		if complete.len() < data.len() {
+			warn!(
+				"dropping the torn last record of state store {}",
+				path.display()
+			);
+		}
+
+		for (number, line) in complete.split(|b| *b == b'\n').enumerate() {
+			if line.is_empty() {
+				continue;
+			}
+			match serde_json::from_slice(line) {
+				Ok(record) => LogStore::apply(&mut entries, record),
+				Err(e) => warn!(
+					"dropping invalid line {} of state store {}: {}",
+					number + 1,
+					path.display(),
+					e
+				),
+			}
+		}
+		Ok(entries)
+	}
+
+	fn apply(entries: &mut Entries, record: LogRecord) {
+		match record {
+			LogRecord::Put { table, key, value } => {
+				entries.insert((table, key), value);
+			}
+			LogRecord::Remove { table, key } => {
+				entries.remove(&(table, key));
+			}
+		}
+	}
+
+	/// Rewrite the log with one line per live entry.
+	fn compact(path: &Path, entries: &Entries) -> io::Result<()> {
+		let tmp = path.with_extension("tmp");
+		{
+			let mut file = File::create(&tmp)?;
+			for ((table, key), value) in entries {
+				let record = LogRecord::Put {
+					table: table.clone(),
+					key: key.clone(),
+					value: value.clone(),
+				};
+				writeln!(file, "{}", serde_json::to_string(&record)?)?;
+			}
+			file.sync_all()?;
+		}
+		fs::rename(&tmp, path)
+	}
+
+	fn append(log: &mut File, records: &[LogRecord]) -> io::Result<()> {
+		let mut lines = String::new();
+		for record in records {
+			lines.push_str(&serde_json::to_string(record)?);
+			lines.push('\n');
+		}
+		log.write_all(lines.as_bytes())?;
+		log.sync_data()
+	}
+}
+
+impl Drop for LogStore {
+	fn drop(&mut self) {
+		// let the writer drain what is left and stop
+		let (closed, _) = channel();
+		self.inner.lock().records = closed;
+		if let Some(writer) = self.writer.take() {
+			let _ = writer.join();
+		}
+	}
+}
+
+/// Owns the log file, appending the changes it receives as they come.
+struct Writer {
+	path: PathBuf,
+	log: File,
+	/// What the log holds, to compact it from
+	entries: Entries,
+	/// Records in the log, live or dead
+	written: usize,
+}
+
+impl Writer {
+	fn run(mut self, records: Receiver<LogRecord>) {
+		while let Ok(record) = records.recv() {
+			// whatever queued up meanwhile shares the same sync
+			let mut batch = vec![record];
+			batch.extend(records.try_iter());
+			if let Err(e) = LogStore::append(&mut self.log, &batch) {
+				error!("could not write state store {}: {}", self.path.display(), e);
+			}
+			self.written += batch.len();
+			for record in batch {
+				LogStore::apply(&mut self.entries, record);
+			}
+
+			if needs_compaction(self.written, self.entries.len()) {
+				if let Err(e) = self.compact() {
+					error!(
+						"could not compact state store {}: {}",
+						self.path.display(),
+						e
+					);
+				}
+			}
+		}
+	}
+
+	fn compact(&mut self) -> io::Result<()> {
+		LogStore::compact(&self.path, &self.entries)?;
+		self.log = OpenOptions::new().append(true).open(&self.path)?;
+		self.written = self.entries.len();
+		Ok(())
+	}
+}
+
+/// Whether a log of `written` records, `live` of them still current, is worth
+/// rewriting.
+fn needs_compaction(written: usize, live: usize) -> bool {
+	if written < COMPACT_MIN_RECORDS {
+		return false;
+	}
+	let dead = written.saturating_sub(live);
+	dead as f64 / written as f64 > COMPACT_DEAD_RATIO
+}
+
+impl Inner {
+	fn write(&self, record: LogRecord) -> io::Result<()> {
+		self.records
+			.send(record)
+			.map_err(|_| io::ErrorKind::BrokenPipe.into())
+	}
+}
+
+impl StateStore for LogStore {
+	fn get(&self, table: &str, key: &str) -> Option<String> {
+		self.inner
+			.lock()
+			.entries
+			.get(&(table.to_string(), key.to_string()))
+			.cloned()
+	}
+
+	fn put(&self, table: &str, key: &str, value: &str) -> io::Result<()> {
+		let mut inner = self.inner.lock();
+		let record = LogRecord::Put {
+			table: table.to_string(),
+			key: key.to_string(),
+			value: value.to_string(),
+		};
+		inner.write(record)?;
+		inner
+			.entries
+			.insert((table.to_string(), key.to_string()), value.to_string());
+		Ok(())
+	}
+
+	fn remove(&self, table: &str, key: &str) -> io::Result<()> {
+		let mut inner = self.inner.lock();
+		let entry = (table.to_string(), key.to_string());
+		if !inner.entries.contains_key(&entry) {
+			return Ok(());
+		}
+		let record = LogRecord::Remove {
+			table: table.to_string(),
+			key: key.to_string(),
+		};
+		inner.write(record)?;
+		inner.entries.remove(&entry);
+		Ok(())
+	}
+
+	fn entries(&self, table: &str) -> Vec<(String, String)> {
+		self.inner
+			.lock()
+			.entries
+			.iter()
+			.filter(|((t, _), _)| t == table)
+			.map(|((_, key), value)| (key.clone(), value.clone()))
+			.collect()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use uuid::Uuid;
+
+	#[test]
+	fn log_store_recovers_after_reopen() {
+		let path = std::env::temp_dir().join(format!("grinrelay-store-{}.log", Uuid::new_v4()));
+
+		{
+			let store = LogStore::open(&path).unwrap();
+			store.put("aliases", "shop", "\"gn1...\"").unwrap();
+			store.put("aliases", "cafe", "\"gn1a\"").unwrap();
+			store.put("consumers", "shop", "[]").unwrap();
+			store.remove("aliases", "cafe").unwrap();
+		}
+
+		// a write cut short by a crash
+		OpenOptions::new()
+			.append(true)
+			.open(&path)
+			.unwrap()
+			.write_all(b"{\"op\":\"Put\",\"table\":\"aliases\"")
+			.unwrap();
+
+		let store = LogStore::open(&path).unwrap();
+		assert_eq!(
+			store.entries("aliases"),
+			vec![("shop".to_string(), "\"gn1...\"".to_string())]
+		);
+		assert_eq!(store.get("consumers", "shop"), Some("[]".to_string()));
+		assert_eq!(store.get("aliases", "cafe"), None);
+
+		let _ = fs::remove_file(&path);
+	}
+
+	#[test]
+	fn log_store_compacts_once_mostly_dead() {
+		let path = std::env::temp_dir().join(format!("grinrelay-store-{}.log", Uuid::new_v4()));
+
+		{
+			let store = LogStore::open(&path).unwrap();
+			store.put("consumers", "other", "[]").unwrap();
+			for i in 0..3 * COMPACT_MIN_RECORDS {
+				store.put("consumers", "shop", &i.to_string()).unwrap();
+			}
+		}
+
+		let lines = fs::read_to_string(&path).unwrap().lines().count();
+		assert!(lines < COMPACT_MIN_RECORDS, "{} lines", lines);
+		let store = LogStore::open(&path).unwrap();
+		assert_eq!(
+			store.get("consumers", "shop"),
+			Some((3 * COMPACT_MIN_RECORDS - 1).to_string())
+		);
+		assert_eq!(store.get("consumers", "other"), Some("[]".to_string()));
+
+		let _ = fs::remove_file(&path);
+	}
+
+	#[test]
+	fn compaction_waits_for_enough_dead_records() {
+		assert!(!needs_compaction(COMPACT_MIN_RECORDS - 1, 0));
+		assert!(!needs_compaction(
+			COMPACT_MIN_RECORDS,
+			COMPACT_MIN_RECORDS / 2
+		));
+		assert!(needs_compaction(
+			COMPACT_MIN_RECORDS,
+			COMPACT_MIN_RECORDS / 2 - 1
+		));
+		assert!(needs_compaction(4 * COMPACT_MIN_RECORDS, 1));
+	}
+
+	#[test]
+	fn log_store_drops_a_record_torn_inside_a_character() {
+		let path = std::env::temp_dir().join(format!("grinrelay-store-{}.log", Uuid::new_v4()));
+
+		{
+			let store = LogStore::open(&path).unwrap();
+			store.put("aliases", "shop", "\"gn1...\"").unwrap();
+		}
+
+		// the first byte of a two byte character
+		OpenOptions::new()
+			.append(true)
+			.open(&path)
+			.unwrap()
+			.write_all(b"{\"op\":\"Put\",\"table\":\"aliases\",\"key\":\"caf\xc3")
+			.unwrap();
+
+		let store = LogStore::open(&path).unwrap();
+		assert_eq!(
+			store.entries("aliases"),
+			vec![("shop".to_string(), "\"gn1...\"".to_string())]
+		);
+
+		let _ = fs::remove_file(&path);
+	}
+}
@@ -0,0 +1,64 @@
+// Copyright 2019 The Gotts Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use parking_lot::RwLock;
+use std::collections::BTreeMap;
+use std::io;
+
+use super::StateStore;
+
+/// A store which forgets everything on restart, for tests.
+pub struct MemoryStore {
+	entries: RwLock<BTreeMap<(String, String), String>>,
+}
+
+impl MemoryStore {
+	pub fn new() -> MemoryStore {
+		MemoryStore {
+			entries: RwLock::new(BTreeMap::new()),
+		}
+	}
+}
+
+impl StateStore for MemoryStore {
+	fn get(&self, table: &str, key: &str) -> Option<String> {
+		self.entries
+			.read()
+			.get(&(table.to_string(), key.to_string()))
+			.cloned()
+	}
+
+	fn put(&self, table: &str, key: &str, value: &str) -> io::Result<()> {
+		self.entries
+			.write()
+			.insert((table.to_string(), key.to_string()), value.to_string());
+		Ok(())
+	}
+
+	fn remove(&self, table: &str, key: &str) -> io::Result<()> {
+		self.entries
+			.write()
+			.remove(&(table.to_string(), key.to_string()));
+		Ok(())
+	}
+
+	fn entries(&self, table: &str) -> Vec<(String, String)> {
+		self.entries
+			.read()
+			.iter()
+			.filter(|((t, _), _)| t == table)
+			.map(|((_, key), value)| (key.clone(), value.clone()))
+			.collect()
+	}
+}
@@ -0,0 +1,108 @@
+// Copyright 2019 The Gotts Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Relay metadata which has to survive a restart: registered aliases, the
+//! online consumers lookup and whatever bookkeeping comes next.
+//!
+//! Values are kept as json strings in named tables, `Table` adds the typed
+//! view used by the rest of the relay.
+
+mod log_store;
+#[cfg(test)]
+mod memory_store;
+
+pub use self::log_store::LogStore;
+#[cfg(test)]
+pub use self::memory_store::MemoryStore;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::io;
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+pub trait StateStore: Send + Sync {
+	fn get(&self, table: &str, key: &str) -> Option<String>;
+	fn put(&self, table: &str, key: &str, value: &str) -> io::Result<()>;
+	fn remove(&self, table: &str, key: &str) -> io::Result<()>;
+	/// Every entry of `table`, ordered by key.
+	fn entries(&self, table: &str) -> Vec<(String, String)>;
+}
+
+/// A table of json serialized values of one type.
+pub struct Table<T> {
+	store: Arc<dyn StateStore>,
+	name: String,
+	_marker: PhantomData<fn() -> T>,
+}
+
+impl<T> Clone for Table<T> {
+	fn clone(&self) -> Table<T> {
+		Table {
+			store: self.store.clone(),
+			name: self.name.clone(),
+			_marker: PhantomData,
+		}
+	}
+}
+
+impl<T: Serialize + DeserializeOwned> Table<T> {
+	pub fn new(store: Arc<dyn StateStore>, name: &str) -> Table<T> {
+		Table {
+			store,
+			name: name.to_string(),
+			_marker: PhantomData,
+		}
+	}
+
+	pub fn get(&self, key: &str) -> Option<T> {
+		let value = self.store.get(&self.name, key)?;
+		match serde_json::from_str(&value) {
+			Ok(value) => Some(value),
+			Err(e) => {
+				error!(
+					"invalid [{}] entry [{}] in state store: {}",
+					self.name, key, e
+				);
+				None
+			}
+		}
+	}
+
+	pub fn put(&self, key: &str, value: &T) -> io::Result<()> {
+		let value = serde_json::to_string(value)?;
+		self.store.put(&self.name, key, &value)
+	}
+
+	pub fn remove(&self, key: &str) -> io::Result<()> {
+		self.store.remove(&self.name, key)
+	}
+
+	pub fn entries(&self) -> Vec<(String, T)> {
+		self.store
+			.entries(&self.name)
+			.into_iter()
+			.filter_map(|(key, value)| match serde_json::from_str(&value) {
+				Ok(value) => Some((key, value)),
+				Err(e) => {
+					error!(
+						"invalid [{}] entry [{}] in state store: {}",
+						self.name, key, e
+					);
+					None
+				}
+			})
+			.collect()
+	}
+}